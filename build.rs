@@ -51,6 +51,7 @@ MARK_FIX_753(UBLK_U_CMD_START_USER_RECOVERY);
 MARK_FIX_753(UBLK_U_CMD_END_USER_RECOVERY);
 MARK_FIX_753(UBLK_U_CMD_GET_DEV_INFO2);
 MARK_FIX_753(UBLK_U_CMD_GET_FEATURES);
+MARK_FIX_753(UBLK_U_CMD_UPDATE_SIZE);
 const int Fix753_UBLK_IO_RES_ABORT = UBLK_IO_RES_ABORT;
     "#;
 
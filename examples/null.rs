@@ -1,9 +1,10 @@
 use bitflags::bitflags;
 use clap::{Arg, ArgAction, Command};
 use libublk::dev_flags::*;
-use libublk::io::{UblkDev, UblkIOCtx, UblkQueue};
-use libublk::{ctrl::UblkCtrl, exe::Executor, UblkIORes, UblkSession};
-use std::rc::Rc;
+use libublk::io::UblkDev;
+use libublk::targets::null::{NullTgt, NullTgtBuilder};
+use libublk::{ctrl::UblkCtrl, UblkSession};
+use std::time::Duration;
 
 bitflags! {
     #[derive(Default)]
@@ -14,30 +15,24 @@ bitflags! {
     }
 }
 
-#[inline]
-fn get_io_cmd_result(q: &UblkQueue, tag: u16) -> i32 {
-    let iod = q.get_iod(tag);
-    let bytes = (iod.nr_sectors << 9) as i32;
-
-    bytes
-}
-
-#[inline]
-fn handle_io_cmd(q: &UblkQueue, tag: u16) {
-    let bytes = get_io_cmd_result(q, tag);
-    q.complete_io_cmd(tag, Ok(UblkIORes::Result(bytes)));
-}
-
-fn test_add(id: i32, nr_queues: u32, depth: u32, ctrl_flags: u64, buf_size: u32, flags: NullFlags) {
+fn test_add(
+    id: i32,
+    nr_queues: u32,
+    depth: u32,
+    ctrl_flags: u64,
+    buf_size: u32,
+    flags: NullFlags,
+    null: NullTgt,
+) {
     if flags.intersects(NullFlags::FOREGROUND) {
-        __test_add(id, nr_queues, depth, ctrl_flags, buf_size, flags);
+        __test_add(id, nr_queues, depth, ctrl_flags, buf_size, flags, null);
     } else {
         let daemonize = daemonize::Daemonize::new()
             .stdout(daemonize::Stdio::keep())
             .stderr(daemonize::Stdio::keep());
 
         match daemonize.start() {
-            Ok(_) => __test_add(id, nr_queues, depth, ctrl_flags, buf_size, flags),
+            Ok(_) => __test_add(id, nr_queues, depth, ctrl_flags, buf_size, flags, null),
             _ => panic!(),
         }
     }
@@ -50,6 +45,7 @@ fn __test_add(
     ctrl_flags: u64,
     buf_size: u32,
     flags: NullFlags,
+    null: NullTgt,
 ) {
     let aio = flags.intersects(NullFlags::ASYNC);
     let oneshot = flags.intersects(NullFlags::ONESHOT);
@@ -72,67 +68,20 @@ fn __test_add(
             )
             .build()
             .unwrap();
-        let tgt_init = |dev: &mut UblkDev| {
-            dev.set_default_params(250_u64 << 30);
-            Ok(0)
-        };
+        let tgt_init = |dev: &mut UblkDev| null.init_tgt(dev);
         let (mut ctrl, dev) = sess.create_devices(tgt_init).unwrap();
-        // queue level logic
-        let q_sync_handler = move |qid: u16, dev: &UblkDev| {
-            // logic for io handling
-            let io_handler = move |q: &UblkQueue, tag: u16, _io: &UblkIOCtx| {
-                handle_io_cmd(q, tag);
-            };
-
-            UblkQueue::new(qid, dev)
-                .unwrap()
-                .wait_and_handle_io(io_handler);
+        let q_fn = move |qid: u16, dev: &UblkDev| {
+            null.run_queue(qid, dev).unwrap();
         };
-        let q_async_handler = move |qid: u16, dev: &UblkDev| {
-            let q_rc = Rc::new(UblkQueue::new(qid as u16, &dev).unwrap());
-            let exe = Executor::new(dev.get_nr_ios());
-
-            for tag in 0..depth as u16 {
-                let q = q_rc.clone();
-
-                exe.spawn(tag as u16, async move {
-                    let buf_addr = q.get_io_buf_addr(tag);
-                    let mut cmd_op = libublk::sys::UBLK_IO_FETCH_REQ;
-                    let mut res = 0;
-                    loop {
-                        let cmd_res = q.submit_io_cmd(tag, cmd_op, buf_addr, res).await;
-                        if cmd_res == libublk::sys::UBLK_IO_RES_ABORT {
-                            break;
-                        }
 
-                        res = get_io_cmd_result(&q, tag);
-                        cmd_op = libublk::sys::UBLK_IO_COMMIT_AND_FETCH_REQ;
-                    }
-                });
+        sess.run_target(&mut ctrl, &dev, q_fn, move |dev_id| {
+            let mut d_ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+            d_ctrl.dump();
+            if oneshot {
+                d_ctrl.kill_dev().unwrap();
             }
-            q_rc.wait_and_wake_io_tasks(&exe);
-        };
-
-        if aio {
-            // Now start this ublk target
-            sess.run_target(&mut ctrl, &dev, q_async_handler, move |dev_id| {
-                let mut d_ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
-                d_ctrl.dump();
-                if oneshot {
-                    d_ctrl.kill_dev().unwrap();
-                }
-            })
-            .unwrap();
-        } else {
-            sess.run_target(&mut ctrl, &dev, q_sync_handler, move |dev_id| {
-                let mut d_ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
-                d_ctrl.dump();
-                if oneshot {
-                    d_ctrl.kill_dev().unwrap();
-                }
-            })
-            .unwrap();
-        }
+        })
+        .unwrap();
     }
 }
 
@@ -208,6 +157,27 @@ fn main() {
                         .short('a')
                         .action(ArgAction::SetTrue)
                         .help("use async/await to handle IO command"),
+                )
+                .arg(
+                    Arg::new("lat_min_us")
+                        .long("lat_min_us")
+                        .default_value("0")
+                        .help("min latency added to each IO in microseconds")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("lat_max_us")
+                        .long("lat_max_us")
+                        .default_value("0")
+                        .help("max latency added to each IO in microseconds")
+                        .action(ArgAction::Set),
+                )
+                .arg(
+                    Arg::new("error_rate")
+                        .long("error_rate")
+                        .default_value("0")
+                        .help("fraction of IOs failed with EIO, in range of [0, 1]")
+                        .action(ArgAction::Set),
                 ),
         )
         .subcommand(
@@ -245,6 +215,27 @@ fn main() {
                 .unwrap()
                 .parse::<u32>()
                 .unwrap_or(52288);
+            let lat_min = add_matches
+                .get_one::<String>("lat_min_us")
+                .unwrap()
+                .parse::<u64>()
+                .unwrap_or(0);
+            let lat_max = add_matches
+                .get_one::<String>("lat_max_us")
+                .unwrap()
+                .parse::<u64>()
+                .unwrap_or(0);
+            let error_rate = add_matches
+                .get_one::<String>("error_rate")
+                .unwrap()
+                .parse::<f64>()
+                .unwrap_or(0.0);
+            let null = NullTgtBuilder::default()
+                .lat_min(Duration::from_micros(lat_min))
+                .lat_max(Duration::from_micros(lat_max))
+                .error_rate(error_rate)
+                .build()
+                .unwrap();
             let mut flags: NullFlags = Default::default();
 
             if add_matches.get_flag("async") {
@@ -266,7 +257,7 @@ fn main() {
                 0
             };
 
-            test_add(id, nr_queues, depth, ctrl_flags, buf_size, flags);
+            test_add(id, nr_queues, depth, ctrl_flags, buf_size, flags, null);
         }
         Some(("del", add_matches)) => {
             let id = add_matches
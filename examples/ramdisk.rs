@@ -63,7 +63,12 @@ fn rd_add_dev(dev_id: i32, buf_addr: u64, size: u64, for_add: bool) {
         dev.set_default_params(size);
         Ok(0)
     };
-    let (mut ctrl, dev) = sess.create_devices(tgt_init).unwrap();
+    let (mut ctrl, dev) = if for_add {
+        sess.create_devices(tgt_init).unwrap()
+    } else {
+        // dev_size & params are restored from json
+        sess.recover_devices(|_dev, _tgt, _data| Ok(0)).unwrap()
+    };
 
     let exe = Executor::new(dev.get_nr_ios());
     let q_rc = Rc::new(UblkQueue::new(0, &dev).unwrap());
@@ -117,7 +122,7 @@ fn rd_add_dev(dev_id: i32, buf_addr: u64, size: u64, for_add: bool) {
     match res {
         Ok(res) if res >= 0 => {
             ctrl.dump();
-            q_rc.wait_and_wake_io_tasks(&exe);
+            q_rc.wait_and_wake_io_tasks(&exe).unwrap();
         }
         _ => {}
     };
@@ -154,8 +159,6 @@ fn test_add(recover: usize) {
                 assert!(dev_id >= 0);
                 let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
                 size = rd_get_device_size(&mut ctrl);
-
-                ctrl.start_user_recover().unwrap();
             }
             let buf = libublk::ublk_alloc_buf(size as usize, 4096);
 
@@ -4,7 +4,7 @@ use bitmaps::Bitmap;
 use io_uring::{cqueue, opcode, squeue, types, IoUring};
 use log::{error, trace};
 use serde::Deserialize;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
 use std::{
     fs,
     io::{Read, Write},
@@ -43,6 +43,146 @@ impl UblkQueueAffinity {
     }
 }
 
+bitflags::bitflags! {
+    /// Typed ublk driver feature flags(`UBLK_F_*`)
+    ///
+    /// Used for decoding the features returned from GET_FEATURES command,
+    /// and for checking `ctrl_flags` against the driver's features before
+    /// sending ADD_DEV.
+    #[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+    pub struct UblkFeatures: u64 {
+        const SUPPORT_ZERO_COPY = sys::UBLK_F_SUPPORT_ZERO_COPY as u64;
+        const URING_CMD_COMP_IN_TASK = sys::UBLK_F_URING_CMD_COMP_IN_TASK as u64;
+        const NEED_GET_DATA = sys::UBLK_F_NEED_GET_DATA as u64;
+        const USER_RECOVERY = sys::UBLK_F_USER_RECOVERY as u64;
+        const USER_RECOVERY_REISSUE = sys::UBLK_F_USER_RECOVERY_REISSUE as u64;
+        const UNPRIVILEGED_DEV = sys::UBLK_F_UNPRIVILEGED_DEV as u64;
+        const CMD_IOCTL_ENCODE = sys::UBLK_F_CMD_IOCTL_ENCODE as u64;
+        const USER_COPY = sys::UBLK_F_USER_COPY as u64;
+        const ZONED = sys::UBLK_F_ZONED as u64;
+        const UPDATE_SIZE = sys::UBLK_F_UPDATE_SIZE as u64;
+    }
+}
+
+impl UblkFeatures {
+    /// Return name of each flag included, such as `UBLK_F_USER_COPY`
+    ///
+    /// Bits unknown to libublk are returned as hex string.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .iter_names()
+            .map(|(name, _)| format!("UBLK_F_{}", name))
+            .collect();
+        let unknown = self.bits() & !Self::all().bits();
+
+        if unknown != 0 {
+            names.push(format!("0x{:x}", unknown));
+        }
+        names
+    }
+
+    /// Check if all flags in `flags` are supported by this feature set
+    ///
+    /// Return the unsupported flags in case of failure.
+    pub fn check(&self, flags: u64) -> Result<(), UblkFeatures> {
+        let unsupported = flags & !self.bits();
+
+        if unsupported != 0 {
+            Err(UblkFeatures::from_bits_retain(unsupported))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl std::fmt::Display for UblkFeatures {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.names().join(" | "))
+    }
+}
+
+/// ublk device state(`UBLK_S_DEV_*`), retrieved by GET_DEV_INFO command
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UblkDevState {
+    /// device isn't started, or has been stopped
+    Dead,
+
+    /// device is started and serving IO
+    Live,
+
+    /// ublk server is gone, and device is waiting for user recovery
+    Quiesced,
+
+    /// state not known by libublk
+    Unknown(u16),
+}
+
+impl From<u16> for UblkDevState {
+    fn from(state: u16) -> Self {
+        match state as u32 {
+            sys::UBLK_S_DEV_DEAD => UblkDevState::Dead,
+            sys::UBLK_S_DEV_LIVE => UblkDevState::Live,
+            sys::UBLK_S_DEV_QUIESCED => UblkDevState::Quiesced,
+            _ => UblkDevState::Unknown(state),
+        }
+    }
+}
+
+impl std::fmt::Display for UblkDevState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UblkDevState::Dead => write!(f, "DEAD"),
+            UblkDevState::Live => write!(f, "LIVE"),
+            UblkDevState::Quiesced => write!(f, "QUIESCED"),
+            UblkDevState::Unknown(_) => write!(f, "UNKNOWN"),
+        }
+    }
+}
+
+/// Permission and ownership of exported json files
+///
+/// Applied on the running directory created by libublk, and on each
+/// device's json and target state file.
+#[derive(Debug, Copy, Clone)]
+pub struct UblkJsonPerm {
+    /// mode of running directory, default is 0o777
+    pub dir_mode: u32,
+
+    /// mode of exported json file, default is 0o700, so it is only
+    /// visible for the device owner
+    pub file_mode: u32,
+
+    /// (uid, gid) of running directory and json file, don't change
+    /// ownership if it is None
+    pub owner: Option<(u32, u32)>,
+}
+
+impl Default for UblkJsonPerm {
+    fn default() -> Self {
+        UblkJsonPerm {
+            dir_mode: 0o777,
+            file_mode: 0o700,
+            owner: None,
+        }
+    }
+}
+
+/// Block device of one started ublk device
+///
+/// Retrieved from driver's `UBLK_PARAM_TYPE_DEVT` parameter and sysfs,
+/// so the node name assigned by udev is respected.
+#[derive(Debug, Clone, Default)]
+pub struct UblkBlkDev {
+    pub major: u32,
+    pub minor: u32,
+
+    /// device node, such as `/dev/ublkb0`
+    pub path: String,
+
+    /// sysfs directory, such as `/sys/devices/virtual/block/ublkb0`
+    pub sysfs_path: String,
+}
+
 #[repr(C)]
 union CtrlCmd {
     ctrl_cmd: sys::ublksrv_ctrl_cmd,
@@ -95,8 +235,12 @@ impl UblkCtrlCmdData {
             super::ublk_alloc_buf(size, 8)
         };
 
-        let path_str = dev.get_cdev_path().to_string();
-        assert!(path_str.len() <= CTRL_UBLKC_PATH_MAX);
+        // the resolved node path may not fit in the buffer, and the driver
+        // only checks permission of the node, so use the default one
+        let path_str = match dev.get_cdev_path() {
+            p if p.len() < CTRL_UBLKC_PATH_MAX => p,
+            _ => format!("{}{}", UblkCtrl::CDEV_PATH, dev.dev_info.dev_id),
+        };
 
         unsafe {
             libc::memset(buf as *mut libc::c_void, 0, CTRL_UBLKC_PATH_MAX);
@@ -174,6 +318,12 @@ pub struct UblkCtrl {
     queue_tids: Vec<i32>,
     nr_queues_configured: u16,
     ring: IoUring<squeue::Entry128>,
+    json_perm: UblkJsonPerm,
+    run_dir: String,
+    cdev_timeout: std::time::Duration,
+    devt: Option<sys::ublk_param_devt>,
+    cdev_node: Option<String>,
+    bdev: Option<UblkBlkDev>,
 }
 
 impl AsRawFd for UblkCtrl {
@@ -201,6 +351,13 @@ impl UblkCtrl {
     const CDEV_PATH: &'static str = "/dev/ublkc";
     const BDEV_PATH: &'static str = "/dev/ublkb";
 
+    /// version of exported json, has to be increased when json
+    /// format is changed
+    pub const JSON_VERSION: u64 = 1;
+
+    /// environment variable for overriding the default running directory
+    pub const RUN_DIR_ENV: &'static str = "LIBUBLK_RUN_DIR";
+
     /// New one ublk control device
     ///
     /// # Arguments:
@@ -217,7 +374,6 @@ impl UblkCtrl {
     /// ublk control device is for sending command to driver, and maintain
     /// device exported json file, dump, or any misc management task.
     ///
-    #[allow(clippy::uninit_vec)]
     pub fn new(
         id: i32,
         nr_queues: u32,
@@ -226,6 +382,34 @@ impl UblkCtrl {
         flags: u64,
         tgt_flags: u64,
         dev_flags: u32,
+    ) -> Result<UblkCtrl, UblkError> {
+        Self::new_in_dir(
+            id,
+            nr_queues,
+            depth,
+            io_buf_bytes,
+            flags,
+            tgt_flags,
+            dev_flags,
+            None,
+        )
+    }
+
+    /// New one ublk control device with json files stored in `run_dir`
+    ///
+    /// Same with `new()`, and `run_dir_for()` is used if `run_dir` is None.
+    /// Json of existing device is loaded from `run_dir` directly.
+    #[allow(clippy::uninit_vec)]
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new_in_dir(
+        id: i32,
+        nr_queues: u32,
+        depth: u32,
+        io_buf_bytes: u32,
+        flags: u64,
+        tgt_flags: u64,
+        dev_flags: u32,
+        run_dir: Option<&str>,
     ) -> Result<UblkCtrl, UblkError> {
         if !Path::new(CTRL_PATH).exists() {
             eprintln!("Please run `modprobe ublk_drv` first");
@@ -288,6 +472,12 @@ impl UblkCtrl {
             nr_queues_configured: 0,
             dev_flags,
             features: None,
+            json_perm: Default::default(),
+            run_dir: run_dir.map_or_else(|| Self::run_dir_for(flags), str::to_string),
+            cdev_timeout: std::time::Duration::from_secs(3),
+            devt: None,
+            cdev_node: None,
+            bdev: None,
         };
 
         let features = match dev.__get_features() {
@@ -298,13 +488,25 @@ impl UblkCtrl {
 
         //add cdev if the device is for adding device
         if dev.for_add_dev() {
+            // driver without GET_FEATURES can only fail ADD_DEV with -EINVAL
+            if let Some(f) = dev.get_ublk_features() {
+                f.check(flags).map_err(UblkError::UnsupportedFeatures)?;
+            }
             dev.add()?;
+
+            // driver may not support DEVT, then fallback to default node path
+            let _ = dev.update_dev_nodes();
         } else if id >= 0 {
-            let res = dev.reload_json();
-            if res.is_err() {
-                eprintln!("device reload json failed");
-            }
             dev.get_info()?;
+            let _ = dev.update_dev_nodes();
+
+            // json of unprivileged device is stored in per-user directory
+            if run_dir.is_none() {
+                dev.run_dir = Self::run_dir_for(dev.dev_info.flags);
+            }
+            if let Err(e) = dev.reload_json() {
+                log::warn!("dev {}: reload json failed {:?}", id, e);
+            }
         }
         trace!("ctrl: device {} created", dev.dev_info.dev_id);
 
@@ -319,18 +521,307 @@ impl UblkCtrl {
         self.features
     }
 
+    /// Return ublk driver's features as `UblkFeatures`
+    ///
+    /// None is returned if the driver doesn't support GET_FEATURES
+    pub fn get_ublk_features(&self) -> Option<UblkFeatures> {
+        self.features.map(UblkFeatures::from_bits_retain)
+    }
+
     fn is_unprivileged(&self) -> bool {
         (self.dev_info.flags & (super::sys::UBLK_F_UNPRIVILEGED_DEV as u64)) != 0
     }
 
     /// Return ublk char device path
+    ///
+    /// The path resolved from `UBLK_PARAM_TYPE_DEVT` is returned if it
+    /// is available, otherwise `/dev/ublkc{id}` is returned.
     pub fn get_cdev_path(&self) -> String {
-        format!("{}{}", Self::CDEV_PATH, self.dev_info.dev_id)
+        match &self.cdev_node {
+            Some(path) => path.clone(),
+            None => format!("{}{}", Self::CDEV_PATH, self.dev_info.dev_id),
+        }
     }
 
     /// Return ublk block device path
+    ///
+    /// The path resolved from `UBLK_PARAM_TYPE_DEVT` is returned if it
+    /// is available, otherwise `/dev/ublkb{id}` is returned.
     pub fn get_bdev_path(&self) -> String {
-        format!("{}{}", Self::BDEV_PATH, self.dev_info.dev_id)
+        match &self.bdev {
+            Some(bdev) => bdev.path.clone(),
+            None => format!("{}{}", Self::BDEV_PATH, self.dev_info.dev_id),
+        }
+    }
+
+    /// Return (major, minor) of ublk char device
+    ///
+    /// Available after `update_dev_nodes()` is done
+    pub fn get_cdev_devt(&self) -> Option<(u32, u32)> {
+        match &self.devt {
+            Some(d) if d.char_major != 0 => Some((d.char_major, d.char_minor)),
+            _ => None,
+        }
+    }
+
+    /// Return (major, minor) of ublk block device
+    ///
+    /// Available after the device is started and `update_dev_nodes()`
+    /// is done
+    pub fn get_bdev_devt(&self) -> Option<(u32, u32)> {
+        match &self.devt {
+            Some(d) if d.disk_major != 0 => Some((d.disk_major, d.disk_minor)),
+            _ => None,
+        }
+    }
+
+    /// Return ublk block device resolved from `UBLK_PARAM_TYPE_DEVT`
+    pub fn get_bdev(&self) -> Option<&UblkBlkDev> {
+        self.bdev.as_ref()
+    }
+
+    /// Resolve device nodes of ublk char & block device
+    ///
+    /// Device numbers are retrieved via `UBLK_PARAM_TYPE_DEVT`, then
+    /// the device nodes are looked up from sysfs and /dev, so nodes
+    /// renamed by udev or nodes in container can be found. The block
+    /// device is only available after the device is started.
+    ///
+    /// Called after adding and starting device, and when opening
+    /// existing device.
+    pub fn update_dev_nodes(&mut self) -> Result<i32, UblkError> {
+        let devt = self.get_devt()?;
+
+        self.devt = Some(devt);
+        if let Some((major, minor)) = self.get_cdev_devt() {
+            if let Ok((path, _)) = Self::find_dev_node(false, major, minor) {
+                self.cdev_node = Some(path);
+            }
+        }
+        if let Some((major, minor)) = self.get_bdev_devt() {
+            if let Ok((path, sysfs)) = Self::find_dev_node(true, major, minor) {
+                self.bdev = Some(UblkBlkDev {
+                    major,
+                    minor,
+                    path,
+                    sysfs_path: sysfs,
+                });
+            }
+        }
+        Ok(0)
+    }
+
+    /// Look up char device node which may be just created by udev
+    fn lookup_cdev_path(&self) -> String {
+        if let Some((major, minor)) = self.get_cdev_devt() {
+            if let Ok((path, _)) = Self::find_dev_node(false, major, minor) {
+                return path;
+            }
+        }
+        self.get_cdev_path()
+    }
+
+    /// Check if the current user owns this unprivileged device
+    ///
+    /// Owner is retrieved via GET_DEV_INFO2, and root can access any device.
+    /// Return `-EPERM` if the device is owned by other user, and the owner
+    /// is logged.
+    pub fn check_dev_owner(&mut self) -> Result<i32, UblkError> {
+        if !self.is_unprivileged() {
+            return Ok(0);
+        }
+
+        self.get_info()?;
+
+        let uid = unsafe { libc::geteuid() };
+        let gid = unsafe { libc::getegid() };
+        let info = &self.dev_info;
+        if uid != 0 && (info.owner_uid != uid || info.owner_gid != gid) {
+            log::error!(
+                "dev {} is owned by {}:{}, not current user {}:{}",
+                info.dev_id,
+                info.owner_uid,
+                info.owner_gid,
+                uid,
+                gid
+            );
+            return Err(UblkError::OtherError(-libc::EPERM));
+        }
+        Ok(0)
+    }
+
+    /// Wait until ublk char device becomes accessible for current user
+    ///
+    /// For unprivileged device, the char device node is created by udev
+    /// as root, and its owner is changed by udev rule(`ublk_chown.sh`)
+    /// later, so have to wait until the permission is applied.
+    ///
+    /// # Arguments:
+    ///
+    /// * `timeout`: max time for waiting
+    ///
+    /// Return the last error of accessing the char device on timeout.
+    pub fn wait_for_cdev_access(&self, timeout: std::time::Duration) -> Result<i32, UblkError> {
+        let res = Self::wait_for_dev_node(timeout, || {
+            let path = std::ffi::CString::new(self.lookup_cdev_path()).unwrap();
+            if unsafe { libc::access(path.as_ptr(), libc::R_OK | libc::W_OK) } == 0 {
+                Ok(0)
+            } else {
+                Err(std::io::Error::last_os_error())
+            }
+        });
+
+        if let Err(UblkError::OtherIOError(e)) = &res {
+            self.cdev_error_hint(e);
+        }
+        res
+    }
+
+    /// Open ublk char device, and wait until it is ready
+    ///
+    /// The char device node is created by udev after ADD_DEV, and it may
+    /// take a while on busy system. The last open error is returned after
+    /// waiting for `get_cdev_timeout()`.
+    pub fn open_cdev(&mut self) -> Result<fs::File, UblkError> {
+        let res = Self::wait_for_dev_node(self.cdev_timeout, || {
+            let cdev_path = self.lookup_cdev_path();
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&cdev_path)
+                .map(|f| (f, cdev_path))
+        });
+
+        match res {
+            Ok((f, cdev_path)) => {
+                if self.get_cdev_devt().is_some() {
+                    self.cdev_node = Some(cdev_path);
+                }
+                Ok(f)
+            }
+            Err(e) => {
+                if let UblkError::OtherIOError(e) = &e {
+                    self.cdev_error_hint(e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn cdev_error_hint(&self, e: &std::io::Error) {
+        match e.raw_os_error() {
+            Some(libc::ENOENT) => log::warn!(
+                "{} isn't created, please check if udev is running",
+                self.get_cdev_path()
+            ),
+            Some(libc::EACCES) | Some(libc::EPERM) if self.is_unprivileged() => log::warn!(
+                "{} isn't accessible, please check if ublk udev rules and ublk_chown.sh are installed",
+                self.get_cdev_path()
+            ),
+            _ => {}
+        }
+    }
+
+    /// Set timeout for waiting ublk char device to be ready
+    pub fn set_cdev_timeout(&mut self, timeout: std::time::Duration) {
+        self.cdev_timeout = timeout;
+    }
+
+    /// Return timeout for waiting ublk char device to be ready
+    pub fn get_cdev_timeout(&self) -> std::time::Duration {
+        self.cdev_timeout
+    }
+
+    /// Wait until device node under /dev is ready
+    ///
+    /// `f` is called for checking if the node is ready, and it is retried
+    /// when any node under /dev is created or its attribute(owner, mode, ...)
+    /// is changed, which is notified via inotify. Return the last error of
+    /// `f` on timeout.
+    pub(crate) fn wait_for_dev_node<T, F>(
+        timeout: std::time::Duration,
+        mut f: F,
+    ) -> Result<T, UblkError>
+    where
+        F: FnMut() -> std::io::Result<T>,
+    {
+        // watch /dev before checking node, so that no event can be missed
+        let ifd = unsafe {
+            let fd = libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC);
+            if fd >= 0 {
+                let dir = std::ffi::CString::new("/dev").unwrap();
+                let mask = libc::IN_CREATE | libc::IN_ATTRIB | libc::IN_MOVED_TO;
+                libc::inotify_add_watch(fd, dir.as_ptr(), mask);
+                Some(OwnedFd::from_raw_fd(fd))
+            } else {
+                None
+            }
+        };
+        let start = std::time::Instant::now();
+
+        loop {
+            let err = match f() {
+                Ok(v) => return Ok(v),
+                Err(e) => e,
+            };
+
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Err(UblkError::OtherIOError(err));
+            }
+
+            // still wakeup periodically in case that inotify isn't available
+            let wait = std::cmp::min(timeout - elapsed, std::time::Duration::from_millis(100));
+            match &ifd {
+                Some(fd) => {
+                    let mut pfd = libc::pollfd {
+                        fd: fd.as_raw_fd(),
+                        events: libc::POLLIN,
+                        revents: 0,
+                    };
+                    let mut buf = [0_u8; 4096];
+
+                    unsafe {
+                        if libc::poll(&mut pfd, 1, wait.as_millis() as i32) > 0 {
+                            while libc::read(
+                                fd.as_raw_fd(),
+                                buf.as_mut_ptr() as *mut libc::c_void,
+                                buf.len(),
+                            ) > 0
+                            {}
+                        }
+                    }
+                }
+                None => {
+                    std::thread::sleep(std::cmp::min(wait, std::time::Duration::from_millis(10)))
+                }
+            }
+        }
+    }
+
+    /// Return (uid, gid) of unprivileged ublk device's owner
+    ///
+    /// # Arguments:
+    ///
+    /// * `name`: device node name, such as `ublkb0` or `ublkc0`
+    ///
+    /// Used by udev rule for changing owner of unprivileged ublk device
+    /// nodes, and None is returned if the name isn't valid or the device
+    /// isn't unprivileged.
+    pub fn get_dev_owner_by_name(name: &str) -> Option<(u32, u32)> {
+        let id = name
+            .strip_prefix("ublkb")
+            .or_else(|| name.strip_prefix("ublkc"))?
+            .parse::<i32>()
+            .ok()
+            .filter(|id| *id >= 0)?;
+
+        let ctrl = Self::new_simple(id, 0).ok()?;
+        if ctrl.is_unprivileged() {
+            Some((ctrl.dev_info.owner_uid, ctrl.dev_info.owner_gid))
+        } else {
+            None
+        }
     }
 
     /// Allocate one simple UblkCtrl device for delelting, listing, recovering,..,
@@ -353,12 +844,41 @@ impl UblkCtrl {
         self.dev_flags
     }
 
-    fn dev_state_desc(&self) -> String {
-        match self.dev_info.state as u32 {
-            sys::UBLK_S_DEV_DEAD => "DEAD".to_string(),
-            sys::UBLK_S_DEV_LIVE => "LIVE".to_string(),
-            sys::UBLK_S_DEV_QUIESCED => "QUIESCED".to_string(),
-            _ => "UNKNOWN".to_string(),
+    /// Return device state cached in `dev_info`
+    ///
+    /// Call `get_info()` first for retrieving the latest state from driver.
+    pub fn get_state(&self) -> UblkDevState {
+        UblkDevState::from(self.dev_info.state)
+    }
+
+    /// Wait until this device becomes the specified state
+    ///
+    /// # Arguments:
+    ///
+    /// * `state`: the expected device state
+    /// * `timeout`: how long to wait at most
+    ///
+    /// The driver doesn't send any notification(uevent) when device state
+    /// is changed, such as LIVE -> QUIESCED, so device state is retrieved
+    /// via GET_DEV_INFO periodically. -ETIMEDOUT is returned if the state
+    /// isn't reached in `timeout`.
+    pub fn wait_for_state(
+        &mut self,
+        state: UblkDevState,
+        timeout: std::time::Duration,
+    ) -> Result<i32, UblkError> {
+        let unit = std::time::Duration::from_millis(20);
+        let start = std::time::Instant::now();
+
+        loop {
+            self.get_info()?;
+            if self.get_state() == state {
+                return Ok(0);
+            }
+            if start.elapsed() >= timeout {
+                return Err(UblkError::OtherError(-libc::ETIMEDOUT));
+            }
+            std::thread::sleep(unit);
         }
     }
 
@@ -405,6 +925,20 @@ impl UblkCtrl {
         }
     }
 
+    /// Get device info from exported json file for this device
+    ///
+    /// It is the device info when the device is added, and is used for
+    /// checking if the recovered device matches with the original one.
+    pub fn get_dev_info_from_json(&self) -> Result<sys::ublksrv_ctrl_dev_info, UblkError> {
+        let info_val = &self.json["dev_info"];
+        let info: Result<sys::ublksrv_ctrl_dev_info, _> = serde_json::from_value(info_val.clone());
+        if let Ok(p) = info {
+            Ok(p)
+        } else {
+            Err(UblkError::OtherError(-libc::EINVAL))
+        }
+    }
+
     // Return target json data
     //
     // Should only be called after device is started, otherwise target data
@@ -528,7 +1062,7 @@ impl UblkCtrl {
             info.max_io_buf_bytes,
             info.ublksrv_pid,
             info.flags,
-            self.dev_state_desc()
+            self.get_state()
         );
         println!(
             "\tublkc: {}:{} ublkb: {}:{} owner: {}:{}",
@@ -539,18 +1073,170 @@ impl UblkCtrl {
             info.owner_uid,
             info.owner_gid
         );
+        println!(
+            "\tublkc: {} ublkb: {}",
+            self.get_cdev_path(),
+            self.get_bdev_path()
+        );
 
         self.dump_from_json();
     }
 
+    /// Return default running directory for storing exported json files
+    ///
+    /// `$LIBUBLK_RUN_DIR` is used if it is set, otherwise `$TMPDIR/ublk`
     pub fn run_dir() -> String {
-        format!("{}/ublk", std::env::temp_dir().display())
+        match std::env::var(Self::RUN_DIR_ENV) {
+            Ok(dir) if !dir.is_empty() => dir,
+            _ => format!("{}/ublk", std::env::temp_dir().display()),
+        }
+    }
+
+    /// Return per-user running directory, which is used for storing
+    /// json files of unprivileged devices created by non-root user
+    pub fn user_run_dir() -> String {
+        format!("{}/ublk.{}", std::env::temp_dir().display(), unsafe {
+            libc::geteuid()
+        })
+    }
+
+    /// Return default running directory for device with ublk `flags`
+    ///
+    /// `$LIBUBLK_RUN_DIR` is respected, otherwise json files of unprivileged
+    /// device created by non-root user are stored in `user_run_dir()`, and
+    /// the others are stored in `run_dir()`.
+    pub fn run_dir_for(flags: u64) -> String {
+        let unprivileged = (flags & sys::UBLK_F_UNPRIVILEGED_DEV as u64) != 0;
+
+        if std::env::var_os(Self::RUN_DIR_ENV).is_none()
+            && unprivileged
+            && unsafe { libc::geteuid() } != 0
+        {
+            Self::user_run_dir()
+        } else {
+            Self::run_dir()
+        }
+    }
+
+    /// Set running directory of this device
+    ///
+    /// Exported json and target state files are stored in this directory,
+    /// and json is reloaded from the new directory for existing device.
+    ///
+    /// # Arguments:
+    ///
+    /// * `dir`: running directory
+    ///
+    pub fn set_run_dir(&mut self, dir: &str) {
+        self.run_dir = dir.to_string();
+
+        if !self.for_add_dev() && self.dev_info.dev_id != u32::MAX {
+            if let Err(e) = self.reload_json() {
+                log::warn!("dev {}: reload json failed {:?}", self.dev_info.dev_id, e);
+            }
+        }
+    }
+
+    /// Return running directory of this device
+    pub fn get_run_dir(&self) -> &str {
+        &self.run_dir
+    }
+
+    /// Set permission and ownership of exported json files
+    ///
+    /// Has to be called before the device is started.
+    pub fn set_json_perm(&mut self, perm: UblkJsonPerm) {
+        self.json_perm = perm;
+    }
+
+    /// Return permission and ownership of exported json files
+    pub fn get_json_perm(&self) -> UblkJsonPerm {
+        self.json_perm
     }
 
     /// Returned path of this device's exported json file
     ///
     pub fn run_path(&self) -> String {
-        format!("{}/{:04}.json", UblkCtrl::run_dir(), self.dev_info.dev_id)
+        format!("{}/{:04}.json", self.run_dir, self.dev_info.dev_id)
+    }
+
+    /// Returned path of this device's target state file, which is written
+    /// by the serializer registered via `UblkDev::set_state_serializer()`
+    ///
+    pub fn state_path(&self) -> String {
+        format!("{}/{:04}.state.json", self.run_dir, self.dev_info.dev_id)
+    }
+
+    /// Load target state saved in this device's target state file
+    ///
+    /// Used for recovering target state after the old ublk server is crashed
+    pub fn get_target_state(&self) -> Result<serde_json::Value, UblkError> {
+        let mut file = fs::File::open(self.state_path()).map_err(UblkError::OtherIOError)?;
+        let mut json_str = String::new();
+
+        file.read_to_string(&mut json_str)
+            .map_err(UblkError::OtherIOError)?;
+        serde_json::from_str(&json_str).map_err(UblkError::JsonError)
+    }
+
+    fn remove_run_files(&self) -> Result<i32, UblkError> {
+        for p in [self.run_path(), self.state_path()] {
+            if Path::new(&p).exists() {
+                fs::remove_file(p).map_err(UblkError::OtherIOError)?;
+            }
+        }
+        Ok(0)
+    }
+
+    /// Write `buf` to `path` atomically
+    ///
+    /// Data is written and synced to one temporary file in the same directory
+    /// first, then the temporary file is renamed to `path`, so either the old
+    /// or the new file content can be observed even though the process or
+    /// system is crashed in the middle.
+    pub(crate) fn write_file_atomic(
+        path: &Path,
+        buf: &[u8],
+        perm: &UblkJsonPerm,
+    ) -> Result<i32, UblkError> {
+        let tmp_path = format!("{}.tmp.{}", path.display(), unsafe { libc::gettid() });
+        let tmp = Path::new(&tmp_path);
+        let res = Self::__write_file(tmp, buf, perm)
+            .and_then(|_| fs::rename(tmp, path).map_err(UblkError::OtherIOError))
+            .map(|_| 0);
+
+        if res.is_err() {
+            let _ = fs::remove_file(tmp);
+            return res;
+        }
+
+        // make the rename durable
+        if let Some(parent_dir) = path.parent() {
+            fs::File::open(parent_dir)
+                .and_then(|d| d.sync_all())
+                .map_err(UblkError::OtherIOError)?;
+        }
+        res
+    }
+
+    fn __write_file(path: &Path, buf: &[u8], perm: &UblkJsonPerm) -> Result<i32, UblkError> {
+        let mut file = fs::File::create(path).map_err(UblkError::OtherIOError)?;
+
+        Self::set_path_permission(path, perm.file_mode)?;
+        Self::set_path_owner(path, perm.owner)?;
+        file.write_all(buf).map_err(UblkError::OtherIOError)?;
+        file.sync_all().map_err(UblkError::OtherIOError)?;
+        Ok(0)
+    }
+
+    /// Create the running directory for storing json files
+    pub(crate) fn create_run_dir(dir: &Path, perm: &UblkJsonPerm) -> Result<i32, UblkError> {
+        if !dir.exists() {
+            fs::create_dir_all(dir).map_err(UblkError::OtherIOError)?;
+            Self::set_path_permission(dir, perm.dir_mode)?;
+            Self::set_path_owner(dir, perm.owner)?;
+        }
+        Ok(0)
     }
 
     fn ublk_ctrl_prep_cmd(
@@ -642,6 +1328,13 @@ impl UblkCtrl {
     }
 
     fn ublk_ctrl_cmd(&mut self, data: &UblkCtrlCmdData) -> Result<i32, UblkError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!(
+            "ublk_ctrl_cmd",
+            dev_id = self.dev_info.dev_id,
+            cmd_op = data.cmd_op
+        )
+        .entered();
         let mut data = *data;
         let to_wait = 1;
 
@@ -651,6 +1344,8 @@ impl UblkCtrl {
 
         data.unprep_un_privileged_dev_path(self, old_buf);
 
+        #[cfg(feature = "tracing")]
+        tracing::debug!(res = ?res, "ctrl cmd done");
         res
     }
 
@@ -687,10 +1382,7 @@ impl UblkCtrl {
     ///
     pub fn del_dev(&mut self) -> Result<i32, UblkError> {
         self.del()?;
-        if Path::new(&self.run_path()).exists() {
-            fs::remove_file(self.run_path()).map_err(UblkError::OtherIOError)?;
-        }
-        Ok(0)
+        self.remove_run_files()
     }
 
     fn __get_features(&mut self) -> Result<u64, UblkError> {
@@ -807,6 +1499,213 @@ impl UblkCtrl {
         self.ublk_ctrl_cmd(&data)
     }
 
+    /// Retrieve device numbers of ublk char & block device from driver
+    ///
+    /// Return `-EOPNOTSUPP` if the driver doesn't provide
+    /// `UBLK_PARAM_TYPE_DEVT`.
+    pub fn get_devt(&mut self) -> Result<sys::ublk_param_devt, UblkError> {
+        let mut p = sys::ublk_params {
+            ..Default::default()
+        };
+
+        self.get_params(&mut p)?;
+        if (p.types & sys::UBLK_PARAM_TYPE_DEVT) == 0 {
+            return Err(UblkError::OtherError(-libc::EOPNOTSUPP));
+        }
+        Ok(p.devt)
+    }
+
+    /// Return device node path of the device in sysfs directory `sysfs`
+    ///
+    /// The node name is from `DEVNAME` of the device's uevent file
+    pub(crate) fn devnode_from_sysfs(sysfs: &Path) -> std::io::Result<String> {
+        let uevent = fs::read_to_string(sysfs.join("uevent"))?;
+
+        uevent
+            .lines()
+            .find_map(|l| l.strip_prefix("DEVNAME="))
+            .map(|name| format!("/dev/{}", name))
+            .ok_or_else(|| std::io::Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    fn dev_node_match(path: &Path, block: bool, major: u32, minor: u32) -> bool {
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::fs::MetadataExt;
+
+        match fs::metadata(path) {
+            Ok(meta) => {
+                let ft = meta.file_type();
+                let is_dev = if block {
+                    ft.is_block_device()
+                } else {
+                    ft.is_char_device()
+                };
+
+                is_dev && Self::split_devt(meta.rdev()) == (major, minor)
+            }
+            _ => false,
+        }
+    }
+
+    /// Find device node of block or char device `major:minor`
+    ///
+    /// Return (node path, sysfs path). The node named by `DEVNAME` of the
+    /// device's uevent is preferred, then `/dev/{block,char}/MAJ:MIN`, and
+    /// finally all nodes under /dev are checked.
+    pub(crate) fn find_dev_node(
+        block: bool,
+        major: u32,
+        minor: u32,
+    ) -> std::io::Result<(String, String)> {
+        let kind = if block { "block" } else { "char" };
+        let sysfs = fs::canonicalize(format!("/sys/dev/{}/{}:{}", kind, major, minor))?;
+        let mut candidates = Vec::new();
+
+        if let Ok(path) = Self::devnode_from_sysfs(&sysfs) {
+            candidates.push(std::path::PathBuf::from(path));
+        }
+        candidates.push(format!("/dev/{}/{}:{}", kind, major, minor).into());
+
+        let found = candidates
+            .into_iter()
+            .find(|p| Self::dev_node_match(p, block, major, minor))
+            .map(|p| fs::canonicalize(&p).unwrap_or(p))
+            .or_else(|| {
+                fs::read_dir("/dev")
+                    .ok()?
+                    .flatten()
+                    .map(|e| e.path())
+                    .find(|p| !p.is_symlink() && Self::dev_node_match(p, block, major, minor))
+            });
+
+        match found {
+            Some(path) => Ok((path.display().to_string(), sysfs.display().to_string())),
+            None => Err(std::io::Error::from_raw_os_error(libc::ENOENT)),
+        }
+    }
+
+    /// Split device number into (major, minor)
+    pub(crate) fn split_devt(rdev: u64) -> (u32, u32) {
+        let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+        let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+
+        (major as u32, minor as u32)
+    }
+
+    /// Wait until ublk block device is ready after the device is started
+    ///
+    /// # Arguments:
+    ///
+    /// * `timeout`: max time for waiting
+    ///
+    /// The block device is located by its device number retrieved via
+    /// `UBLK_PARAM_TYPE_DEVT`, and it is ready when the device node is
+    /// present and openable. Partitions are scanned before START_DEV
+    /// returns, so partition nodes are usually ready too.
+    ///
+    /// Can be called from `device_fn` of `UblkSession::run_target()`.
+    pub fn wait_for_bdev(&mut self, timeout: std::time::Duration) -> Result<UblkBlkDev, UblkError> {
+        let devt = self.get_devt()?;
+        let (major, minor) = (devt.disk_major, devt.disk_minor);
+
+        self.devt = Some(devt);
+        let bdev = Self::wait_for_dev_node(timeout, || {
+            let (path, sysfs_path) = Self::find_dev_node(true, major, minor)?;
+
+            fs::File::open(&path)?;
+            Ok(UblkBlkDev {
+                major,
+                minor,
+                path,
+                sysfs_path,
+            })
+        })?;
+
+        self.bdev = Some(bdev.clone());
+        Ok(bdev)
+    }
+
+    fn update_size(&mut self, sectors: u64) -> Result<i32, UblkError> {
+        let data: UblkCtrlCmdData = UblkCtrlCmdData {
+            cmd_op: sys::UBLK_U_CMD_UPDATE_SIZE,
+            flags: CTRL_CMD_HAS_DATA,
+            data: sectors,
+            ..Default::default()
+        };
+
+        self.ublk_ctrl_cmd(&data)
+    }
+
+    /// Change size of this device
+    ///
+    /// # Arguments:
+    ///
+    /// * `dev`: ublk device
+    /// * `new_size`: new device size in bytes, has to be aligned with
+    ///   logical block size
+    ///
+    /// Live device is resized via UPDATE_SIZE if the device is created with
+    /// `UBLK_F_UPDATE_SIZE`. Otherwise parameters are updated via SET_PARAMS,
+    /// which is only allowed when the device isn't started, so live device
+    /// is quiesced by STOP_DEV first, and all queues exit, then the device
+    /// is restarted by `UblkSession::run_target()`, which sends the new size
+    /// via SET_PARAMS before START_DEV. The fallback requires the driver to
+    /// support starting stopped device.
+    ///
+    /// `dev_size` of exported json and `UblkDev::get_dev_size()` are updated
+    /// too, so recovered device uses the new size. -EINVAL is returned if
+    /// `new_size` is bigger than `UblkDev::get_max_dev_size()`.
+    pub fn resize(&mut self, dev: &UblkDev, new_size: u64) -> Result<i32, UblkError> {
+        let mut p = sys::ublk_params {
+            ..Default::default()
+        };
+
+        self.get_params(&mut p)?;
+        let lbs = 1_u64 << p.basic.logical_bs_shift;
+        if new_size == 0 || (new_size & (lbs - 1)) != 0 || new_size > dev.get_max_dev_size() {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        let features = UblkFeatures::from_bits_retain(self.dev_info.flags);
+        if features.contains(UblkFeatures::UPDATE_SIZE) {
+            self.update_size(new_size >> 9)?;
+            dev.set_dev_size(new_size);
+        } else {
+            self.get_info()?;
+            match self.get_state() {
+                UblkDevState::Dead => {
+                    p.basic.dev_sectors = new_size >> 9;
+                    self.set_params(&p)?;
+                    dev.set_dev_size(new_size);
+                }
+                UblkDevState::Live => {
+                    // new size is sent to driver when the device is
+                    // restarted after all queues exit
+                    let old_size = dev.get_dev_size();
+
+                    dev.set_dev_size(new_size);
+                    dev.set_restart();
+                    if let Err(e) = self.stop() {
+                        dev.take_restart();
+                        dev.set_dev_size(old_size);
+                        return Err(e);
+                    }
+                }
+                _ => return Err(UblkError::OtherError(-libc::EBUSY)),
+            }
+        }
+
+        if let Some(tgt) = self.json.get_mut("target") {
+            tgt["dev_size"] = serde_json::json!(new_size);
+            tgt["params"]["basic"]["dev_sectors"] = serde_json::json!(new_size >> 9);
+        }
+        // json is flushed when starting device
+        if self.json.get("target").is_some() {
+            self.flush_json()?;
+        }
+        Ok(0)
+    }
+
     /// Send this device's parameter to ublk driver
     ///
     /// Note: device parameter has to send to driver before starting
@@ -855,10 +1754,39 @@ impl UblkCtrl {
 
     /// Start user recover for this device
     ///
+    /// Wait until the device becomes quiesced first, then START_USER_RECOVERY
+    /// is retried if -EBUSY is returned because the old ublk server may not
+    /// release the char device yet.
     pub fn start_user_recover(&mut self) -> Result<i32, UblkError> {
+        self.start_user_recover_timeout(std::time::Duration::from_millis(30000))
+    }
+
+    /// Start user recover for this device after it becomes quiesced
+    ///
+    /// # Arguments:
+    ///
+    /// * `timeout`: how long to wait for the device to become quiesced
+    ///
+    /// The driver quiesces the device a while after the old ublk server is
+    /// gone, so the device may still be LIVE when recovery is started.
+    /// -ETIMEDOUT is returned if the device isn't quiesced in `timeout`,
+    /// and -EBUSY is returned at once if `timeout` is zero and the device
+    /// isn't quiesced.
+    pub fn start_user_recover_timeout(
+        &mut self,
+        timeout: std::time::Duration,
+    ) -> Result<i32, UblkError> {
         let mut count = 0u32;
         let unit = 100_u32;
 
+        if timeout.is_zero() {
+            self.get_info()?;
+            if self.get_state() != UblkDevState::Quiesced {
+                return Err(UblkError::OtherError(-libc::EBUSY));
+            }
+        } else {
+            self.wait_for_state(UblkDevState::Quiesced, timeout)?;
+        }
         loop {
             let res = self.__start_user_recover();
             if let Ok(r) = res {
@@ -889,12 +1817,18 @@ impl UblkCtrl {
 
     fn prep_start_dev(&mut self, dev: &UblkDev) -> Result<i32, UblkError> {
         self.get_info()?;
-        if self.dev_info.state == sys::UBLK_S_DEV_LIVE as u16 {
+        if self.get_state() == UblkDevState::Live {
             return Ok(0);
         }
 
-        if self.dev_info.state != sys::UBLK_S_DEV_QUIESCED as u16 {
-            self.set_params(&dev.tgt.params)?;
+        if self.get_state() != UblkDevState::Quiesced {
+            let mut params = dev.tgt.params;
+
+            // device may be resized before starting
+            if dev.get_dev_size() != dev.tgt.dev_size {
+                params.basic.dev_sectors = dev.get_dev_size() >> 9;
+            }
+            self.set_params(&params)?;
             self.flush_json()?;
         } else if self.for_recover_dev() {
             self.flush_json()?;
@@ -902,6 +1836,11 @@ impl UblkCtrl {
             return Err(crate::UblkError::OtherError(-libc::EINVAL));
         };
 
+        // save target state before IO is coming
+        if self.for_add_dev() || self.for_recover_dev() {
+            dev.checkpoint_state()?;
+        }
+
         Ok(0)
     }
 
@@ -917,13 +1856,17 @@ impl UblkCtrl {
     pub fn start_dev(&mut self, dev: &UblkDev) -> Result<i32, UblkError> {
         self.prep_start_dev(dev)?;
 
-        if self.dev_info.state != sys::UBLK_S_DEV_QUIESCED as u16 {
+        let res = if self.get_state() != UblkDevState::Quiesced {
             self.start(unsafe { libc::getpid() as i32 })
         } else if self.for_recover_dev() {
             self.end_user_recover(unsafe { libc::getpid() as i32 })
         } else {
             Err(crate::UblkError::OtherError(-libc::EINVAL))
-        }
+        }?;
+
+        // block device is available now
+        let _ = self.update_dev_nodes();
+        Ok(res)
     }
 
     /// submit starting of ublk device from queue daemon context
@@ -989,8 +1932,8 @@ impl UblkCtrl {
     /// Remove json export, and send stop command to control device
     ///
     pub fn stop_dev(&mut self, _dev: &UblkDev) -> Result<i32, UblkError> {
-        if self.for_add_dev() && Path::new(&self.run_path()).exists() {
-            fs::remove_file(self.run_path()).map_err(UblkError::OtherIOError)?;
+        if self.for_add_dev() {
+            self.remove_run_files()?;
         }
         self.stop()
     }
@@ -1007,6 +1950,14 @@ impl UblkCtrl {
         Ok(0)
     }
 
+    fn set_path_owner(path: &Path, owner: Option<(u32, u32)>) -> Result<i32, UblkError> {
+        if let Some((uid, gid)) = owner {
+            std::os::unix::fs::chown(path, Some(uid), Some(gid))
+                .map_err(UblkError::OtherIOError)?;
+        }
+        Ok(0)
+    }
+
     /// Flush this device's json info as file
     fn flush_json(&mut self) -> Result<i32, UblkError> {
         if self.json == serde_json::json!({}) {
@@ -1023,26 +1974,12 @@ impl UblkCtrl {
         let json_path = Path::new(&run_path);
 
         if let Some(parent_dir) = json_path.parent() {
-            if !Path::new(&parent_dir).exists() {
-                fs::create_dir_all(parent_dir).map_err(UblkError::OtherIOError)?;
-
-                // It is just fine to expose the running parent directory as
-                // 777, and we will make sure every exported running json
-                // file as 700.
-                Self::set_path_permission(parent_dir, 0o777)?;
-            }
+            Self::create_run_dir(parent_dir, &self.json_perm)?;
         }
-        let mut run_file = fs::File::create(json_path).map_err(UblkError::OtherIOError)?;
-
-        // Each exported json file is only visible for the device owner.
-        // In future, it can be relaxed, such as allowing group to access,
-        // according to ublk use policy
-        Self::set_path_permission(json_path, 0o700)?;
 
-        run_file
-            .write_all(self.json.to_string().as_bytes())
-            .map_err(UblkError::OtherIOError)?;
-        Ok(0)
+        // write json atomically, so the old json is still available
+        // for recovery if we are crashed in the middle
+        Self::write_file_atomic(json_path, self.json.to_string().as_bytes(), &self.json_perm)
     }
 
     /// Build json info for this device
@@ -1056,7 +1993,7 @@ impl UblkCtrl {
     ///
     fn build_json(&mut self, dev: &UblkDev) -> Result<i32, UblkError> {
         // keep everything not changed except for queue tid
-        if dev.dev_info.state == sys::UBLK_S_DEV_QUIESCED as u16 {
+        if UblkDevState::from(dev.dev_info.state) == UblkDevState::Quiesced {
             if let Some(queues) = self.json.get_mut("queues") {
                 for qid in 0..dev.dev_info.nr_hw_queues {
                     let t = format!("{}", qid);
@@ -1088,11 +2025,18 @@ impl UblkCtrl {
         }
 
         let mut json = serde_json::json!({
+                    "version": Self::JSON_VERSION,
                     "dev_info": dev.dev_info,
                     "target": dev.tgt,
                     "target_flags": dev.flags,
         });
 
+        if dev.get_dev_size() != dev.tgt.dev_size {
+            json["target"]["dev_size"] = serde_json::json!(dev.get_dev_size());
+            json["target"]["params"]["basic"]["dev_sectors"] =
+                serde_json::json!(dev.get_dev_size() >> 9);
+        }
+
         if let Some(val) = tgt_data {
             json["target_data"] = val.clone()
         }
@@ -1111,26 +2055,174 @@ impl UblkCtrl {
 
         file.read_to_string(&mut json_str)
             .map_err(UblkError::OtherIOError)?;
-        self.json = serde_json::from_str(&json_str).map_err(UblkError::JsonError)?;
+        let mut json = serde_json::from_str(&json_str).map_err(UblkError::JsonError)?;
+
+        Self::migrate_json(&mut json)?;
+        self.json = json;
 
         Ok(0)
     }
+
+    /// Convert json exported by older libublk into current version
+    ///
+    /// json written by newer libublk is rejected, since we can't
+    /// understand it.
+    fn migrate_json(json: &mut serde_json::Value) -> Result<i32, UblkError> {
+        // "version" isn't stored before v1, and v1 just adds "version"
+        let version = match json.get("version") {
+            None => 0,
+            Some(v) => match v.as_u64() {
+                Some(v) => v,
+                None => return Err(UblkError::OtherError(-libc::EINVAL)),
+            },
+        };
+
+        if version > Self::JSON_VERSION {
+            error!(
+                "json version {} isn't supported, max supported version {}",
+                version,
+                Self::JSON_VERSION
+            );
+            return Err(UblkError::OtherError(-libc::EPROTONOSUPPORT));
+        }
+
+        if version == 0 {
+            json["version"] = serde_json::json!(Self::JSON_VERSION);
+        }
+
+        Ok(0)
+    }
+
+    /// Return version of the loaded json
+    pub fn get_json_version(&self) -> Option<u64> {
+        self.json["version"].as_u64()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::dev_flags::*;
+    use crate::ctrl::{UblkFeatures, UblkJsonPerm};
     use crate::{ctrl::UblkCtrl, io::UblkDev, UblkSessionBuilder};
     use std::path::Path;
 
     #[test]
     fn test_ublk_get_features() {
         match UblkCtrl::get_features() {
-            Some(f) => eprintln!("features is {:04x}", f),
+            Some(f) => eprintln!(
+                "features is {:04x}: {}",
+                f,
+                UblkFeatures::from_bits_retain(f)
+            ),
             None => eprintln!("not support GET_FEATURES, require linux v6.5"),
         }
     }
 
+    #[test]
+    fn test_ublk_features_decode() {
+        let f = UblkFeatures::USER_COPY | UblkFeatures::UNPRIVILEGED_DEV;
+        assert!(f.names() == vec!["UBLK_F_UNPRIVILEGED_DEV", "UBLK_F_USER_COPY"]);
+
+        let f = UblkFeatures::from_bits_retain(crate::sys::UBLK_F_ZONED as u64 | (1_u64 << 63));
+        assert!(f.to_string() == "UBLK_F_ZONED | 0x8000000000000000");
+
+        let supported = UblkFeatures::USER_COPY | UblkFeatures::USER_RECOVERY;
+        assert!(supported.check(crate::sys::UBLK_F_USER_COPY as u64).is_ok());
+        assert!(
+            supported.check((crate::sys::UBLK_F_USER_COPY | crate::sys::UBLK_F_ZONED) as u64)
+                == Err(UblkFeatures::ZONED)
+        );
+    }
+
+    #[test]
+    fn test_ublk_dev_state() {
+        use crate::ctrl::UblkDevState;
+
+        assert!(UblkDevState::from(crate::sys::UBLK_S_DEV_LIVE as u16) == UblkDevState::Live);
+        assert!(UblkDevState::from(0x100) == UblkDevState::Unknown(0x100));
+        assert!(UblkDevState::Quiesced.to_string() == "QUIESCED");
+    }
+
+    #[test]
+    fn test_write_file_atomic() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("0000.state.json");
+
+        let perm = UblkJsonPerm::default();
+
+        UblkCtrl::write_file_atomic(&path, b"{\"a\":1}", &perm).unwrap();
+        UblkCtrl::write_file_atomic(&path, b"{\"a\":2}", &perm).unwrap();
+        assert!(std::fs::read_to_string(&path).unwrap() == "{\"a\":2}");
+
+        // no temporary file is left
+        assert!(std::fs::read_dir(dir.path()).unwrap().count() == 1);
+    }
+
+    #[test]
+    fn test_dev_owner_by_invalid_name() {
+        assert!(UblkCtrl::get_dev_owner_by_name("").is_none());
+        assert!(UblkCtrl::get_dev_owner_by_name("ublkb").is_none());
+        assert!(UblkCtrl::get_dev_owner_by_name("ublkx0").is_none());
+        assert!(UblkCtrl::get_dev_owner_by_name("ublkc-1").is_none());
+    }
+
+    #[test]
+    fn test_wait_for_dev_node() {
+        use std::time::Duration;
+
+        let res = UblkCtrl::wait_for_dev_node(Duration::from_millis(50), || {
+            std::fs::File::open("/dev/ublk-not-exist")
+        });
+        match res {
+            Err(crate::UblkError::OtherIOError(e)) => {
+                assert!(e.raw_os_error() == Some(libc::ENOENT))
+            }
+            _ => panic!(),
+        }
+
+        let mut cnt = 0;
+        let res = UblkCtrl::wait_for_dev_node(Duration::from_secs(3), || {
+            cnt += 1;
+            if cnt < 3 {
+                Err(std::io::Error::from_raw_os_error(libc::EACCES))
+            } else {
+                Ok(cnt)
+            }
+        });
+        assert!(res.unwrap() == 3);
+    }
+
+    #[test]
+    fn test_devnode_from_sysfs() {
+        let dir = tempfile::tempdir().unwrap();
+        let uevent = "MAJOR=259\nMINOR=1\nDEVNAME=ublkb1\nDEVTYPE=disk\n";
+
+        std::fs::write(dir.path().join("uevent"), uevent).unwrap();
+        assert!(UblkCtrl::devnode_from_sysfs(dir.path()).unwrap() == "/dev/ublkb1");
+
+        assert!(UblkCtrl::split_devt(0x10301) == (259, 1));
+    }
+
+    #[test]
+    fn test_find_dev_node() {
+        // /dev/null is always char device 1:3
+        let (path, _) = UblkCtrl::find_dev_node(false, 1, 3).unwrap();
+        assert!(path == "/dev/null");
+
+        assert!(UblkCtrl::find_dev_node(true, 1, 3).is_err());
+    }
+
+    #[test]
+    fn test_json_migrate() {
+        let mut json = serde_json::json!({"dev_info": {}, "target": {}});
+
+        UblkCtrl::migrate_json(&mut json).unwrap();
+        assert!(json["version"] == UblkCtrl::JSON_VERSION);
+
+        let mut json = serde_json::json!({"version": UblkCtrl::JSON_VERSION + 1});
+        assert!(UblkCtrl::migrate_json(&mut json).is_err());
+    }
+
     #[test]
     fn test_add_ctrl_dev() {
         let ctrl = UblkCtrl::new(-1, 1, 64, 512_u32 * 1024, 0, 0, UBLK_DEV_F_ADD_DEV).unwrap();
@@ -79,6 +79,65 @@ impl UringOpFutureMultiShot {
     }
 }
 
+/// Wait for a batch of uring OPs issued concurrently from one io task
+///
+/// The i-th OP has to be submitted with user_data of `user_data` plus
+/// `(i + 1) << 24`, that is, `i + 1` is passed as `tgt_data` or `op_id`
+/// of `UblkIOCtx::build_user_data()`, and `.await` returns results of all
+/// OPs in submission order.
+///
+/// Unlike joining multiple `UringOpFuture`, it works with any number of
+/// OPs, since it doesn't depend on waker.
+pub struct UringOpFutureBatch {
+    user_data: u64,
+    res: Vec<Option<i32>>,
+    pending: usize,
+}
+
+impl UringOpFutureBatch {
+    const IDX_SHIFT: u32 = 24;
+    const IDX_MASK: u64 = 0xffff;
+
+    pub fn new(user_data: u64, nr_ops: usize) -> UringOpFutureBatch {
+        assert!(nr_ops as u64 <= Self::IDX_MASK);
+
+        UringOpFutureBatch {
+            user_data: user_data & !(Self::IDX_MASK << Self::IDX_SHIFT),
+            res: vec![None; nr_ops],
+            pending: nr_ops,
+        }
+    }
+}
+
+impl Future for UringOpFutureBatch {
+    type Output = Vec<i32>;
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Self::Output> {
+        let me = self.get_mut();
+        let cqe = Executor::get_thread_local_cqe();
+
+        if !cqe.is_null() {
+            let data = unsafe { (*cqe).user_data() };
+            let idx = ((data >> Self::IDX_SHIFT) & Self::IDX_MASK) as usize;
+
+            if (data & !(Self::IDX_MASK << Self::IDX_SHIFT)) == me.user_data
+                && idx >= 1
+                && idx <= me.res.len()
+                && me.res[idx - 1].is_none()
+            {
+                Executor::set_thread_local_cqe(std::ptr::null());
+                me.res[idx - 1] = Some(unsafe { (*cqe).result() });
+                me.pending -= 1;
+            }
+        }
+
+        if me.pending == 0 {
+            Poll::Ready(me.res.iter().map(|r| r.unwrap_or(0)).collect())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
 pub struct Task<'a> {
     cnt: i16,
     tag: u16,
@@ -361,6 +420,26 @@ mod tests {
         assert!(__test_uring_wakeup(&e, 0, k2, res2));
     }
 
+    /// Test if uring batch future collects results in submission order
+    #[test]
+    fn test_executor_uring_batch() {
+        let k: u64 = 0x8000_0000_0000_0003;
+        let e = Executor::new(4);
+
+        e.spawn(3, async move {
+            let res = UringOpFutureBatch::new(k, 40).await;
+            assert!(res == (1..=40).collect::<Vec<i32>>());
+        });
+        assert!(!e.tick(3));
+
+        // complete in reverse order, and CQE of other user_data is ignored
+        for i in (1..=40_u64).rev() {
+            assert!(!__test_uring_wakeup(&e, 3, k | (0x100 << 24), -1));
+            assert!(__test_uring_wakeup(&e, 3, k | (i << 24), i as i32) == (i == 1));
+        }
+        assert!(UringOpFutureBatch::new(k, 0).res.is_empty());
+    }
+
     /// Test Waker
     ///
     /// Also one simple prototype of spawn_blocking() for offloading
@@ -1,12 +1,16 @@
+use super::ctrl::{UblkCtrl, UblkJsonPerm};
 use super::dev_flags::*;
+use super::stats::{UblkStats, UblkStatsCounter};
 #[cfg(feature = "fat_complete")]
 use super::UblkFatRes;
-use super::{ctrl::UblkCtrl, exe::Executor, exe::UringOpFuture, sys, UblkError, UblkIORes};
+use super::{exe::Executor, exe::UringOpFuture, sys, UblkError, UblkIORes};
 use io_uring::{cqueue, opcode, squeue, types, IoUring};
 use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
+use std::cell::{RefCell, UnsafeCell};
 use std::fs;
 use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
 
 /// UblkIOCtx
 ///
@@ -24,7 +28,7 @@ use std::os::unix::io::AsRawFd;
 /// UblkIOCtx & UblkQueue provide enough information for target code to
 /// handle this CQE and implement target IO handling logic.
 ///
-pub struct UblkIOCtx<'a>(&'a cqueue::Entry, u32);
+pub struct UblkIOCtx<'a>(pub(crate) &'a cqueue::Entry, pub(crate) u32);
 
 impl<'a> UblkIOCtx<'a> {
     const UBLK_IO_F_FIRST: u32 = 1u32 << 16;
@@ -170,6 +174,9 @@ pub struct UblkTgt {
     pub tgt_type: String,
 
     /// target device size, will be the actual size of /dev/ublkbN
+    ///
+    /// It is the initial size, and `UblkDev::get_dev_size()` returns the
+    /// current size after the device is resized.
     pub dev_size: u64,
 
     /// target specific io_ring flags, default is 0
@@ -211,8 +218,33 @@ pub struct UblkDev {
 
     pub tgt: UblkTgt,
     tgt_json: Option<serde_json::Value>,
+
+    /// target state serializer and the file for storing target state
+    state_fn: Option<Box<UblkStateFn>>,
+    state_path: String,
+    state_perm: UblkJsonPerm,
+    /// serializes snapshot and rename of the state file
+    state_lock: Mutex<()>,
+
+    /// target state loaded in case of recovering device
+    recovered_state: Option<serde_json::Value>,
+
+    /// current device size, updated by `UblkCtrl::resize()`
+    dev_size: AtomicU64,
+
+    /// max size supported by target, see `UblkDev::set_max_dev_size()`
+    max_dev_size: u64,
+
+    /// device is stopped by `UblkCtrl::resize()`, and has to be restarted
+    restart: AtomicBool,
+
+    /// per-queue stats, only allocated for UBLK_DEV_F_STATS
+    stats: Vec<UblkStatsCounter>,
 }
 
+/// Target state serializer, see `UblkDev::set_state_serializer()`
+pub type UblkStateFn = dyn Fn(&UblkDev) -> Result<serde_json::Value, UblkError> + Send + Sync;
+
 unsafe impl Send for UblkDev {}
 unsafe impl Sync for UblkDev {}
 
@@ -241,27 +273,11 @@ impl UblkDev {
             ring_flags: 0,
             ..Default::default()
         };
-        let mut cnt = 0;
-        let cdev_path = ctrl.get_cdev_path();
 
         // ublk char device setup(udev event handling, ...) may not be done
-        // successfully, so wait a while. And the timeout is set as 3sec now.
-        let cdev_file = loop {
-            let f_result = fs::OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(&cdev_path);
-
-            if let Ok(f) = f_result {
-                break f;
-            }
-
-            cnt += 1;
-            std::thread::sleep(std::time::Duration::from_millis(10));
-            if cnt >= 300 {
-                return Err(UblkError::OtherError(-libc::EACCES));
-            }
-        };
+        // yet, so wait until it is ready, and the real open error is
+        // returned on timeout
+        let cdev_file = ctrl.open_cdev()?;
 
         tgt.fds[0] = cdev_file.as_raw_fd();
         tgt.nr_fds = 1;
@@ -272,9 +288,25 @@ impl UblkDev {
             tgt,
             flags: ctrl.get_dev_flags(),
             tgt_json: None,
+            state_fn: None,
+            state_path: ctrl.state_path(),
+            state_perm: ctrl.get_json_perm(),
+            state_lock: Mutex::new(()),
+            recovered_state: None,
+            dev_size: AtomicU64::new(0),
+            max_dev_size: u64::MAX,
+            restart: AtomicBool::new(false),
+            stats: if (ctrl.get_dev_flags() & UBLK_DEV_F_STATS) != 0 {
+                (0..info.nr_hw_queues)
+                    .map(|_| UblkStatsCounter::default())
+                    .collect()
+            } else {
+                Vec::new()
+            },
         };
 
         ops(&mut dev)?;
+        dev.dev_size = AtomicU64::new(dev.tgt.dev_size);
         log::info!("dev {} initialized", dev.dev_info.dev_id);
 
         Ok(dev)
@@ -319,6 +351,136 @@ impl UblkDev {
         }
     }
 
+    /// Register target state serializer
+    ///
+    /// The returned json value is written to the device's state file
+    /// atomically when the device is started or recovered, and whenever
+    /// target code calls `checkpoint_state()`, such as after handling
+    /// FLUSH. After the ublk server is crashed, the last checkpointed state
+    /// is handed back via `get_recovered_state()` in recovery.
+    pub fn set_state_serializer<F>(&mut self, f: F)
+    where
+        F: Fn(&UblkDev) -> Result<serde_json::Value, UblkError> + Send + Sync + 'static,
+    {
+        self.state_fn = Some(Box::new(f));
+    }
+
+    /// Serialize target state and write it to the state file atomically
+    ///
+    /// Can be called from any queue context, and it is no-op if no
+    /// serializer is registered. Snapshot and rename are done with one
+    /// lock held, so the state file never goes back to older snapshot.
+    /// It blocks for write and fsync, so target may call it from one
+    /// dedicated thread.
+    pub fn checkpoint_state(&self) -> Result<i32, UblkError> {
+        let _guard = self.state_lock.lock().unwrap();
+        let state = match self.state_fn.as_ref() {
+            Some(f) => f(self)?,
+            None => return Ok(0),
+        };
+        let path = std::path::Path::new(&self.state_path);
+
+        if let Some(parent_dir) = path.parent() {
+            UblkCtrl::create_run_dir(parent_dir, &self.state_perm)?;
+        }
+        UblkCtrl::write_file_atomic(path, state.to_string().as_bytes(), &self.state_perm)
+    }
+
+    pub(crate) fn set_recovered_state(&mut self, state: Option<serde_json::Value>) {
+        self.recovered_state = state;
+    }
+
+    /// Return IO statistics of queue `qid`
+    ///
+    /// None is returned if UBLK_DEV_F_STATS isn't set. Can be called
+    /// from any thread, such as the thread for exporting metrics.
+    pub fn get_queue_stats(&self, qid: u16) -> Option<UblkStats> {
+        self.stats.get(qid as usize).map(|c| c.snapshot())
+    }
+
+    /// Return IO statistics of the whole device, which are merged from
+    /// all queues
+    pub fn get_stats(&self) -> Option<UblkStats> {
+        if self.stats.is_empty() {
+            return None;
+        }
+
+        let mut total = UblkStats::default();
+        for c in &self.stats {
+            total.merge(&c.snapshot());
+        }
+        Some(total)
+    }
+
+    /// Return current device size in bytes
+    ///
+    /// Same with `tgt.dev_size` unless the device is resized by
+    /// `UblkCtrl::resize()`.
+    pub fn get_dev_size(&self) -> u64 {
+        self.dev_size.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_dev_size(&self, size: u64) {
+        self.dev_size.store(size, Ordering::Relaxed);
+    }
+
+    /// Set max device size supported by target
+    ///
+    /// # Arguments:
+    ///
+    /// * `size`: max size in bytes
+    ///
+    /// Called in target init closure by target which can't grow, such as
+    /// target with fixed layout, then `UblkCtrl::resize()` fails if the
+    /// new size is bigger than `size`. Default is unlimited.
+    pub fn set_max_dev_size(&mut self, size: u64) {
+        self.max_dev_size = size;
+    }
+
+    /// Return max device size supported by target
+    pub fn get_max_dev_size(&self) -> u64 {
+        self.max_dev_size
+    }
+
+    /// Mark the device as stopped for being restarted with new size
+    pub(crate) fn set_restart(&self) {
+        self.restart.store(true, Ordering::Release);
+    }
+
+    /// Return if the device needs to be restarted, and clear the mark
+    pub(crate) fn take_restart(&self) -> bool {
+        self.restart.swap(false, Ordering::AcqRel)
+    }
+
+    /// Re-open ublk char device before restarting the stopped device
+    ///
+    /// The driver resets the stopped device when its char device is
+    /// released, so close the char device and open it again. The fd
+    /// number is kept since it is registered to queue ring as `fds[0]`.
+    pub(crate) fn reopen_cdev(&self, ctrl: &mut UblkCtrl) -> Result<i32, UblkError> {
+        let fd = self.cdev_file.as_raw_fd();
+        let null = fs::File::open("/dev/null").map_err(UblkError::OtherIOError)?;
+
+        // release the char device, and hold the fd number by /dev/null
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+            return Err(UblkError::OtherIOError(std::io::Error::last_os_error()));
+        }
+
+        let cdev = ctrl.open_cdev()?;
+        if unsafe { libc::dup2(cdev.as_raw_fd(), fd) } < 0 {
+            return Err(UblkError::OtherIOError(std::io::Error::last_os_error()));
+        }
+        Ok(0)
+    }
+
+    /// Return target state saved by the old ublk server
+    ///
+    /// Only available in case of recovering device, and None is returned
+    /// if no target state is saved.
+    pub fn get_recovered_state(&self) -> Option<&serde_json::Value> {
+        self.recovered_state.as_ref()
+    }
+
     /// Return how many io slots, which is usually same with executor's
     /// nr_tasks.
     #[inline]
@@ -414,6 +576,18 @@ pub struct UblkQueue<'a> {
     bufs: Vec<*mut u8>,
     state: RefCell<UblkQueueState>,
 
+    /// stats counter & fetch time of each tag, for UBLK_DEV_F_STATS
+    stats: Option<&'a UblkStatsCounter>,
+    io_start: RefCell<Vec<std::time::Instant>>,
+
+    /// span of each IO tag, from IO being fetched to being committed
+    #[cfg(feature = "tracing")]
+    io_spans: RefCell<Vec<tracing::Span>>,
+
+    /// per-tag IO descriptor & result overriding the ones from driver &
+    /// target, see `override_io()`
+    io_override: Vec<UnsafeCell<Option<(sys::ublksrv_io_desc, i32)>>>,
+
     /// uring is shared for handling target IO, so has to be
     /// public
     pub q_ring: RefCell<IoUring<squeue::Entry>>,
@@ -552,6 +726,15 @@ impl UblkQueue<'_> {
             }),
             q_ring: RefCell::new(ring),
             bufs,
+            stats: dev.stats.get(q_id as usize),
+            io_start: RefCell::new(if dev.stats.is_empty() {
+                Vec::new()
+            } else {
+                vec![std::time::Instant::now(); depth as usize]
+            }),
+            #[cfg(feature = "tracing")]
+            io_spans: RefCell::new(vec![tracing::Span::none(); depth as usize]),
+            io_override: (0..depth).map(|_| UnsafeCell::new(None)).collect(),
         };
 
         // async/.await needn't to submit FETCH_REQ command beforehand
@@ -592,10 +775,152 @@ impl UblkQueue<'_> {
     #[inline(always)]
     pub fn get_iod(&self, tag: u16) -> &sys::ublksrv_io_desc {
         assert!((tag as u32) < self.q_depth);
+        if let Some((iod, _)) = unsafe { &*self.io_override[tag as usize].get() } {
+            return iod;
+        }
         let iod = (self.io_cmd_buf + tag as u64 * 24) as *const sys::ublksrv_io_desc;
         unsafe { &*iod }
     }
 
+    /// Override IO descriptor & result of IO command `tag`
+    ///
+    /// # Arguments:
+    ///
+    /// * `tag`: io command tag
+    /// * `iod`: returned from `get_iod()` instead of the one from driver
+    /// * `res`: committed instead of target's result if target doesn't
+    ///   fail the IO
+    ///
+    /// Has to be called before handing the IO command to target, and the
+    /// override is dropped when the IO command is committed, so target
+    /// can't use the returned `iod` after completing the IO command.
+    pub(crate) fn override_io(&self, tag: u16, iod: sys::ublksrv_io_desc, res: i32) {
+        assert!((tag as u32) < self.q_depth);
+        unsafe {
+            *self.io_override[tag as usize].get() = Some((iod, res));
+        }
+    }
+
+    /// Drop override of IO `tag` when committing it, and return the
+    /// result to be committed
+    #[inline(always)]
+    fn commit_override(&self, tag: u16, cmd_op: u32, res: i32) -> i32 {
+        if (cmd_op == sys::UBLK_IO_COMMIT_AND_FETCH_REQ
+            || cmd_op == sys::UBLK_U_IO_COMMIT_AND_FETCH_REQ)
+            && (tag as u32) < self.q_depth
+        {
+            if let Some((_, r)) = unsafe { (*self.io_override[tag as usize].get()).take() } {
+                return if res < 0 { res } else { r };
+            }
+        }
+        res
+    }
+
+    /// Return IO statistics of this queue
+    ///
+    /// None is returned if UBLK_DEV_F_STATS isn't set.
+    pub fn get_stats(&self) -> Option<UblkStats> {
+        self.stats.map(|c| c.snapshot())
+    }
+
+    #[inline(always)]
+    fn stats_fetch(&self, tag: u16) {
+        if let Some(c) = self.stats {
+            let iod = self.get_iod(tag);
+
+            c.account_fetch(
+                iod.op_flags & 0xff,
+                (iod.nr_sectors as u64) << 9,
+                self.get_inflight_nr_io() as u64,
+            );
+            self.io_start.borrow_mut()[tag as usize] = std::time::Instant::now();
+        }
+    }
+
+    #[inline(always)]
+    fn stats_commit(&self, tag: u16, cmd_op: u32, res: i32) {
+        if let Some(c) = self.stats {
+            if (cmd_op == sys::UBLK_IO_COMMIT_AND_FETCH_REQ
+                || cmd_op == sys::UBLK_U_IO_COMMIT_AND_FETCH_REQ)
+                && (tag as u32) < self.q_depth
+            {
+                c.account_commit(res, self.io_start.borrow()[tag as usize].elapsed());
+            }
+        }
+    }
+
+    /// Run `f` in span of IO `tag`
+    #[cfg(feature = "tracing")]
+    #[inline(always)]
+    fn in_io_span<R, F: FnOnce() -> R>(&self, tag: u16, f: F) -> R {
+        self.io_span(tag).in_scope(f)
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    #[inline(always)]
+    fn in_io_span<R, F: FnOnce() -> R>(&self, _tag: u16, f: F) -> R {
+        f()
+    }
+
+    #[cfg(feature = "tracing")]
+    #[inline(always)]
+    fn trace_fetch(&self, tag: u16) {
+        let iod = self.get_iod(tag);
+        let span = tracing::trace_span!(
+            "ublk_io",
+            qid = self.q_id,
+            tag,
+            op = iod.op_flags & 0xff,
+            res = tracing::field::Empty
+        );
+
+        span.in_scope(|| {
+            tracing::trace!(
+                start_sector = iod.start_sector,
+                nr_sectors = iod.nr_sectors,
+                "fetch"
+            )
+        });
+        self.io_spans.borrow_mut()[tag as usize] = span;
+    }
+
+    /// Return span of IO `tag`, which is created when the IO is fetched
+    #[cfg(feature = "tracing")]
+    pub(crate) fn io_span(&self, tag: u16) -> tracing::Span {
+        match self.io_spans.borrow().get(tag as usize) {
+            Some(s) => s.clone(),
+            None => tracing::Span::none(),
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[inline(always)]
+    pub(crate) fn trace_tgt_submit(&self, tag: u16) {
+        let spans = self.io_spans.borrow();
+
+        // not committed yet, so IO is being handled by target
+        if !spans[tag as usize].is_none() {
+            spans[tag as usize].in_scope(|| tracing::trace!("tgt_submit"));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[inline(always)]
+    fn trace_commit(&self, tag: u16, cmd_op: u32, res: i32) {
+        if (cmd_op == sys::UBLK_IO_COMMIT_AND_FETCH_REQ
+            || cmd_op == sys::UBLK_U_IO_COMMIT_AND_FETCH_REQ)
+            && (tag as u32) < self.q_depth
+        {
+            let span = std::mem::replace(
+                &mut self.io_spans.borrow_mut()[tag as usize],
+                tracing::Span::none(),
+            );
+
+            span.record("res", res);
+            span.in_scope(|| tracing::trace!(res, "commit"));
+        }
+    }
+
     #[inline(always)]
     pub fn get_io_buf_addr(&self, tag: u16) -> *mut u8 {
         self.bufs[tag as usize]
@@ -618,6 +943,7 @@ impl UblkQueue<'_> {
         user_data: u64,
         res: i32,
     ) -> i32 {
+        let res = self.commit_override(tag, cmd_op, res);
         let mut state = self.state.borrow_mut();
         if state.is_stopping() {
             return 0;
@@ -648,6 +974,9 @@ impl UblkQueue<'_> {
         }
 
         state.inc_cmd_inflight();
+        self.stats_commit(tag, cmd_op, res);
+        #[cfg(feature = "tracing")]
+        self.trace_commit(tag, cmd_op, res);
 
         log::trace!(
             "{}: (qid {} tag {} cmd_op {}) stopping {}",
@@ -849,7 +1178,11 @@ impl UblkQueue<'_> {
                     UblkIOCtx::user_data_to_op(data)
                 );
             }
-            ops(self, tag as u16, e);
+            self.in_io_span(tag as u16, || {
+                #[cfg(feature = "tracing")]
+                tracing::trace!(op = cmd_op, res, "tgt_complete");
+                ops(self, tag as u16, e)
+            });
             return;
         }
 
@@ -857,7 +1190,12 @@ impl UblkQueue<'_> {
 
         if res == sys::UBLK_IO_RES_OK as i32 {
             assert!(tag < self.q_depth);
-            ops(self, tag as u16, e);
+            self.stats_fetch(tag as u16);
+            #[cfg(feature = "tracing")]
+            self.trace_fetch(tag as u16);
+            self.in_io_span(tag as u16, || ops(self, tag as u16, e));
+            #[cfg(feature = "tracing")]
+            self.trace_tgt_submit(tag as u16);
         }
     }
 
@@ -914,6 +1252,9 @@ impl UblkQueue<'_> {
                 self.q_id
             );
             state.set_idle(true);
+            if let Some(c) = self.stats {
+                c.account_idle();
+            }
             self.discard_io_pages();
         }
     }
@@ -1071,13 +1412,16 @@ impl UblkQueue<'_> {
     /// Called in queue context. won't return unless error is observed.
     /// Wait and handle any incoming cqe until queue is down.
     ///
-    pub fn wait_and_handle_io<F>(&self, mut ops: F)
+    /// Return `Ok(())` if queue is down, otherwise the observed error is
+    /// returned, such as `UblkError::UringSubmissionError`.
+    pub fn wait_and_handle_io<F>(&self, mut ops: F) -> Result<(), UblkError>
     where
         F: FnMut(&UblkQueue, u16, &UblkIOCtx),
     {
         loop {
             match self.process_ios(&mut ops, 1) {
-                Err(_) => break,
+                Err(UblkError::QueueIsDown(_)) => return Ok(()),
+                Err(e) => return Err(e),
                 _ => continue,
             }
         }
@@ -1117,6 +1461,25 @@ impl UblkQueue<'_> {
                     let user_data = cqe.user_data();
                     if UblkIOCtx::is_io_command(user_data) {
                         self.update_state(&cqe);
+
+                        // io command is fetched, and io task is woken up
+                        // for handling it
+                        if cqe.result() == sys::UBLK_IO_RES_OK as i32 {
+                            let tag = UblkIOCtx::user_data_to_tag(user_data) as u16;
+
+                            self.stats_fetch(tag);
+                            #[cfg(feature = "tracing")]
+                            self.trace_fetch(tag);
+                        }
+                    } else {
+                        #[cfg(feature = "tracing")]
+                        self.in_io_span(UblkIOCtx::user_data_to_tag(user_data) as u16, || {
+                            tracing::trace!(
+                                op = UblkIOCtx::user_data_to_op(user_data),
+                                res = cqe.result(),
+                                "tgt_complete"
+                            )
+                        });
                     }
                     wake_handler(user_data, &cqe, i == done - 1);
                 }
@@ -1135,14 +1498,18 @@ impl UblkQueue<'_> {
     /// Wait and handle any incoming cqe until queue is down.
     ///
     /// This should be the only foreground thing done in queue thread.
-    pub fn wait_and_wake_io_tasks(&self, exe: &Executor) {
+    ///
+    /// Return `Ok(())` if queue is down, otherwise the observed error is
+    /// returned.
+    pub fn wait_and_wake_io_tasks(&self, exe: &Executor) -> Result<(), UblkError> {
         let wake_handler = |data: u64, cqe: &cqueue::Entry, _last: bool| {
             let tag = UblkIOCtx::user_data_to_tag(data);
             exe.wake_with_uring_cqe(tag as u16, &cqe);
         };
         loop {
             match self.flush_and_wake_io_tasks(wake_handler, 1) {
-                Err(_) => break,
+                Err(UblkError::QueueIsDown(_)) => return Ok(()),
+                Err(e) => return Err(e),
                 _ => continue,
             }
         }
@@ -12,7 +12,11 @@ use std::sync::Arc;
 pub mod ctrl;
 pub mod exe;
 pub mod io;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod stats;
 pub mod sys;
+pub mod targets;
 
 pub mod dev_flags {
     /// feature: support IO batch completion from single IO tag, typical
@@ -33,11 +37,15 @@ pub mod dev_flags {
     /// together
     pub const UBLK_DEV_F_DONT_ALLOC_BUF: u32 = 1u32 << 4;
 
+    /// collect per-queue IO statistics, see `UblkDev::get_stats()`
+    pub const UBLK_DEV_F_STATS: u32 = 1u32 << 5;
+
     pub const UBLK_DEV_F_ALL: u32 = UBLK_DEV_F_COMP_BATCH
         | UBLK_DEV_F_ADD_DEV
         | UBLK_DEV_F_RECOVER_DEV
         | UBLK_DEV_F_ASYNC
-        | UBLK_DEV_F_DONT_ALLOC_BUF;
+        | UBLK_DEV_F_DONT_ALLOC_BUF
+        | UBLK_DEV_F_STATS;
 }
 
 /// Ublk Fat completion result
@@ -97,6 +105,9 @@ pub enum UblkError {
     #[error("IO Queued")]
     IoQueued(i32),
 
+    #[error("unsupported ublk features: {0}")]
+    UnsupportedFeatures(ctrl::UblkFeatures),
+
     #[error("other failure")]
     OtherError(i32),
 }
@@ -167,6 +178,23 @@ pub struct UblkSession {
     /// libublk feature flags: UBLK_DEV_F_*
     #[builder(default = "0")]
     dev_flags: u32,
+
+    /// permission and ownership of exported json files
+    #[builder(default)]
+    json_perm: ctrl::UblkJsonPerm,
+
+    /// timeout for waiting ublk char device to be ready
+    #[builder(default = "std::time::Duration::from_secs(3)")]
+    cdev_timeout: std::time::Duration,
+
+    /// running directory for storing json files, `UblkCtrl::run_dir()`
+    /// is used if it isn't set
+    #[builder(default, setter(into, strip_option))]
+    run_dir: Option<String>,
+
+    /// timeout for waiting the recovered device to become quiesced
+    #[builder(default = "std::time::Duration::from_secs(30)")]
+    recover_timeout: std::time::Duration,
 }
 
 impl UblkSession {
@@ -175,7 +203,37 @@ impl UblkSession {
     where
         T: Fn(u32) + Clone + 'static,
     {
-        if let Ok(entries) = std::fs::read_dir(ctrl::UblkCtrl::run_dir()) {
+        let dir = ctrl::UblkCtrl::run_dir();
+        let user_dir = ctrl::UblkCtrl::run_dir_for(sys::UBLK_F_UNPRIVILEGED_DEV as u64);
+
+        Self::for_each_dev_id_in(&dir, ops.clone());
+
+        // unprivileged devices of current user
+        if user_dir != dir {
+            Self::for_each_dev_id_in(&user_dir, ops);
+        }
+    }
+
+    /// iterator over each ublk device ID of this session
+    ///
+    /// Only the configured `run_dir` is scanned if it is set, otherwise
+    /// it is same with `for_each_dev_id()`.
+    pub fn for_each_session_dev_id<T>(&self, ops: T)
+    where
+        T: Fn(u32) + Clone + 'static,
+    {
+        match &self.run_dir {
+            Some(dir) => Self::for_each_dev_id_in(dir, ops),
+            None => Self::for_each_dev_id(ops),
+        }
+    }
+
+    /// iterator over each ublk device ID stored in running directory `dir`
+    pub fn for_each_dev_id_in<T>(dir: &str, ops: T)
+    where
+        T: Fn(u32) + Clone + 'static,
+    {
+        if let Ok(entries) = std::fs::read_dir(dir) {
             for entry in entries.flatten() {
                 let f = entry.path();
                 if f.is_file() {
@@ -191,6 +249,27 @@ impl UblkSession {
         }
     }
 
+    /// Return running directory of this session
+    pub fn get_run_dir(&self) -> String {
+        match &self.run_dir {
+            Some(dir) => dir.clone(),
+            None => ctrl::UblkCtrl::run_dir_for(self.ctrl_flags),
+        }
+    }
+
+    /// Prepare unprivileged device for current user
+    ///
+    /// Make sure that the device is owned by us, and wait until udev
+    /// applies permission of the char device
+    fn prep_unprivileged_dev(&self, ctrl: &mut ctrl::UblkCtrl) -> Result<i32, UblkError> {
+        if (self.ctrl_flags & sys::UBLK_F_UNPRIVILEGED_DEV as u64) == 0 {
+            return Ok(0);
+        }
+
+        ctrl.check_dev_owner()?;
+        ctrl.wait_for_cdev_access(self.cdev_timeout)
+    }
+
     /// create one pair of ublk devices, the 1st one is control device(`UblkCtrl`),
     /// and the 2nd one is data device(`UblkDev`)
     pub fn create_devices<T>(
@@ -200,7 +279,7 @@ impl UblkSession {
     where
         T: FnOnce(&mut io::UblkDev) -> Result<i32, UblkError>,
     {
-        let mut ctrl = ctrl::UblkCtrl::new(
+        let mut ctrl = ctrl::UblkCtrl::new_in_dir(
             self.id,
             self.nr_queues,
             self.depth,
@@ -208,13 +287,108 @@ impl UblkSession {
             self.ctrl_flags,
             self.ctrl_target_flags,
             self.dev_flags,
+            Some(&self.get_run_dir()),
         )?;
+        ctrl.set_json_perm(self.json_perm);
+        ctrl.set_cdev_timeout(self.cdev_timeout);
+        self.prep_unprivileged_dev(&mut ctrl)?;
 
         let dev = Arc::new(io::UblkDev::new(self.name.clone(), tgt_fn, &mut ctrl)?);
 
         Ok((ctrl, dev))
     }
 
+    /// recover one pair of ublk devices from the quiesced one, the 1st one
+    /// is control device(`UblkCtrl`), and the 2nd one is data device(`UblkDev`)
+    ///
+    /// # Arguments:
+    ///
+    /// * `tgt_fn`: target recover closure, called with the new `UblkDev`,
+    ///   the target(`UblkTgt`) and target data(`target_data`) reloaded from
+    ///   the exported json file
+    ///
+    /// The device has to be created with `UBLK_F_USER_RECOVERY`, and
+    /// `UBLK_F_USER_RECOVERY_REISSUE` is supported too. `dev_size`, `params`
+    /// and uring depth of `UblkDev.tgt` are restored from json before calling
+    /// `tgt_fn`, and the target can re-open its backing files or rebuild its
+    /// state in `tgt_fn`, and the last checkpointed target state is available
+    /// from `UblkDev::get_recovered_state()`.
+    ///
+    /// Queues are restarted and user recovery is ended by `run_target()`,
+    /// same with adding new device.
+    pub fn recover_devices<T>(
+        &self,
+        tgt_fn: T,
+    ) -> Result<(ctrl::UblkCtrl, Arc<io::UblkDev>), UblkError>
+    where
+        T: FnOnce(
+            &mut io::UblkDev,
+            &io::UblkTgt,
+            Option<&serde_json::Value>,
+        ) -> Result<i32, UblkError>,
+    {
+        if self.id < 0 || (self.dev_flags & dev_flags::UBLK_DEV_F_ADD_DEV) != 0 {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        let mut ctrl = ctrl::UblkCtrl::new_in_dir(
+            self.id,
+            self.nr_queues,
+            self.depth,
+            self.io_buf_bytes,
+            self.ctrl_flags,
+            self.ctrl_target_flags,
+            self.dev_flags | dev_flags::UBLK_DEV_F_RECOVER_DEV,
+            Some(&self.get_run_dir()),
+        )?;
+        ctrl.set_json_perm(self.json_perm);
+        ctrl.set_cdev_timeout(self.cdev_timeout);
+        self.prep_unprivileged_dev(&mut ctrl)?;
+
+        let features = ctrl::UblkFeatures::from_bits_retain(ctrl.dev_info.flags);
+        if !features.contains(ctrl::UblkFeatures::USER_RECOVERY) {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        // queue setting has to be same with the recovered device
+        let info = &ctrl.dev_info;
+        if info.nr_hw_queues as u32 != self.nr_queues || info.queue_depth as u32 != self.depth {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+        let saved_info = ctrl.get_dev_info_from_json()?;
+        if saved_info.nr_hw_queues != info.nr_hw_queues
+            || saved_info.queue_depth != info.queue_depth
+        {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        let tgt = ctrl.get_target_from_json()?;
+        let tgt_data = ctrl.get_target_data_from_json().cloned();
+        let tgt_state = ctrl.get_target_state().ok();
+
+        if ctrl.start_user_recover_timeout(self.recover_timeout)? == -libc::EBUSY {
+            return Err(UblkError::OtherError(-libc::EBUSY));
+        }
+
+        let recover_fn = |dev: &mut io::UblkDev| {
+            dev.tgt.dev_size = tgt.dev_size;
+            dev.tgt.params = tgt.params;
+            dev.tgt.ring_flags = tgt.ring_flags;
+            dev.tgt.sq_depth = tgt.sq_depth;
+            dev.tgt.cq_depth = tgt.cq_depth;
+            dev.tgt.extra_ios = tgt.extra_ios;
+            if let Some(val) = tgt_data.as_ref() {
+                dev.set_target_json(val.clone());
+            }
+            dev.set_recovered_state(tgt_state);
+
+            tgt_fn(dev, &tgt, tgt_data.as_ref())
+        };
+        let dev = Arc::new(io::UblkDev::new(self.name.clone(), recover_fn, &mut ctrl)?);
+
+        Ok((ctrl, dev))
+    }
+
     fn create_queue_handlers<Q>(
         &self,
         ctrl: &mut ctrl::UblkCtrl,
@@ -287,6 +461,9 @@ impl UblkSession {
     /// This one is the preferred interface for creating ublk daemon, and
     /// is friendly for user, such as, user can customize queue setup and
     /// io handler, such as setup async/await for handling io command.
+    ///
+    /// If the device is stopped by `UblkCtrl::resize()` for applying new
+    /// size, queues are created by `q_fn` again and the device is restarted.
     pub fn run_target<Q, W>(
         &self,
         ctrl: &mut ctrl::UblkCtrl,
@@ -298,17 +475,27 @@ impl UblkSession {
         Q: FnOnce(u16, &io::UblkDev) + Send + Sync + Clone + 'static,
         W: FnOnce(i32) + Send + Sync + 'static,
     {
-        let handles = self.create_queue_handlers(ctrl, dev, q_fn);
+        let mut handles = self.create_queue_handlers(ctrl, dev, q_fn.clone());
         let dev_id = dev.dev_info.dev_id as i32;
 
         ctrl.start_dev(dev)?;
 
         device_fn(dev_id);
 
-        for qh in handles {
-            qh.join().unwrap_or_else(|_| {
-                eprintln!("dev-{} join queue thread failed", dev.dev_info.dev_id)
-            });
+        loop {
+            for qh in handles {
+                qh.join().unwrap_or_else(|_| {
+                    eprintln!("dev-{} join queue thread failed", dev.dev_info.dev_id)
+                });
+            }
+
+            // device is stopped by UblkCtrl::resize() for applying new size
+            if !dev.take_restart() {
+                break;
+            }
+            dev.reopen_cdev(ctrl)?;
+            handles = self.create_queue_handlers(ctrl, dev, q_fn.clone());
+            ctrl.start_dev(dev)?;
         }
 
         //device may be deleted from another context, so it is normal
@@ -371,7 +558,8 @@ mod libublk {
 
             UblkQueue::new(qid, _dev)
                 .unwrap()
-                .wait_and_handle_io(io_handler);
+                .wait_and_handle_io(io_handler)
+                .unwrap();
         };
 
         sess.run_target(&mut ctrl, &dev, q_fn, move |dev_id| {
@@ -432,4 +620,39 @@ mod libublk {
 
         handle.join().unwrap();
     }
+
+    /// test for_each_dev_id_in with private running directory
+    #[test]
+    fn test_ublk_for_each_dev_id_in() {
+        let dir = tempfile::tempdir().unwrap();
+        for f in ["0001.json", "0001.state.json", "0003.json", "ublk.json"] {
+            std::fs::write(dir.path().join(f), b"{}").unwrap();
+        }
+
+        let ids_arc = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let ids = ids_arc.clone();
+        UblkSession::for_each_dev_id_in(dir.path().to_str().unwrap(), move |dev_id| {
+            ids.borrow_mut().push(dev_id);
+        });
+
+        let mut ids = ids_arc.borrow().clone();
+        ids.sort();
+        assert!(ids == vec![1, 3]);
+
+        // session follows its own running directory
+        let sess = UblkSessionBuilder::default()
+            .name("null")
+            .run_dir(dir.path().to_str().unwrap())
+            .build()
+            .unwrap();
+        let ids_arc = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let ids = ids_arc.clone();
+        sess.for_each_session_dev_id(move |dev_id| {
+            ids.borrow_mut().push(dev_id);
+        });
+
+        let mut ids = ids_arc.borrow().clone();
+        ids.sort();
+        assert!(ids == vec![1, 3]);
+    }
 }
@@ -0,0 +1,426 @@
+//! OpenMetrics exporter of ublk devices
+//!
+//! Enabled by cargo feature `metrics`. Devices are registered to
+//! `UblkMetricsExporter`, which serves OpenMetrics text over unix socket
+//! or local HTTP port, so Prometheus compatible scraper can collect IO
+//! statistics of each queue. Devices have to be created with
+//! `UBLK_DEV_F_STATS` for exporting IO statistics.
+
+use super::ctrl::{UblkCtrl, UblkDevState};
+use super::io::UblkDev;
+use super::stats::{UblkStats, UBLK_STATS_LAT_BUCKETS};
+use super::UblkError;
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+/// Timeout for reading request from and writing metrics to one connection
+const CONN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Content type of OpenMetrics text exposition format
+pub const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Exporting OpenMetrics of registered ublk devices
+///
+/// Only weak reference of `UblkDev` is held, and removed devices are
+/// dropped from the exporter automatically.
+#[derive(Default)]
+pub struct UblkMetricsExporter {
+    devs: Mutex<Vec<UblkMetricsDev>>,
+}
+
+/// Registered device, and its control device is opened in the first
+/// scrape, then reused for retrieving device state
+struct UblkMetricsDev {
+    dev: Weak<UblkDev>,
+    ctrl: Option<UblkCtrl>,
+}
+
+struct UblkDevMetrics<'a> {
+    dev_id: u32,
+    tgt_type: &'a str,
+    depth: u16,
+    state: Option<UblkDevState>,
+    queues: Vec<UblkStats>,
+}
+
+impl UblkMetricsExporter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Register one ublk device for exporting its metrics
+    pub fn add_dev(&self, dev: &Arc<UblkDev>) {
+        self.devs.lock().unwrap().push(UblkMetricsDev {
+            dev: Arc::downgrade(dev),
+            ctrl: None,
+        });
+    }
+
+    /// Unregister ublk device `dev_id`
+    pub fn remove_dev(&self, dev_id: u32) {
+        self.devs
+            .lock()
+            .unwrap()
+            .retain(|d| matches!(d.dev.upgrade(), Some(dev) if dev.dev_info.dev_id != dev_id));
+    }
+
+    /// Escape label value as required by the text format
+    fn escape(v: &str) -> String {
+        let mut out = String::with_capacity(v.len());
+
+        for c in v.chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                '"' => out.push_str("\\\""),
+                '\n' => out.push_str("\\n"),
+                _ => out.push(c),
+            }
+        }
+        out
+    }
+
+    fn label(m: &UblkDevMetrics, qid: Option<usize>) -> String {
+        let mut l = format!(
+            "dev_id=\"{}\",target=\"{}\"",
+            m.dev_id,
+            Self::escape(m.tgt_type)
+        );
+
+        if let Some(q) = qid {
+            let _ = write!(l, ",qid=\"{}\"", q);
+        }
+        l
+    }
+
+    fn render_devs(devs: &[UblkDevMetrics]) -> String {
+        let mut out = String::new();
+
+        out.push_str("# TYPE ublk_dev_state stateset\n");
+        out.push_str("# HELP ublk_dev_state ublk device state.\n");
+        for m in devs {
+            if let Some(state) = m.state {
+                for s in [
+                    UblkDevState::Dead,
+                    UblkDevState::Live,
+                    UblkDevState::Quiesced,
+                ] {
+                    let _ = writeln!(
+                        out,
+                        "ublk_dev_state{{{},ublk_dev_state=\"{}\"}} {}",
+                        Self::label(m, None),
+                        s,
+                        (s == state) as u32
+                    );
+                }
+            }
+        }
+
+        out.push_str("# TYPE ublk_queue_depth gauge\n");
+        out.push_str("# HELP ublk_queue_depth Queue depth.\n");
+        for m in devs {
+            for qid in 0..m.queues.len() {
+                let _ = writeln!(
+                    out,
+                    "ublk_queue_depth{{{}}} {}",
+                    Self::label(m, Some(qid)),
+                    m.depth
+                );
+            }
+        }
+
+        out.push_str("# TYPE ublk_inflight_ios gauge\n");
+        out.push_str("# HELP ublk_inflight_ios IOs being handled by target.\n");
+        for m in devs {
+            for (qid, s) in m.queues.iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "ublk_inflight_ios{{{}}} {}",
+                    Self::label(m, Some(qid)),
+                    s.inflight
+                );
+            }
+        }
+
+        for (name, help) in [("ios", "IOs"), ("bytes", "Bytes")] {
+            let _ = writeln!(out, "# TYPE ublk_{} counter", name);
+            let _ = writeln!(out, "# HELP ublk_{} {} fetched from driver.", name, help);
+            for m in devs {
+                for (qid, s) in m.queues.iter().enumerate() {
+                    let ops = [
+                        ("read", &s.read),
+                        ("write", &s.write),
+                        ("flush", &s.flush),
+                        ("discard", &s.discard),
+                        ("write_zeroes", &s.write_zeroes),
+                        ("other", &s.other),
+                    ];
+                    for (op, st) in ops {
+                        let _ = writeln!(
+                            out,
+                            "ublk_{}_total{{{},op=\"{}\"}} {}",
+                            name,
+                            Self::label(m, Some(qid)),
+                            op,
+                            if name == "ios" { st.ios } else { st.bytes }
+                        );
+                    }
+                }
+            }
+        }
+
+        out.push_str("# TYPE ublk_errors counter\n");
+        out.push_str("# HELP ublk_errors IOs completed with failure.\n");
+        for m in devs {
+            for (qid, s) in m.queues.iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "ublk_errors_total{{{}}} {}",
+                    Self::label(m, Some(qid)),
+                    s.errors
+                );
+            }
+        }
+
+        out.push_str("# TYPE ublk_io_latency_seconds histogram\n");
+        out.push_str("# HELP ublk_io_latency_seconds Latency from IO fetch to commit.\n");
+        for m in devs {
+            for (qid, s) in m.queues.iter().enumerate() {
+                let l = Self::label(m, Some(qid));
+                let mut cnt = 0;
+
+                for (i, nr) in s.lat_hist.iter().enumerate() {
+                    cnt += nr;
+                    if i + 1 < UBLK_STATS_LAT_BUCKETS {
+                        let le = (1_u64 << i) as f64 / 1e6;
+                        let _ = writeln!(
+                            out,
+                            "ublk_io_latency_seconds_bucket{{{},le=\"{}\"}} {}",
+                            l, le, cnt
+                        );
+                    }
+                }
+                let _ = writeln!(
+                    out,
+                    "ublk_io_latency_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                    l, cnt
+                );
+                let _ = writeln!(out, "ublk_io_latency_seconds_count{{{}}} {}", l, cnt);
+                let _ = writeln!(
+                    out,
+                    "ublk_io_latency_seconds_sum{{{}}} {}",
+                    l,
+                    s.lat_total_us as f64 / 1e6
+                );
+            }
+        }
+
+        out.push_str("# EOF\n");
+        out
+    }
+
+    /// Return OpenMetrics text of all registered devices
+    pub fn render(&self) -> String {
+        let mut devs = self.devs.lock().unwrap();
+
+        devs.retain(|d| d.dev.strong_count() > 0);
+        let states: Vec<(Arc<UblkDev>, Option<UblkDevState>)> = devs
+            .iter_mut()
+            .filter_map(|d| {
+                let dev = d.dev.upgrade()?;
+
+                if d.ctrl.is_none() {
+                    d.ctrl = UblkCtrl::new_simple(dev.dev_info.dev_id as i32, 0).ok();
+                }
+                let state = match d.ctrl.as_mut().map(|c| c.get_info()) {
+                    Some(Ok(_)) => d.ctrl.as_ref().map(|c| c.get_state()),
+                    _ => {
+                        // device may be deleted, open it again next time
+                        d.ctrl = None;
+                        None
+                    }
+                };
+                Some((dev, state))
+            })
+            .collect();
+        drop(devs);
+
+        let metrics: Vec<UblkDevMetrics> = states
+            .iter()
+            .map(|(dev, state)| {
+                let info = &dev.dev_info;
+
+                UblkDevMetrics {
+                    dev_id: info.dev_id,
+                    tgt_type: &dev.tgt.tgt_type,
+                    depth: info.queue_depth,
+                    state: *state,
+                    queues: (0..info.nr_hw_queues)
+                        .filter_map(|q| dev.get_queue_stats(q))
+                        .collect(),
+                }
+            })
+            .collect();
+
+        Self::render_devs(&metrics)
+    }
+
+    /// Serve OpenMetrics text over unix socket `path`
+    ///
+    /// The metrics text is written to each accepted connection, then the
+    /// connection is closed. Stale socket at `path` is replaced, and -EEXIST
+    /// is returned if `path` is other kind of file. Return handle of the
+    /// serving thread.
+    pub fn serve_unix(
+        self: &Arc<Self>,
+        path: &str,
+    ) -> Result<std::thread::JoinHandle<()>, UblkError> {
+        let listener = crate::targets::bind_unix_socket(path)?;
+        let exporter = self.clone();
+
+        Ok(std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = stream.set_write_timeout(Some(CONN_TIMEOUT));
+                let _ = stream.write_all(exporter.render().as_bytes());
+            }
+        }))
+    }
+
+    /// Serve OpenMetrics text over HTTP
+    ///
+    /// # Arguments:
+    ///
+    /// * `addr`: local address for listening, such as `127.0.0.1:9100`
+    ///
+    /// Any HTTP GET request is responded with the metrics text. Requests
+    /// are served one by one, and connection which doesn't send the whole
+    /// request header in 5 seconds is dropped. Return handle of the serving
+    /// thread and the bound address, which is useful in case that port 0
+    /// is passed.
+    pub fn serve_http(
+        self: &Arc<Self>,
+        addr: &str,
+    ) -> Result<(std::thread::JoinHandle<()>, std::net::SocketAddr), UblkError> {
+        let listener = std::net::TcpListener::bind(addr).map_err(UblkError::OtherIOError)?;
+        let local = listener.local_addr().map_err(UblkError::OtherIOError)?;
+        let exporter = self.clone();
+
+        let handle = std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = [0_u8; 4096];
+                let mut req = Vec::new();
+
+                if stream.set_read_timeout(Some(CONN_TIMEOUT)).is_err()
+                    || stream.set_write_timeout(Some(CONN_TIMEOUT)).is_err()
+                {
+                    continue;
+                }
+
+                // read request header, and the request is always treated
+                // as scraping metrics
+                let mut done = false;
+                while let Ok(n) = stream.read(&mut buf) {
+                    req.extend_from_slice(&buf[..n]);
+                    done = req.windows(4).any(|w| w == b"\r\n\r\n");
+                    if n == 0 || done {
+                        break;
+                    }
+                }
+                if !done {
+                    continue;
+                }
+
+                let body = exporter.render();
+                let resp = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    OPENMETRICS_CONTENT_TYPE,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(resp.as_bytes());
+            }
+        });
+
+        Ok((handle, local))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{UblkDevMetrics, UblkMetricsExporter};
+    use crate::ctrl::UblkDevState;
+    use crate::stats::UblkStats;
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_render_metrics() {
+        let mut s = UblkStats::default();
+        s.read.ios = 3;
+        s.read.bytes = 12288;
+        s.lat_hist[2] = 3;
+        s.lat_total_us = 9;
+
+        let m = UblkDevMetrics {
+            dev_id: 1,
+            tgt_type: "null",
+            depth: 64,
+            state: Some(UblkDevState::Live),
+            queues: vec![s],
+        };
+        let out = UblkMetricsExporter::render_devs(&[m]);
+
+        assert!(
+            out.contains("ublk_dev_state{dev_id=\"1\",target=\"null\",ublk_dev_state=\"LIVE\"} 1")
+        );
+        assert!(out.contains("ublk_queue_depth{dev_id=\"1\",target=\"null\",qid=\"0\"} 64"));
+        assert!(
+            out.contains("ublk_ios_total{dev_id=\"1\",target=\"null\",qid=\"0\",op=\"read\"} 3")
+        );
+        assert!(out.contains(
+            "ublk_bytes_total{dev_id=\"1\",target=\"null\",qid=\"0\",op=\"read\"} 12288"
+        ));
+        assert!(out.contains(
+            "ublk_io_latency_seconds_bucket{dev_id=\"1\",target=\"null\",qid=\"0\",le=\"+Inf\"} 3"
+        ));
+        assert!(out.ends_with("# EOF\n"));
+
+        assert!(UblkMetricsExporter::escape("a\"b\\c\nd") == "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn test_serve_metrics() {
+        let exporter = Arc::new(UblkMetricsExporter::new());
+
+        let (_h, addr) = exporter.serve_http("127.0.0.1:0").unwrap();
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200 OK"));
+        assert!(resp.ends_with("# EOF\n"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metrics.sock");
+        let _h = exporter.serve_unix(path.to_str().unwrap()).unwrap();
+        let mut stream = std::os::unix::net::UnixStream::connect(&path).unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        assert!(resp.starts_with("# TYPE ublk_dev_state stateset\n"));
+        assert!(resp.ends_with("# EOF\n"));
+
+        // stale socket is replaced, but regular file is kept
+        let _h = exporter.serve_unix(path.to_str().unwrap()).unwrap();
+        let file = dir.path().join("metrics.txt");
+        std::fs::write(&file, b"data").unwrap();
+        assert!(matches!(
+            exporter.serve_unix(file.to_str().unwrap()),
+            Err(crate::UblkError::OtherError(e)) if e == -libc::EEXIST
+        ));
+        assert!(std::fs::read(&file).unwrap() == b"data");
+    }
+}
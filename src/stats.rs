@@ -0,0 +1,262 @@
+use super::sys;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Count of latency histogram buckets
+///
+/// Bucket 0 counts IOs completed in less than 1us, bucket `i` counts IOs
+/// completed in [2^(i-1)us, 2^i us), and the last bucket counts all slower
+/// IOs.
+pub const UBLK_STATS_LAT_BUCKETS: usize = 24;
+
+/// IO count and bytes of one IO operation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct UblkOpStats {
+    pub ios: u64,
+    pub bytes: u64,
+}
+
+impl UblkOpStats {
+    fn merge(&mut self, other: &UblkOpStats) {
+        self.ios += other.ios;
+        self.bytes += other.bytes;
+    }
+}
+
+/// IO statistics of one ublk queue or the whole ublk device
+///
+/// Enabled by `UBLK_DEV_F_STATS`, and retrieved from `UblkQueue::get_stats()`,
+/// `UblkDev::get_queue_stats()` or `UblkDev::get_stats()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct UblkStats {
+    pub read: UblkOpStats,
+    pub write: UblkOpStats,
+    pub flush: UblkOpStats,
+    pub discard: UblkOpStats,
+    pub write_zeroes: UblkOpStats,
+
+    /// zoned & other operations
+    pub other: UblkOpStats,
+
+    /// IOs completed with failure
+    pub errors: u64,
+
+    /// count of IOs being handled by target code
+    pub inflight: u64,
+
+    /// max count of IOs handled by target code at the same time, it is
+    /// the max of all queues for the whole device
+    pub inflight_max: u64,
+
+    /// how many times the queue becomes idle
+    pub idle_enter: u64,
+
+    /// latency histogram from IO being fetched to being committed, see
+    /// `UBLK_STATS_LAT_BUCKETS`
+    pub lat_hist: [u64; UBLK_STATS_LAT_BUCKETS],
+
+    /// total latency in microsecond
+    pub lat_total_us: u64,
+}
+
+impl UblkStats {
+    /// Return count of all IOs
+    pub fn ios(&self) -> u64 {
+        self.read.ios
+            + self.write.ios
+            + self.flush.ios
+            + self.discard.ios
+            + self.write_zeroes.ios
+            + self.other.ios
+    }
+
+    /// Return average latency in microsecond
+    pub fn lat_avg_us(&self) -> u64 {
+        let nr: u64 = self.lat_hist.iter().sum();
+
+        self.lat_total_us.checked_div(nr).unwrap_or(0)
+    }
+
+    /// Return upper bound of the latency percentile `p` in microsecond
+    ///
+    /// # Arguments:
+    ///
+    /// * `p`: percentile, such as 99.0
+    pub fn lat_percentile_us(&self, p: f64) -> u64 {
+        let nr: u64 = self.lat_hist.iter().sum();
+        let target = ((nr as f64) * p / 100.0).ceil() as u64;
+        let mut sum = 0;
+
+        if nr == 0 {
+            return 0;
+        }
+        for (i, cnt) in self.lat_hist.iter().enumerate() {
+            sum += cnt;
+            if sum >= target {
+                return 1_u64 << i;
+            }
+        }
+        1_u64 << (UBLK_STATS_LAT_BUCKETS - 1)
+    }
+
+    /// Merge stats of another queue
+    pub fn merge(&mut self, other: &UblkStats) {
+        self.read.merge(&other.read);
+        self.write.merge(&other.write);
+        self.flush.merge(&other.flush);
+        self.discard.merge(&other.discard);
+        self.write_zeroes.merge(&other.write_zeroes);
+        self.other.merge(&other.other);
+        self.errors += other.errors;
+        self.inflight += other.inflight;
+        self.inflight_max = std::cmp::max(self.inflight_max, other.inflight_max);
+        self.idle_enter += other.idle_enter;
+        for (i, cnt) in other.lat_hist.iter().enumerate() {
+            self.lat_hist[i] += cnt;
+        }
+        self.lat_total_us += other.lat_total_us;
+    }
+}
+
+#[derive(Debug, Default)]
+struct UblkOpCounter {
+    ios: AtomicU64,
+    bytes: AtomicU64,
+}
+
+/// Per-queue stats counters
+///
+/// Only updated from the queue thread, and can be read from any
+/// thread, so relaxed atomics are enough.
+#[derive(Debug, Default)]
+pub(crate) struct UblkStatsCounter {
+    ops: [UblkOpCounter; 6],
+    errors: AtomicU64,
+    inflight: AtomicU64,
+    inflight_max: AtomicU64,
+    idle_enter: AtomicU64,
+    lat_hist: [AtomicU64; UBLK_STATS_LAT_BUCKETS],
+    lat_total_us: AtomicU64,
+}
+
+impl UblkStatsCounter {
+    fn op_idx(op: u32) -> usize {
+        match op {
+            sys::UBLK_IO_OP_READ => 0,
+            sys::UBLK_IO_OP_WRITE => 1,
+            sys::UBLK_IO_OP_FLUSH => 2,
+            sys::UBLK_IO_OP_DISCARD => 3,
+            sys::UBLK_IO_OP_WRITE_ZEROES => 4,
+            _ => 5,
+        }
+    }
+
+    fn lat_bucket(us: u64) -> usize {
+        let idx = (u64::BITS - us.leading_zeros()) as usize;
+
+        std::cmp::min(idx, UBLK_STATS_LAT_BUCKETS - 1)
+    }
+
+    #[inline(always)]
+    fn inc(c: &AtomicU64, val: u64) {
+        c.store(c.load(Ordering::Relaxed) + val, Ordering::Relaxed);
+    }
+
+    /// Account one IO fetched from driver
+    #[inline(always)]
+    pub(crate) fn account_fetch(&self, op: u32, bytes: u64, inflight: u64) {
+        let c = &self.ops[Self::op_idx(op)];
+
+        Self::inc(&c.ios, 1);
+        Self::inc(&c.bytes, bytes);
+        self.inflight.store(inflight, Ordering::Relaxed);
+        if inflight > self.inflight_max.load(Ordering::Relaxed) {
+            self.inflight_max.store(inflight, Ordering::Relaxed);
+        }
+    }
+
+    /// Account one IO committed to driver
+    #[inline(always)]
+    pub(crate) fn account_commit(&self, res: i32, lat: std::time::Duration) {
+        let us = lat.as_micros() as u64;
+
+        if res < 0 {
+            Self::inc(&self.errors, 1);
+        }
+        if let Some(v) = self.inflight.load(Ordering::Relaxed).checked_sub(1) {
+            self.inflight.store(v, Ordering::Relaxed);
+        }
+        Self::inc(&self.lat_hist[Self::lat_bucket(us)], 1);
+        Self::inc(&self.lat_total_us, us);
+    }
+
+    #[inline(always)]
+    pub(crate) fn account_idle(&self) {
+        Self::inc(&self.idle_enter, 1);
+    }
+
+    pub(crate) fn snapshot(&self) -> UblkStats {
+        let op = |i: usize| UblkOpStats {
+            ios: self.ops[i].ios.load(Ordering::Relaxed),
+            bytes: self.ops[i].bytes.load(Ordering::Relaxed),
+        };
+        let mut stats = UblkStats {
+            read: op(0),
+            write: op(1),
+            flush: op(2),
+            discard: op(3),
+            write_zeroes: op(4),
+            other: op(5),
+            errors: self.errors.load(Ordering::Relaxed),
+            inflight: self.inflight.load(Ordering::Relaxed),
+            inflight_max: self.inflight_max.load(Ordering::Relaxed),
+            idle_enter: self.idle_enter.load(Ordering::Relaxed),
+            lat_total_us: self.lat_total_us.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+
+        for (i, cnt) in self.lat_hist.iter().enumerate() {
+            stats.lat_hist[i] = cnt.load(Ordering::Relaxed);
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{UblkStats, UblkStatsCounter};
+    use crate::sys;
+    use std::time::Duration;
+
+    #[test]
+    fn test_stats_account() {
+        let c = UblkStatsCounter::default();
+
+        c.account_fetch(sys::UBLK_IO_OP_READ, 4096, 1);
+        c.account_fetch(sys::UBLK_IO_OP_WRITE, 8192, 3);
+        c.account_fetch(sys::UBLK_IO_OP_REPORT_ZONES, 0, 2);
+        c.account_commit(4096, Duration::from_nanos(100));
+        c.account_commit(-libc::EIO, Duration::from_micros(5));
+        c.account_idle();
+
+        let s = c.snapshot();
+        assert!(s.read.ios == 1 && s.read.bytes == 4096);
+        assert!(s.write.ios == 1 && s.write.bytes == 8192);
+        assert!(s.other.ios == 1);
+        assert!(s.ios() == 3);
+        assert!(s.errors == 1);
+        assert!(s.inflight == 0);
+        assert!(s.inflight_max == 3);
+        assert!(s.idle_enter == 1);
+        assert!(s.lat_hist[0] == 1 && s.lat_hist[3] == 1);
+        assert!(s.lat_percentile_us(50.0) == 1);
+        assert!(s.lat_percentile_us(99.0) == 8);
+
+        let mut total = UblkStats::default();
+        total.merge(&s);
+        total.merge(&s);
+        assert!(total.ios() == 6);
+        assert!(total.inflight_max == 3);
+        assert!(total.lat_avg_us() == 2);
+    }
+}
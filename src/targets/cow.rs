@@ -0,0 +1,833 @@
+//! Copy-on-write overlay target: writable view of read-only base image
+//!
+//! Reads are served from the base image until the chunk is written, and
+//! the first write to one chunk copies the chunk from base to the overlay
+//! file, then all following IO of this chunk goes to the overlay. The
+//! chunk map is kept in memory and saved to the overlay file on FLUSH and
+//! when the queue exits, so only flushed writes are guaranteed to survive
+//! crash.
+//!
+//! The overlay can be merged back into the base by `merge_overlay()`, or
+//! discarded by `reset_overlay()`, both have to be done when the device
+//! isn't running.
+//!
+//! Overlay file layout:
+//!
+//! * header in the first 4096 bytes, see `CowHeader`
+//! * chunk map starting from 4096, one little-endian u32 for each chunk,
+//!   which is (slot + 1) of the chunk in data area, or zero if the chunk
+//!   isn't copied
+//! * data area starting from `data_off`, slot `i` is stored at
+//!   `data_off + (i << chunk_shift)`
+
+use super::TgtMember;
+use crate::dev_flags::UBLK_DEV_F_ASYNC;
+use crate::io::{UblkDev, UblkQueue};
+use crate::{sys, UblkError};
+use io_uring::{opcode, squeue, types};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashSet};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const COW_MAGIC: &[u8; 8] = b"UBLKCOW\0";
+const COW_VERSION: u32 = 1;
+const COW_HDR_SIZE: u64 = 4096;
+const COW_MAP_PAGE: usize = 4096;
+
+#[derive(Debug, Serialize)]
+struct CowJson {
+    base_path: String,
+    overlay_path: String,
+    chunk_size: u32,
+    direct_io: bool,
+    allocated_chunks: u64,
+}
+
+/// Overlay file header
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+struct CowHeader {
+    chunk_shift: u32,
+    dev_size: u64,
+    nr_chunks: u64,
+    /// how many slots are allocated in data area
+    nr_slots: u64,
+    data_off: u64,
+}
+
+impl CowHeader {
+    fn new(dev_size: u64, chunk_shift: u32) -> Self {
+        let chunk = 1_u64 << chunk_shift;
+        let nr_chunks = dev_size.div_ceil(chunk);
+        let map_end = COW_HDR_SIZE + nr_chunks * 4;
+
+        CowHeader {
+            chunk_shift,
+            dev_size,
+            nr_chunks,
+            nr_slots: 0,
+            data_off: map_end.div_ceil(chunk) * chunk,
+        }
+    }
+
+    fn parse(buf: &[u8]) -> Result<Self, UblkError> {
+        let u32_at = |o: usize| u32::from_le_bytes(buf[o..o + 4].try_into().unwrap());
+        let u64_at = |o: usize| u64::from_le_bytes(buf[o..o + 8].try_into().unwrap());
+
+        if buf.len() < 56 || &buf[0..8] != COW_MAGIC || u32_at(8) != COW_VERSION {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        let h = CowHeader {
+            chunk_shift: u32_at(12),
+            dev_size: u64_at(16),
+            nr_chunks: u64_at(24),
+            nr_slots: u64_at(32),
+            data_off: u64_at(48),
+        };
+        if !(12..=20).contains(&h.chunk_shift) || u64_at(40) != COW_HDR_SIZE {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        // all layout fields are derived from dev_size and chunk size
+        let expected = CowHeader {
+            nr_slots: h.nr_slots,
+            ..CowHeader::new(h.dev_size, h.chunk_shift)
+        };
+        if h != expected {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+        Ok(h)
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut buf = vec![0_u8; COW_HDR_SIZE as usize];
+
+        buf[0..8].copy_from_slice(COW_MAGIC);
+        buf[8..12].copy_from_slice(&COW_VERSION.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.chunk_shift.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.dev_size.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.nr_chunks.to_le_bytes());
+        buf[32..40].copy_from_slice(&self.nr_slots.to_le_bytes());
+        buf[40..48].copy_from_slice(&COW_HDR_SIZE.to_le_bytes());
+        buf[48..56].copy_from_slice(&self.data_off.to_le_bytes());
+        buf
+    }
+
+    fn chunk_size(&self) -> u64 {
+        1 << self.chunk_shift
+    }
+
+    fn slot_off(&self, slot: u32) -> u64 {
+        self.data_off + ((slot as u64) << self.chunk_shift)
+    }
+}
+
+/// In-memory chunk map and allocation state
+#[derive(Default, Debug)]
+struct CowMap {
+    hdr: CowHeader,
+    map: Vec<u32>,
+    /// map pages changed since the last FLUSH
+    dirty: BTreeSet<usize>,
+    /// chunks being copied from base
+    busy: HashSet<u64>,
+}
+
+impl CowMap {
+    /// Load chunk map from overlay file
+    fn load(f: &File) -> Result<Self, UblkError> {
+        let mut buf = vec![0_u8; COW_HDR_SIZE as usize];
+
+        f.read_exact_at(&mut buf, 0)
+            .map_err(UblkError::OtherIOError)?;
+        let hdr = CowHeader::parse(&buf)?;
+
+        let mut buf = vec![0_u8; hdr.nr_chunks as usize * 4];
+        f.read_exact_at(&mut buf, COW_HDR_SIZE)
+            .map_err(UblkError::OtherIOError)?;
+        let map: Vec<u32> = buf
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+
+        if map.iter().any(|e| *e as u64 > hdr.nr_slots) {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+        Ok(CowMap {
+            hdr,
+            map,
+            ..Default::default()
+        })
+    }
+
+    /// Create overlay file for base of `dev_size` bytes
+    fn create(f: &File, dev_size: u64, chunk_shift: u32) -> Result<Self, UblkError> {
+        let hdr = CowHeader::new(dev_size, chunk_shift);
+
+        f.set_len(0).map_err(UblkError::OtherIOError)?;
+        f.set_len(hdr.data_off).map_err(UblkError::OtherIOError)?;
+        f.write_all_at(&hdr.to_bytes(), 0)
+            .map_err(UblkError::OtherIOError)?;
+        f.sync_all().map_err(UblkError::OtherIOError)?;
+
+        Ok(CowMap {
+            hdr,
+            map: vec![0; hdr.nr_chunks as usize],
+            ..Default::default()
+        })
+    }
+
+    /// Return `Ok(slot)` if `chunk` is copied, otherwise allocate one slot
+    /// for copying it and return `Err(slot)`
+    ///
+    /// None is returned if the chunk is being copied.
+    fn get_slot(&mut self, chunk: u64) -> Option<Result<u32, u32>> {
+        match self.map[chunk as usize] {
+            0 if self.busy.contains(&chunk) => None,
+            0 => {
+                let slot = self.hdr.nr_slots as u32;
+
+                self.hdr.nr_slots += 1;
+                self.busy.insert(chunk);
+                Some(Err(slot))
+            }
+            e => Some(Ok(e - 1)),
+        }
+    }
+
+    fn set(&mut self, chunk: u64, slot: u32) {
+        self.map[chunk as usize] = slot + 1;
+        self.dirty.insert(chunk as usize * 4 / COW_MAP_PAGE);
+    }
+
+    fn allocated(&self) -> u64 {
+        self.map.iter().filter(|e| **e != 0).count() as u64
+    }
+
+    /// Take changed map pages, return (page, file offset, data) of them
+    /// and the header, which is the last one
+    ///
+    /// Empty vector is returned if nothing is changed.
+    fn take_dirty(&mut self) -> Vec<(Option<usize>, u64, Vec<u8>)> {
+        if self.dirty.is_empty() {
+            return Vec::new();
+        }
+
+        let mut pages: Vec<_> = std::mem::take(&mut self.dirty)
+            .into_iter()
+            .map(|page| {
+                let start = page * COW_MAP_PAGE / 4;
+                let end = std::cmp::min(start + COW_MAP_PAGE / 4, self.map.len());
+                let buf: Vec<u8> = self.map[start..end]
+                    .iter()
+                    .flat_map(|e| e.to_le_bytes())
+                    .collect();
+
+                (Some(page), COW_HDR_SIZE + (page * COW_MAP_PAGE) as u64, buf)
+            })
+            .collect();
+        pages.push((None, 0, self.hdr.to_bytes()));
+        pages
+    }
+
+    /// Write pages returned from `take_dirty()` to overlay file
+    fn write_pages(f: &File, pages: &[(Option<usize>, u64, Vec<u8>)]) -> Result<(), UblkError> {
+        for (_, off, buf) in pages {
+            f.write_all_at(buf, *off).map_err(UblkError::OtherIOError)?;
+        }
+        Ok(())
+    }
+
+    /// Write changed map pages & header to overlay file
+    fn save(&mut self, f: &File) -> Result<(), UblkError> {
+        Self::write_pages(f, &self.take_dirty())
+    }
+
+    /// Clear the map and drop all copied chunks
+    fn reset(&mut self, f: &File) -> Result<(), UblkError> {
+        self.map.fill(0);
+        self.hdr.nr_slots = 0;
+        self.dirty = (0..self.map.len().div_ceil(COW_MAP_PAGE / 4)).collect();
+        self.save(f)?;
+        f.set_len(self.hdr.data_off)
+            .map_err(UblkError::OtherIOError)?;
+        f.sync_all().map_err(UblkError::OtherIOError)
+    }
+}
+
+/// Merge copied chunks of overlay into base, then discard the overlay
+///
+/// # Arguments:
+///
+/// * `base_path`: path of base image
+/// * `overlay_path`: path of overlay file
+///
+/// Return how many chunks are merged. Can't be called when the device is
+/// running.
+pub fn merge_overlay(base_path: &str, overlay_path: &str) -> Result<u64, UblkError> {
+    let ovl = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(overlay_path)
+        .map_err(UblkError::OtherIOError)?;
+    let base = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(base_path)
+        .map_err(UblkError::OtherIOError)?;
+    let mut map = CowMap::load(&ovl)?;
+    let hdr = map.hdr;
+
+    if super::backing_file_size(&base)?.0 != hdr.dev_size {
+        return Err(UblkError::OtherError(-libc::EINVAL));
+    }
+
+    let mut buf = vec![0_u8; hdr.chunk_size() as usize];
+    let mut merged = 0;
+    for (chunk, e) in map.map.iter().enumerate() {
+        if *e == 0 {
+            continue;
+        }
+
+        let off = (chunk as u64) << hdr.chunk_shift;
+        let len = std::cmp::min(hdr.chunk_size(), hdr.dev_size - off) as usize;
+        ovl.read_exact_at(&mut buf[..len], hdr.slot_off(*e - 1))
+            .map_err(UblkError::OtherIOError)?;
+        base.write_all_at(&buf[..len], off)
+            .map_err(UblkError::OtherIOError)?;
+        merged += 1;
+    }
+    base.sync_all().map_err(UblkError::OtherIOError)?;
+
+    map.reset(&ovl)?;
+    Ok(merged)
+}
+
+/// Discard all writes stored in overlay, so the device becomes same with
+/// base again
+///
+/// Can't be called when the device is running.
+pub fn reset_overlay(overlay_path: &str) -> Result<(), UblkError> {
+    let ovl = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(overlay_path)
+        .map_err(UblkError::OtherIOError)?;
+
+    CowMap::load(&ovl)?.reset(&ovl)
+}
+
+/// Overlay state shared by all queues
+#[derive(Debug)]
+struct CowMeta {
+    /// overlay opened without O_DIRECT, for updating header & map
+    file: File,
+    map: Mutex<CowMap>,
+    /// serializes saving, so older map page never overwrites newer one
+    save_lock: Mutex<()>,
+}
+
+impl CowMeta {
+    /// Save changed chunk map, return if anything is written
+    ///
+    /// Pages are built with map lock held, and written after the lock is
+    /// released, so IO isn't blocked by the write.
+    fn save(&self) -> Result<bool, UblkError> {
+        let _guard = self.save_lock.lock().unwrap();
+        let pages = self.map.lock().unwrap().take_dirty();
+
+        if pages.is_empty() {
+            return Ok(false);
+        }
+        if let Err(e) = CowMap::write_pages(&self.file, &pages) {
+            // retry in next save
+            let mut map = self.map.lock().unwrap();
+
+            map.dirty.extend(pages.iter().filter_map(|p| p.0));
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Make copied chunks durable, then save the chunk map, called when
+    /// the queue exits since clean stop doesn't issue FLUSH
+    fn sync(&self) -> Result<(), UblkError> {
+        self.file.sync_all().map_err(UblkError::OtherIOError)?;
+        if self.save()? {
+            self.file.sync_all().map_err(UblkError::OtherIOError)?;
+        }
+        Ok(())
+    }
+}
+
+/// One chunk covered by IO
+#[derive(Debug, Clone, Copy)]
+struct CowSeg {
+    chunk: u64,
+    /// offset in chunk
+    off: u64,
+    /// offset in IO buffer
+    buf_off: u64,
+    len: u64,
+}
+
+/// Per-queue view of cow target
+#[derive(Debug)]
+struct CowQueueIo {
+    meta: Arc<CowMeta>,
+    hdr: CowHeader,
+    base_idx: u32,
+    ovl_idx: u32,
+}
+
+impl CowQueueIo {
+    fn segs(&self, off: u64, len: u64) -> Vec<CowSeg> {
+        let cs = self.hdr.chunk_size();
+        let end = off + len;
+        let mut pos = off;
+        let mut segs = Vec::new();
+
+        while pos < end {
+            let in_off = pos & (cs - 1);
+            let l = std::cmp::min(cs - in_off, end - pos);
+
+            segs.push(CowSeg {
+                chunk: pos >> self.hdr.chunk_shift,
+                off: in_off,
+                buf_off: pos - off,
+                len: l,
+            });
+            pos += l;
+        }
+        segs
+    }
+
+    fn rw_sqe(&self, write: bool, fd_idx: u32, off: u64, buf: *mut u8, len: u64) -> squeue::Entry {
+        let fd = types::Fixed(fd_idx);
+        let sqe = if write {
+            opcode::Write::new(fd, buf, len as u32).offset(off).build()
+        } else {
+            opcode::Read::new(fd, buf, len as u32).offset(off).build()
+        };
+
+        sqe.flags(squeue::Flags::FIXED_FILE)
+    }
+
+    /// Return 0 if all `res` are same with `lens`, otherwise return error
+    fn check(res: &[i32], lens: &[u64]) -> i32 {
+        for (r, l) in res.iter().zip(lens) {
+            if *r < 0 {
+                return *r;
+            }
+            if *r as u64 != *l {
+                return -libc::EIO;
+            }
+        }
+        0
+    }
+
+    /// Build reads from base for chunks not copied, and from overlay for
+    /// copied chunks
+    fn read_sqes(&self, buf: *mut u8, off: u64, len: u64) -> (Vec<squeue::Entry>, Vec<u64>) {
+        let map = self.meta.map.lock().unwrap();
+        let mut sqes = Vec::new();
+        let mut lens = Vec::new();
+
+        for s in self.segs(off, len) {
+            let addr = unsafe { buf.add(s.buf_off as usize) };
+            let sqe = match map.map[s.chunk as usize] {
+                0 => self.rw_sqe(false, self.base_idx, off + s.buf_off, addr, s.len),
+                e => self.rw_sqe(
+                    false,
+                    self.ovl_idx,
+                    self.hdr.slot_off(e - 1) + s.off,
+                    addr,
+                    s.len,
+                ),
+            };
+
+            sqes.push(sqe);
+            lens.push(s.len);
+        }
+        (sqes, lens)
+    }
+
+    async fn read(&self, q: &UblkQueue<'_>, tag: u16, off: u64, len: u64) -> i32 {
+        let (sqes, lens) = self.read_sqes(q.get_io_buf_addr(tag), off, len);
+
+        match Self::check(&super::uring_ops(q, tag, &sqes).await, &lens) {
+            0 => len as i32,
+            r => r,
+        }
+    }
+
+    /// Copy chunk from base to `slot` of overlay, and apply the write
+    /// covered by `s`
+    async fn copy_chunk(&self, q: &UblkQueue<'_>, tag: u16, s: &CowSeg, slot: u32) -> i32 {
+        let cs = self.hdr.chunk_size();
+        let chunk_off = s.chunk << self.hdr.chunk_shift;
+        let len = std::cmp::min(cs, self.hdr.dev_size - chunk_off);
+        let mut bounce = super::TgtBuf::new(cs as usize);
+        let data = q.get_io_buf_addr(tag);
+
+        // no need to read base if the whole chunk is overwritten
+        if s.len != len {
+            let sqe = self.rw_sqe(false, self.base_idx, chunk_off, bounce.as_mut_ptr(), len);
+            let res = super::uring_op(q, tag, sqe).await;
+
+            if res != len as i32 {
+                return if res < 0 { res } else { -libc::EIO };
+            }
+        }
+
+        let buf = bounce.as_mut_slice();
+        buf[len as usize..].fill(0);
+        unsafe {
+            let src = std::slice::from_raw_parts(data.add(s.buf_off as usize), s.len as usize);
+
+            buf[s.off as usize..(s.off + s.len) as usize].copy_from_slice(src);
+        }
+
+        // always write the whole chunk for keeping O_DIRECT alignment
+        let sqe = self.rw_sqe(
+            true,
+            self.ovl_idx,
+            self.hdr.slot_off(slot),
+            bounce.as_mut_ptr(),
+            cs,
+        );
+        match super::uring_op(q, tag, sqe).await {
+            r if r == cs as i32 => 0,
+            r if r < 0 => r,
+            _ => -libc::EIO,
+        }
+    }
+
+    async fn write(&self, q: &UblkQueue<'_>, tag: u16, off: u64, len: u64) -> i32 {
+        let buf = q.get_io_buf_addr(tag);
+        let mut sqes = Vec::new();
+        let mut lens = Vec::new();
+        let mut cows = Vec::new();
+
+        for s in self.segs(off, len) {
+            let slot = loop {
+                if let Some(slot) = self.meta.map.lock().unwrap().get_slot(s.chunk) {
+                    break slot;
+                }
+
+                // wait until the chunk is copied by other io, chunks are
+                // taken in ascending order, so it can't deadlock
+                let ts = types::Timespec::from(Duration::from_micros(20));
+                let sqe = opcode::Timeout::new(&ts as *const types::Timespec)
+                    .flags(types::TimeoutFlags::ETIME_SUCCESS)
+                    .build();
+                super::uring_op(q, tag, sqe).await;
+            };
+
+            match slot {
+                Ok(slot) => {
+                    let addr = unsafe { buf.add(s.buf_off as usize) };
+
+                    sqes.push(self.rw_sqe(
+                        true,
+                        self.ovl_idx,
+                        self.hdr.slot_off(slot) + s.off,
+                        addr,
+                        s.len,
+                    ));
+                    lens.push(s.len);
+                }
+                Err(slot) => cows.push((s, slot)),
+            }
+        }
+
+        let mut ret = Self::check(&super::uring_ops(q, tag, &sqes).await, &lens);
+        for (s, slot) in cows {
+            let res = if ret == 0 {
+                self.copy_chunk(q, tag, &s, slot).await
+            } else {
+                ret
+            };
+            let mut map = self.meta.map.lock().unwrap();
+
+            // the allocated slot is leaked in case of failure
+            map.busy.remove(&s.chunk);
+            if res == 0 {
+                map.set(s.chunk, slot);
+            }
+            ret = res;
+        }
+
+        if ret == 0 {
+            len as i32
+        } else {
+            ret
+        }
+    }
+
+    async fn fsync(&self, q: &UblkQueue<'_>, tag: u16) -> i32 {
+        let sqe = opcode::Fsync::new(types::Fixed(self.ovl_idx))
+            .flags(types::FsyncFlags::DATASYNC)
+            .build()
+            .flags(squeue::Flags::FIXED_FILE);
+
+        super::uring_op(q, tag, sqe).await
+    }
+
+    /// Make copied chunks durable first, then save the chunk map
+    async fn flush(&self, q: &UblkQueue<'_>, tag: u16) -> i32 {
+        let res = self.fsync(q, tag).await;
+        if res < 0 {
+            return res;
+        }
+
+        match self.meta.save() {
+            Ok(false) => 0,
+            Ok(true) => self.fsync(q, tag).await,
+            Err(e) => {
+                log::error!("cow: failed to save chunk map {:?}", e);
+                -libc::EIO
+            }
+        }
+    }
+
+    async fn handle_io(&self, q: &UblkQueue<'_>, tag: u16) -> i32 {
+        let iod = q.get_iod(tag);
+        let off = iod.start_sector << 9;
+        let len = (iod.nr_sectors << 9) as u64;
+
+        match iod.op_flags & 0xff {
+            sys::UBLK_IO_OP_READ => self.read(q, tag, off, len).await,
+            sys::UBLK_IO_OP_WRITE => self.write(q, tag, off, len).await,
+            sys::UBLK_IO_OP_FLUSH => self.flush(q, tag).await,
+            _ => -libc::EINVAL,
+        }
+    }
+}
+
+/// Copy-on-write overlay target
+///
+/// Built by `CowTgtBuilder`, the base image and overlay are opened in
+/// `init_tgt()`. The target has to live in the whole device lifetime, and
+/// it is cheap to clone for moving into queue closure.
+#[derive(Default, Builder, Debug, Clone)]
+#[builder(setter(into))]
+pub struct CowTgt {
+    /// path of base image or block device, which is never written
+    base_path: String,
+
+    /// path of overlay file, created if it doesn't exist
+    overlay_path: String,
+
+    /// chunk size for creating overlay, has to be power of 2 in
+    /// [4096, 1MB]; existing overlay uses its own chunk size
+    #[builder(default = "65536")]
+    chunk_size: u32,
+
+    /// open base & overlay with O_DIRECT, fallback to buffered IO if it
+    /// isn't supported
+    #[builder(default = "true")]
+    direct_io: bool,
+
+    #[builder(setter(skip))]
+    meta: Option<Arc<CowMeta>>,
+
+    #[builder(setter(skip))]
+    io: Option<(CowHeader, u32, u32)>,
+}
+
+impl CowTgt {
+    /// Return `UBLK_DEV_F_*` flags required by this target
+    pub fn dev_flags(&self) -> u32 {
+        UBLK_DEV_F_ASYNC
+    }
+
+    /// Return how many chunks are copied to overlay, only available after
+    /// `init_tgt()` returns
+    pub fn allocated_chunks(&self) -> Result<u64, UblkError> {
+        match self.meta.as_ref() {
+            Some(m) => Ok(m.map.lock().unwrap().allocated()),
+            None => Err(UblkError::OtherError(-libc::EINVAL)),
+        }
+    }
+
+    /// Setup cow target, passed to `UblkSession::create_devices()`
+    ///
+    /// # Arguments:
+    ///
+    /// * `dev`: ublk device being created
+    ///
+    /// Open base read-only, create or load the overlay, and register both
+    /// as fixed files.
+    pub fn init_tgt(&mut self, dev: &mut UblkDev) -> Result<i32, UblkError> {
+        log::trace!("cow: init_tgt {}", dev.dev_info.dev_id);
+
+        let cs = self.chunk_size;
+        if (dev.flags & UBLK_DEV_F_ASYNC) == 0
+            || !cs.is_power_of_two()
+            || !(4096..=1 << 20).contains(&cs)
+        {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        let base = TgtMember::open(dev, &self.base_path, self.direct_io, true)?;
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.overlay_path)
+            .map_err(UblkError::OtherIOError)?;
+        let map = if file.metadata().map_err(UblkError::OtherIOError)?.len() == 0 {
+            CowMap::create(&file, base.size, cs.trailing_zeros())?
+        } else {
+            CowMap::load(&file)?
+        };
+        let hdr = map.hdr;
+        if hdr.dev_size != base.size || (hdr.dev_size & ((1 << base.lbs_shift) - 1)) != 0 {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+        let ovl = TgtMember::open(dev, &self.overlay_path, self.direct_io, false)?;
+
+        let tgt = &mut dev.tgt;
+        tgt.dev_size = hdr.dev_size;
+        tgt.params = sys::ublk_params {
+            types: sys::UBLK_PARAM_TYPE_BASIC,
+            basic: sys::ublk_param_basic {
+                attrs: sys::UBLK_ATTR_VOLATILE_CACHE,
+                logical_bs_shift: base.lbs_shift,
+                physical_bs_shift: base.pbs_shift,
+                io_opt_shift: hdr.chunk_shift as u8,
+                io_min_shift: base.lbs_shift,
+                max_sectors: dev.dev_info.max_io_buf_bytes >> 9,
+                dev_sectors: hdr.dev_size >> 9,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // the chunk map covers the base only
+        dev.set_max_dev_size(hdr.dev_size);
+        let val = serde_json::json!({"cow": CowJson {
+            base_path: self.base_path.clone(),
+            overlay_path: self.overlay_path.clone(),
+            chunk_size: hdr.chunk_size() as u32,
+            direct_io: base.direct && ovl.direct,
+            allocated_chunks: map.allocated(),
+        }});
+        dev.set_target_json(val);
+
+        self.io = Some((hdr, base.fd_idx, ovl.fd_idx));
+        self.meta = Some(Arc::new(CowMeta {
+            file,
+            map: Mutex::new(map),
+            save_lock: Mutex::new(()),
+        }));
+
+        Ok(0)
+    }
+
+    /// Handle IO of queue `qid`, called from queue closure
+    ///
+    /// # Arguments:
+    ///
+    /// * `qid`: queue id
+    /// * `dev`: ublk device
+    ///
+    /// Won't return until the queue is down, then writes since the last
+    /// FLUSH are synced and the chunk map is saved.
+    pub fn run_queue(&self, qid: u16, dev: &UblkDev) -> Result<(), UblkError> {
+        let (meta, (hdr, base_idx, ovl_idx)) = match (self.meta.as_ref(), self.io) {
+            (Some(m), Some(io)) => (m.clone(), io),
+            _ => return Err(UblkError::OtherError(-libc::EINVAL)),
+        };
+        let io = Rc::new(CowQueueIo {
+            meta: meta.clone(),
+            hdr,
+            base_idx,
+            ovl_idx,
+        });
+
+        let res = super::run_async_queue(qid, dev, move |q, tag| {
+            let io = io.clone();
+
+            async move { io.handle_io(&q, tag).await }
+        });
+
+        // save writes since the last FLUSH
+        if let Err(e) = meta.sync() {
+            log::error!("cow: queue {} failed to save chunk map {:?}", qid, e);
+            return res.and(Err(e));
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merge_overlay, reset_overlay, CowHeader, CowMap, CowMeta, CowTgtBuilder};
+    use std::os::unix::fs::FileExt;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_cow_overlay() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base");
+        let ovl = dir.path().join("ovl");
+        let (base_s, ovl_s) = (base.to_str().unwrap(), ovl.to_str().unwrap());
+
+        // 2 chunks of 64K and one 4K tail
+        std::fs::write(&base, vec![1_u8; (128 << 10) + 4096]).unwrap();
+        let f = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&ovl)
+            .unwrap();
+        let mut map = CowMap::create(&f, (128 << 10) + 4096, 16).unwrap();
+        assert!(map.hdr.nr_chunks == 3 && map.hdr.data_off == 64 << 10);
+        assert!(CowHeader::parse(&map.hdr.to_bytes()).unwrap() == map.hdr);
+        assert!(CowHeader::parse(&[0_u8; 4096]).is_err());
+
+        // copy chunk 2 & 0 to slot 0 & 1, then save the map
+        f.write_all_at(&[2_u8; 4096], map.hdr.slot_off(0)).unwrap();
+        f.write_all_at(&[3_u8; 65536], map.hdr.slot_off(1)).unwrap();
+        map.hdr.nr_slots = 2;
+        map.set(2, 0);
+        map.set(0, 1);
+        let meta = CowMeta {
+            file: f.try_clone().unwrap(),
+            map: Mutex::new(map),
+            save_lock: Mutex::new(()),
+        };
+        assert!(meta.save().unwrap());
+        assert!(meta.map.lock().unwrap().dirty.is_empty());
+        assert!(!meta.save().unwrap());
+
+        let map = CowMap::load(&f).unwrap();
+        assert!(map.map == vec![2, 0, 1] && map.allocated() == 2);
+
+        assert!(merge_overlay(base_s, ovl_s).unwrap() == 2);
+        let data = std::fs::read(&base).unwrap();
+        assert!(data[..65536].iter().all(|b| *b == 3));
+        assert!(data[65536..131072].iter().all(|b| *b == 1));
+        assert!(data[131072..].iter().all(|b| *b == 2));
+
+        let map = CowMap::load(&f).unwrap();
+        assert!(map.allocated() == 0 && map.hdr.nr_slots == 0);
+        assert!(f.metadata().unwrap().len() == map.hdr.data_off);
+        reset_overlay(ovl_s).unwrap();
+
+        let c = CowTgtBuilder::default()
+            .base_path(base_s)
+            .overlay_path(ovl_s)
+            .build()
+            .unwrap();
+        assert!(c.chunk_size == 65536 && c.direct_io && c.allocated_chunks().is_err());
+    }
+}
@@ -0,0 +1,684 @@
+//! Fault injection for any ublk target
+//!
+//! `UblkFaultQueue` wraps target IO handling closure of one queue, either
+//! the sync closure passed to `UblkQueue::wait_and_handle_io()`, or the
+//! `handle_io` closure passed to `targets::run_async_queue()`. Each incoming
+//! IO command is checked against rules of the shared `UblkFaultInjector`
+//! before it is handed to the wrapped closure, so any target built on the
+//! `UblkIOCtx` & `UblkQueue::complete_io_cmd()` path can be wrapped for
+//! resilience testing.
+//!
+//! Supported faults:
+//!
+//! - error: IO command is failed with errno, and target doesn't see it
+//!
+//! - delay: IO command is handed to target after the delay
+//!
+//! - torn write: target only sees the first sectors of the write, which is
+//!   failed with EIO after target handles it, so the head of data is stored
+//!   and the tail isn't though the write fails
+//!
+//! - drop flush: flush is completed successfully without being handed to
+//!   target
+//!
+//! Rules can be changed at runtime via API or the control socket served
+//! by `UblkFaultInjector::serve_unix()`.
+
+use super::UblkRng;
+use crate::exe::UringOpFuture;
+use crate::io::{UblkIOCtx, UblkQueue};
+use crate::{sys, UblkError, UblkIORes};
+use io_uring::{cqueue, opcode, types};
+use serde::{Deserialize, Serialize};
+use std::cell::{RefCell, UnsafeCell};
+use std::future::Future;
+use std::io::{BufRead, BufReader, Write};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// Operation type matched by fault rule
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UblkFaultOp {
+    #[default]
+    Any,
+    Read,
+    Write,
+    Flush,
+    Discard,
+    WriteZeroes,
+}
+
+impl UblkFaultOp {
+    fn matches(&self, op: u32) -> bool {
+        match self {
+            UblkFaultOp::Any => true,
+            UblkFaultOp::Read => op == sys::UBLK_IO_OP_READ,
+            UblkFaultOp::Write => op == sys::UBLK_IO_OP_WRITE,
+            UblkFaultOp::Flush => op == sys::UBLK_IO_OP_FLUSH,
+            UblkFaultOp::Discard => op == sys::UBLK_IO_OP_DISCARD,
+            UblkFaultOp::WriteZeroes => op == sys::UBLK_IO_OP_WRITE_ZEROES,
+        }
+    }
+}
+
+/// Fault injected to the matched IO
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum UblkFaultAction {
+    /// fail IO with positive `errno`
+    Error { errno: i32 },
+
+    /// hand IO to target after `us` microseconds
+    Delay { us: u64 },
+
+    /// hand the first `sectors` of write to target, then fail the write
+    /// with EIO; write not longer than `sectors` isn't matched
+    TornWrite { sectors: u32 },
+
+    /// complete flush without handing it to target
+    DropFlush,
+}
+
+fn default_end_sector() -> u64 {
+    u64::MAX
+}
+
+fn default_probability() -> f64 {
+    1.0
+}
+
+/// Fault injection rule
+///
+/// IO matches the rule if its operation matches `op`, and it overlaps
+/// with sector range [`start_sector`, `end_sector`). The first matched
+/// rule decides the fault.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UblkFaultRule {
+    #[serde(default)]
+    pub op: UblkFaultOp,
+
+    #[serde(default)]
+    pub start_sector: u64,
+
+    #[serde(default = "default_end_sector")]
+    pub end_sector: u64,
+
+    /// probability of injecting fault to matched IO, in [0.0, 1.0]
+    #[serde(default = "default_probability")]
+    pub probability: f64,
+
+    /// don't inject fault to the first `after` matched IOs
+    #[serde(default)]
+    pub after: u64,
+
+    /// max count of injected faults, unlimited if it is None
+    #[serde(default)]
+    pub count: Option<u64>,
+
+    pub action: UblkFaultAction,
+}
+
+impl UblkFaultRule {
+    /// New one rule which matches all IOs
+    pub fn new(action: UblkFaultAction) -> Self {
+        UblkFaultRule {
+            op: UblkFaultOp::Any,
+            start_sector: 0,
+            end_sector: u64::MAX,
+            probability: 1.0,
+            after: 0,
+            count: None,
+            action,
+        }
+    }
+
+    fn matches(&self, iod: &sys::ublksrv_io_desc) -> bool {
+        let op = iod.op_flags & 0xff;
+        let end = iod.start_sector + iod.nr_sectors as u64;
+
+        let applicable = match self.action {
+            UblkFaultAction::TornWrite { sectors } => {
+                op == sys::UBLK_IO_OP_WRITE && iod.nr_sectors > sectors
+            }
+            UblkFaultAction::DropFlush => op == sys::UBLK_IO_OP_FLUSH,
+            _ => true,
+        };
+
+        applicable
+            && self.op.matches(op)
+            && iod.start_sector < self.end_sector
+            && (end > self.start_sector || (iod.nr_sectors == 0 && end >= self.start_sector))
+    }
+}
+
+/// Rule & its counters, returned from `UblkFaultInjector::get_rules()`
+#[derive(Debug, Clone, Serialize)]
+pub struct UblkFaultRuleStat {
+    pub rule: UblkFaultRule,
+
+    /// count of IOs matched with this rule
+    pub matched: u64,
+
+    /// count of injected faults by this rule
+    pub injected: u64,
+}
+
+#[derive(Debug)]
+struct UblkFaultRuleState {
+    rule: UblkFaultRule,
+    matched: AtomicU64,
+    injected: AtomicU64,
+}
+
+impl UblkFaultRuleState {
+    fn new(rule: UblkFaultRule) -> Self {
+        UblkFaultRuleState {
+            rule,
+            matched: AtomicU64::new(0),
+            injected: AtomicU64::new(0),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "cmd")]
+enum UblkFaultRequest {
+    List,
+    Add { rule: UblkFaultRule },
+    Set { rules: Vec<UblkFaultRule> },
+    Remove { index: usize },
+    Clear,
+}
+
+/// Fault injector shared by all queues of one device
+#[derive(Debug, Default)]
+pub struct UblkFaultInjector {
+    rules: RwLock<Vec<UblkFaultRuleState>>,
+}
+
+impl UblkFaultInjector {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    fn check_rule(rule: &UblkFaultRule) -> Result<(), UblkError> {
+        let action_ok = match rule.action {
+            UblkFaultAction::Error { errno } => errno > 0,
+            UblkFaultAction::TornWrite { sectors } => sectors > 0,
+            _ => true,
+        };
+
+        if !(0.0..=1.0).contains(&rule.probability)
+            || rule.start_sector >= rule.end_sector
+            || !action_ok
+        {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+        Ok(())
+    }
+
+    /// Append one rule
+    pub fn add_rule(&self, rule: UblkFaultRule) -> Result<(), UblkError> {
+        Self::check_rule(&rule)?;
+        self.rules
+            .write()
+            .unwrap()
+            .push(UblkFaultRuleState::new(rule));
+        Ok(())
+    }
+
+    /// Replace all rules
+    pub fn set_rules(&self, rules: Vec<UblkFaultRule>) -> Result<(), UblkError> {
+        for r in &rules {
+            Self::check_rule(r)?;
+        }
+        *self.rules.write().unwrap() = rules.into_iter().map(UblkFaultRuleState::new).collect();
+        Ok(())
+    }
+
+    /// Remove rule at `index`
+    pub fn remove_rule(&self, index: usize) -> Result<UblkFaultRule, UblkError> {
+        let mut rules = self.rules.write().unwrap();
+
+        if index >= rules.len() {
+            return Err(UblkError::OtherError(-libc::ENOENT));
+        }
+        Ok(rules.remove(index).rule)
+    }
+
+    /// Remove all rules
+    pub fn clear(&self) {
+        self.rules.write().unwrap().clear();
+    }
+
+    /// Return all rules and their counters
+    pub fn get_rules(&self) -> Vec<UblkFaultRuleStat> {
+        self.rules
+            .read()
+            .unwrap()
+            .iter()
+            .map(|r| UblkFaultRuleStat {
+                rule: r.rule.clone(),
+                matched: r.matched.load(Ordering::Relaxed),
+                injected: r.injected.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Return fault to be injected to IO `iod`
+    fn check(&self, iod: &sys::ublksrv_io_desc, rng: &UblkRng) -> Option<UblkFaultAction> {
+        let rules = self.rules.read().unwrap();
+
+        for r in rules.iter() {
+            if !r.rule.matches(iod) {
+                continue;
+            }
+            if r.matched.fetch_add(1, Ordering::Relaxed) < r.rule.after {
+                continue;
+            }
+            if r.rule.probability < 1.0 && rng.next_f64() >= r.rule.probability {
+                continue;
+            }
+            if let Some(cnt) = r.rule.count {
+                let res = r
+                    .injected
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| {
+                        (v < cnt).then_some(v + 1)
+                    });
+                if res.is_err() {
+                    continue;
+                }
+            } else {
+                r.injected.fetch_add(1, Ordering::Relaxed);
+            }
+            return Some(r.rule.action);
+        }
+        None
+    }
+
+    /// Handle one control request in json, and return json reply
+    ///
+    /// Supported requests:
+    ///
+    /// - `{"cmd": "list"}`
+    /// - `{"cmd": "add", "rule": RULE}`
+    /// - `{"cmd": "set", "rules": [RULE, ...]}`
+    /// - `{"cmd": "remove", "index": N}`
+    /// - `{"cmd": "clear"}`
+    ///
+    /// RULE is serialized `UblkFaultRule`, such as
+    /// `{"op": "write", "probability": 0.1, "action": {"type": "error", "errno": 5}}`
+    pub fn handle_request(&self, req: &str) -> String {
+        let res = match serde_json::from_str::<UblkFaultRequest>(req) {
+            Err(e) => Err(e.to_string()),
+            Ok(UblkFaultRequest::List) => Ok(serde_json::json!({ "rules": self.get_rules() })),
+            Ok(UblkFaultRequest::Add { rule }) => self
+                .add_rule(rule)
+                .map(|_| serde_json::json!({}))
+                .map_err(|e| e.to_string()),
+            Ok(UblkFaultRequest::Set { rules }) => self
+                .set_rules(rules)
+                .map(|_| serde_json::json!({}))
+                .map_err(|e| e.to_string()),
+            Ok(UblkFaultRequest::Remove { index }) => self
+                .remove_rule(index)
+                .map(|r| serde_json::json!({ "rule": r }))
+                .map_err(|e| e.to_string()),
+            Ok(UblkFaultRequest::Clear) => {
+                self.clear();
+                Ok(serde_json::json!({}))
+            }
+        };
+
+        match res {
+            Ok(mut v) => {
+                v["ok"] = serde_json::json!(true);
+                v.to_string()
+            }
+            Err(e) => serde_json::json!({ "ok": false, "error": e }).to_string(),
+        }
+    }
+
+    /// Serve control requests over unix socket `path`
+    ///
+    /// Each line received is handled by `handle_request()`, and the reply
+    /// is written back as one line. Each connection is served in its own
+    /// thread. Stale socket at `path` is replaced, and -EEXIST is returned
+    /// if `path` is other kind of file. Return handle of the listening
+    /// thread.
+    pub fn serve_unix(
+        self: &Arc<Self>,
+        path: &str,
+    ) -> Result<std::thread::JoinHandle<()>, UblkError> {
+        let listener = super::bind_unix_socket(path)?;
+        let inj = self.clone();
+
+        Ok(std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let inj = inj.clone();
+
+                std::thread::spawn(move || inj.serve_conn(stream));
+            }
+        }))
+    }
+
+    fn serve_conn(&self, stream: std::os::unix::net::UnixStream) {
+        let mut writer = match stream.try_clone() {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(_) => break,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let reply = self.handle_request(&line) + "\n";
+            if writer.write_all(reply.as_bytes()).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// How to handle the incoming IO command, returned from
+/// `UblkFaultQueue::prep()`
+enum UblkFaultPrep {
+    /// complete the IO command with the result
+    Complete(i32),
+
+    /// hand the IO command to target after the delay
+    Delay(std::time::Duration),
+
+    /// hand the first sectors of the write to target, and fail it with EIO
+    Torn(u32),
+}
+
+/// Per-tag state of delayed IO in sync IO handling
+#[derive(Default)]
+struct UblkFaultIo {
+    /// timespec has to live until the timeout is completed
+    ts: UnsafeCell<types::Timespec>,
+
+    /// IO command cqe, replayed to target after the delay
+    cqe: RefCell<Option<cqueue::Entry>>,
+}
+
+/// Per-queue fault injection context, which wraps target IO handling
+/// closure of one queue
+pub struct UblkFaultQueue {
+    inj: Arc<UblkFaultInjector>,
+    rng: UblkRng,
+    ios: Vec<UblkFaultIo>,
+}
+
+impl UblkFaultQueue {
+    /// Marker of user_data of delay timeout in sync IO handling
+    const DELAY_USER_DATA: u64 = 1_u64 << 62;
+
+    /// # Arguments:
+    ///
+    /// * `inj`: fault injector shared by all queues
+    /// * `qid`: queue id
+    /// * `depth`: queue depth
+    pub fn new(inj: &Arc<UblkFaultInjector>, qid: u16, depth: u32) -> Self {
+        UblkFaultQueue {
+            inj: inj.clone(),
+            rng: UblkRng::new(qid),
+            ios: (0..depth).map(|_| Default::default()).collect(),
+        }
+    }
+
+    /// Check the incoming IO command against rules
+    fn prep(&self, iod: &sys::ublksrv_io_desc) -> Option<UblkFaultPrep> {
+        match self.inj.check(iod, &self.rng)? {
+            UblkFaultAction::Error { errno } => Some(UblkFaultPrep::Complete(-errno)),
+            UblkFaultAction::DropFlush => Some(UblkFaultPrep::Complete(0)),
+            UblkFaultAction::Delay { us } => {
+                Some(UblkFaultPrep::Delay(std::time::Duration::from_micros(us)))
+            }
+            UblkFaultAction::TornWrite { sectors } => Some(UblkFaultPrep::Torn(sectors)),
+        }
+    }
+
+    /// Queue timeout for delaying IO, the IO isn't delayed if the timeout
+    /// can't be queued
+    fn queue_timeout(q: &UblkQueue, ts: *const types::Timespec, user_data: u64) -> bool {
+        let sqe = opcode::Timeout::new(ts)
+            .flags(types::TimeoutFlags::ETIME_SUCCESS)
+            .build()
+            .user_data(user_data);
+
+        super::queue_sqe(q, &sqe).is_ok()
+    }
+
+    /// Shorten write `tag` to its first `sectors`, and the write is failed
+    /// with EIO when it is committed
+    fn tear_write(q: &UblkQueue, tag: u16, sectors: u32) {
+        let mut iod = *q.get_iod(tag);
+
+        iod.nr_sectors = sectors;
+        q.override_io(tag, iod, -libc::EIO);
+    }
+
+    /// Inject fault to the incoming IO command, return true if the IO
+    /// command isn't handed to target now
+    fn handle_io(&self, q: &UblkQueue, tag: u16, io: &UblkIOCtx) -> bool {
+        match self.prep(q.get_iod(tag)) {
+            Some(UblkFaultPrep::Complete(res)) => {
+                q.complete_io_cmd(tag, Ok(UblkIORes::Result(res)));
+                true
+            }
+            Some(UblkFaultPrep::Torn(sectors)) => {
+                Self::tear_write(q, tag, sectors);
+                false
+            }
+            Some(UblkFaultPrep::Delay(delay)) => {
+                let f = &self.ios[tag as usize];
+                let op = UblkIOCtx::user_data_to_op(io.user_data());
+                let user_data =
+                    UblkIOCtx::build_user_data(tag, op, 0, true) | Self::DELAY_USER_DATA;
+
+                // cqueue::Entry is plain data without Clone, copy it for
+                // replaying the IO command after the delay
+                *f.cqe.borrow_mut() = Some(unsafe { std::ptr::read(io.0) });
+                unsafe {
+                    *f.ts.get() = types::Timespec::from(delay);
+                }
+                if Self::queue_timeout(q, f.ts.get(), user_data) {
+                    return true;
+                }
+                f.cqe.borrow_mut().take();
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Wrap sync IO handling closure passed to
+    /// `UblkQueue::wait_and_handle_io()`
+    ///
+    /// # Arguments:
+    ///
+    /// * `ops`: target IO handling closure
+    pub fn wrap_io_handler<F>(self, mut ops: F) -> impl FnMut(&UblkQueue, u16, &UblkIOCtx)
+    where
+        F: FnMut(&UblkQueue, u16, &UblkIOCtx),
+    {
+        move |q: &UblkQueue, tag: u16, io: &UblkIOCtx| {
+            if !io.is_tgt_io() {
+                if !self.handle_io(q, tag, io) {
+                    ops(q, tag, io);
+                }
+            } else if (io.user_data() & Self::DELAY_USER_DATA) != 0 {
+                // hand the delayed IO command to target
+                let cqe = self.ios[tag as usize].cqe.borrow_mut().take();
+
+                if let Some(cqe) = cqe {
+                    ops(q, tag, &UblkIOCtx(&cqe, io.1));
+                }
+            } else {
+                ops(q, tag, io);
+            }
+        }
+    }
+
+    /// Wrap `handle_io` closure passed to `targets::run_async_queue()`
+    ///
+    /// # Arguments:
+    ///
+    /// * `handle_io`: return future for handling the incoming IO command
+    pub fn wrap_async_handler<'a, F, Fut>(
+        self,
+        handle_io: F,
+    ) -> impl Fn(Rc<UblkQueue<'a>>, u16) -> Pin<Box<dyn Future<Output = i32> + 'a>> + 'a
+    where
+        F: Fn(Rc<UblkQueue<'a>>, u16) -> Fut + 'a,
+        Fut: Future<Output = i32> + 'a,
+    {
+        let fq = Rc::new(self);
+        let h = Rc::new(handle_io);
+
+        move |q: Rc<UblkQueue<'a>>, tag: u16| {
+            let fq = fq.clone();
+            let h = h.clone();
+
+            Box::pin(async move {
+                match fq.prep(q.get_iod(tag)) {
+                    Some(UblkFaultPrep::Complete(res)) => res,
+                    Some(UblkFaultPrep::Torn(sectors)) => {
+                        Self::tear_write(&q, tag, sectors);
+                        h(q.clone(), tag).await
+                    }
+                    Some(UblkFaultPrep::Delay(delay)) => {
+                        let ts = types::Timespec::from(delay);
+                        let user_data = UblkIOCtx::build_user_data_async(tag, 0, 0);
+
+                        if Self::queue_timeout(&q, &ts, user_data) {
+                            UringOpFuture { user_data }.await;
+                        }
+                        h(q.clone(), tag).await
+                    }
+                    None => h(q.clone(), tag).await,
+                }
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UblkFaultPrep;
+    use super::{UblkFaultAction, UblkFaultInjector, UblkFaultOp, UblkFaultQueue, UblkFaultRule};
+    use crate::sys;
+    use std::io::{BufRead, BufReader, Write};
+    use std::sync::Arc;
+
+    fn iod(op: u32, start_sector: u64, nr_sectors: u32) -> sys::ublksrv_io_desc {
+        sys::ublksrv_io_desc {
+            op_flags: op,
+            nr_sectors,
+            start_sector,
+            addr: 0,
+        }
+    }
+
+    #[test]
+    fn test_fault_rules() {
+        let inj = Arc::new(UblkFaultInjector::new());
+        let fq = UblkFaultQueue::new(&inj, 0, 4);
+
+        // fail the 3rd and 4th write in sector range [100, 200)
+        let mut rule = UblkFaultRule::new(UblkFaultAction::Error { errno: libc::EIO });
+        rule.op = UblkFaultOp::Write;
+        rule.start_sector = 100;
+        rule.end_sector = 200;
+        rule.after = 2;
+        rule.count = Some(2);
+        inj.add_rule(rule).unwrap();
+
+        let w = iod(sys::UBLK_IO_OP_WRITE, 96, 8);
+        assert!(fq.prep(&iod(sys::UBLK_IO_OP_READ, 96, 8)).is_none());
+        assert!(fq.prep(&iod(sys::UBLK_IO_OP_WRITE, 200, 8)).is_none());
+        let res: Vec<Option<i32>> = (0..5)
+            .map(|_| match fq.prep(&w) {
+                Some(UblkFaultPrep::Complete(r)) => Some(r),
+                _ => None,
+            })
+            .collect();
+        assert!(res == vec![None, None, Some(-libc::EIO), Some(-libc::EIO), None]);
+        assert!(inj.get_rules()[0].injected == 2);
+
+        // torn write only applies to write longer than `sectors`
+        inj.set_rules(vec![UblkFaultRule::new(UblkFaultAction::TornWrite {
+            sectors: 4,
+        })])
+        .unwrap();
+        assert!(matches!(fq.prep(&w), Some(UblkFaultPrep::Torn(4))));
+        assert!(fq.prep(&iod(sys::UBLK_IO_OP_WRITE, 96, 4)).is_none());
+        assert!(fq.prep(&iod(sys::UBLK_IO_OP_READ, 96, 8)).is_none());
+        assert!(inj
+            .add_rule(UblkFaultRule::new(UblkFaultAction::TornWrite {
+                sectors: 0
+            }))
+            .is_err());
+
+        // drop flush only applies to flush
+        inj.set_rules(vec![UblkFaultRule::new(UblkFaultAction::DropFlush)])
+            .unwrap();
+        assert!(fq.prep(&w).is_none());
+        assert!(matches!(
+            fq.prep(&iod(sys::UBLK_IO_OP_FLUSH, 0, 0)),
+            Some(UblkFaultPrep::Complete(0))
+        ));
+
+        let mut bad = UblkFaultRule::new(UblkFaultAction::Delay { us: 10 });
+        bad.probability = 2.0;
+        assert!(inj.add_rule(bad).is_err());
+    }
+
+    #[test]
+    fn test_fault_control_socket() {
+        let inj = Arc::new(UblkFaultInjector::new());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fault.sock");
+        let _h = inj.serve_unix(path.to_str().unwrap()).unwrap();
+
+        // one idle connection can't block others
+        let _idle = std::os::unix::net::UnixStream::connect(&path).unwrap();
+        let stream = std::os::unix::net::UnixStream::connect(&path).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut req = |r: &str| {
+            let mut line = String::new();
+
+            writer.write_all(format!("{}\n", r).as_bytes()).unwrap();
+            reader.read_line(&mut line).unwrap();
+            serde_json::from_str::<serde_json::Value>(&line).unwrap()
+        };
+
+        let r = req(
+            r#"{"cmd": "add", "rule": {"op": "read", "probability": 0.5, "action": {"type": "delay", "us": 1000}}}"#,
+        );
+        assert!(r["ok"] == true);
+        let r = req(r#"{"cmd": "list"}"#);
+        assert!(r["rules"][0]["rule"]["op"] == "read");
+        assert!(r["rules"][0]["rule"]["action"]["us"] == 1000);
+        assert!(req(r#"{"cmd": "remove", "index": 1}"#)["ok"] == false);
+        assert!(req(r#"{"cmd": "clear"}"#)["ok"] == true);
+        assert!(req(r#"{"cmd": "bad"}"#)["ok"] == false);
+        assert!(inj.get_rules().is_empty());
+
+        // regular file isn't removed for binding
+        let file = dir.path().join("fault.txt");
+        std::fs::write(&file, b"data").unwrap();
+        assert!(inj.serve_unix(file.to_str().unwrap()).is_err());
+        assert!(std::fs::read(&file).unwrap() == b"data");
+    }
+}
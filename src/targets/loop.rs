@@ -0,0 +1,410 @@
+//! Loop target: expose one regular file or block device as ublk device
+//!
+//! IO is handled by io_uring over the backing file, and both sync and
+//! async/.await queue handling are supported. Discard & write zeroes are
+//! handled by fallocate() for regular file, and by synchronous BLKDISCARD &
+//! BLKZEROOUT ioctl in queue thread for block device.
+
+use crate::dev_flags::UBLK_DEV_F_ASYNC;
+use crate::exe::UringOpFuture;
+use crate::io::{UblkDev, UblkIOCtx, UblkQueue};
+use crate::{sys, UblkError, UblkIORes};
+use io_uring::{opcode, squeue, types};
+use serde::Serialize;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+
+#[derive(Debug, Serialize)]
+struct LoopJson {
+    back_file_path: String,
+    direct_io: bool,
+    read_only: bool,
+    #[serde(rename = "async")]
+    async_io: bool,
+    split: bool,
+}
+
+/// Loop target
+///
+/// Built by `LoopTgtBuilder`, and the backing file is opened in
+/// `init_tgt()`. The target has to live in the whole device lifetime, and
+/// it is cheap to clone for moving into queue closure.
+#[derive(Default, Builder, Debug, Clone)]
+#[builder(setter(into))]
+pub struct LoopTgt {
+    /// path of backing file or block device
+    back_file_path: String,
+
+    /// open backing file with O_DIRECT
+    #[builder(default = "true")]
+    direct_io: bool,
+
+    /// expose read-only ublk device
+    #[builder(default = "false")]
+    read_only: bool,
+
+    /// fallback to buffered IO if O_DIRECT isn't supported by backing file,
+    /// otherwise `init_tgt()` fails
+    #[builder(default = "true")]
+    buffered_fallback: bool,
+
+    /// handle IO by async/.await, the device has to be created with
+    /// `UBLK_DEV_F_ASYNC`
+    #[builder(default = "false")]
+    async_io: bool,
+
+    /// handle WRITE_ZEROES of regular file by FALLOC_FL_ZERO_RANGE,
+    /// otherwise hole is punched unless REQ_NOUNMAP is set
+    #[builder(default = "false")]
+    zero_range: bool,
+
+    /// split READ/WRITE bigger than 4K into two target IOs, so one io task
+    /// waits for concurrent target IOs; only supported with `async_io`
+    #[builder(default = "false")]
+    split: bool,
+
+    #[builder(setter(skip))]
+    back_file: Option<Arc<std::fs::File>>,
+
+    #[builder(setter(skip))]
+    io: LoopQueueIo,
+}
+
+/// Per-queue view of the loop target, copied into each io task
+#[derive(Default, Debug, Clone, Copy)]
+struct LoopQueueIo {
+    /// index of backing file in registered fixed files
+    fd_idx: u32,
+    fd: i32,
+    blkdev: bool,
+    direct: bool,
+    read_only: bool,
+    zero_range: bool,
+    split: bool,
+}
+
+impl LoopQueueIo {
+    /// Queue target IO for handling io command `tag`
+    ///
+    /// Return `Some(res)` if the io command is done synchronously or fails
+    /// to be queued.
+    fn queue_io(&self, q: &UblkQueue, tag: u16, user_data: u64) -> Option<i32> {
+        let iod = q.get_iod(tag);
+        let op = iod.op_flags & 0xff;
+        let off = iod.start_sector << 9;
+        let bytes = iod.nr_sectors << 9;
+        let fd = types::Fixed(self.fd_idx);
+
+        if self.read_only && op != sys::UBLK_IO_OP_READ && op != sys::UBLK_IO_OP_FLUSH {
+            return Some(-libc::EROFS);
+        }
+
+        let sqe = match op {
+            sys::UBLK_IO_OP_FLUSH => opcode::Fsync::new(fd)
+                .flags(types::FsyncFlags::DATASYNC)
+                .build(),
+            sys::UBLK_IO_OP_READ | sys::UBLK_IO_OP_WRITE => {
+                self.rw_sqe(op, off, bytes, q.get_io_buf_addr(tag))
+            }
+            sys::UBLK_IO_OP_DISCARD | sys::UBLK_IO_OP_WRITE_ZEROES => {
+                let zero = op == sys::UBLK_IO_OP_WRITE_ZEROES;
+
+                if self.blkdev {
+                    return Some(super::blk_discard(self.fd, off, bytes as u64, zero));
+                }
+
+                opcode::Fallocate::new(fd, bytes as u64)
+                    .offset(off)
+                    .mode(super::discard_fallocate_mode(iod.op_flags, self.zero_range))
+                    .build()
+            }
+            _ => return Some(-libc::EINVAL),
+        };
+        let sqe = sqe.flags(squeue::Flags::FIXED_FILE).user_data(user_data);
+
+        super::queue_sqe(q, &sqe).err()
+    }
+
+    /// Build READ/WRITE sqe for `bytes` at `off` with io buffer `buf`
+    fn rw_sqe(&self, op: u32, off: u64, bytes: u32, buf: *mut u8) -> squeue::Entry {
+        let fd = types::Fixed(self.fd_idx);
+
+        if op == sys::UBLK_IO_OP_READ {
+            opcode::Read::new(fd, buf, bytes).offset(off).build()
+        } else {
+            opcode::Write::new(fd, buf, bytes).offset(off).build()
+        }
+    }
+
+    /// Handle READ/WRITE by two target IOs, the 1st one covers the 1st 4K
+    async fn handle_io_async_split(&self, q: &UblkQueue<'_>, tag: u16) -> i32 {
+        let iod = q.get_iod(tag);
+        let op = iod.op_flags & 0xff;
+        let off = iod.start_sector << 9;
+        let bytes = iod.nr_sectors << 9;
+        let buf = q.get_io_buf_addr(tag);
+        let user_data = UblkIOCtx::build_user_data_async(tag, op, 0);
+        let user_data2 = UblkIOCtx::build_user_data_async(tag, op, 1);
+        let sqes = [
+            self.rw_sqe(op, off, 4096, buf).user_data(user_data),
+            self.rw_sqe(op, off + 4096, bytes - 4096, unsafe { buf.add(4096) })
+                .user_data(user_data2),
+        ];
+
+        let [sqe, sqe2] = sqes.map(|sqe| sqe.flags(squeue::Flags::FIXED_FILE));
+        if let Err(e) = super::queue_sqe(q, &sqe) {
+            return e;
+        }
+        let f = UringOpFuture { user_data };
+        if let Err(e) = super::queue_sqe(q, &sqe2) {
+            // the 1st one is queued already
+            f.await;
+            return e;
+        }
+
+        let f2 = UringOpFuture {
+            user_data: user_data2,
+        };
+        match futures::join!(f, f2) {
+            (res, _) if res < 0 => res,
+            (_, res2) if res2 < 0 => res2,
+            (res, res2) => res + res2,
+        }
+    }
+
+    async fn handle_io_async(&self, q: &UblkQueue<'_>, tag: u16) -> i32 {
+        let iod = q.get_iod(tag);
+        let op = iod.op_flags & 0xff;
+
+        if self.split
+            && (iod.nr_sectors << 9) > 4096
+            && (op == sys::UBLK_IO_OP_READ || (op == sys::UBLK_IO_OP_WRITE && !self.read_only))
+        {
+            return self.handle_io_async_split(q, tag).await;
+        }
+
+        for _ in 0..4 {
+            // either start to handle or retry
+            let user_data = UblkIOCtx::build_user_data_async(tag, op, 0);
+
+            if let Some(res) = self.queue_io(q, tag, user_data) {
+                return res;
+            }
+            let res = UringOpFuture { user_data }.await;
+            if res != -libc::EAGAIN {
+                return res;
+            }
+        }
+
+        -libc::EAGAIN
+    }
+
+    fn handle_io_sync(&self, q: &UblkQueue, tag: u16, io: &UblkIOCtx) {
+        if io.is_tgt_io() {
+            let res = io.result();
+
+            if res != -libc::EAGAIN {
+                q.complete_io_cmd(tag, Ok(UblkIORes::Result(res)));
+                return;
+            }
+        }
+
+        let op = q.get_iod(tag).op_flags & 0xff;
+        let user_data = UblkIOCtx::build_user_data(tag, op, 0, true);
+        if let Some(res) = self.queue_io(q, tag, user_data) {
+            q.complete_io_cmd(tag, Ok(UblkIORes::Result(res)));
+        }
+    }
+}
+
+impl LoopTgt {
+    /// Return `UBLK_DEV_F_*` flags required by this target
+    pub fn dev_flags(&self) -> u32 {
+        if self.async_io {
+            UBLK_DEV_F_ASYNC
+        } else {
+            0
+        }
+    }
+
+    /// Return if backing file is opened with O_DIRECT
+    ///
+    /// Only meaningful after `init_tgt()` returns, and it is false if
+    /// buffered IO is fallen back.
+    pub fn direct_io_enabled(&self) -> bool {
+        self.io.direct
+    }
+
+    fn set_direct_io(&self, f: &std::fs::File) -> Result<bool, UblkError> {
+        let fd = f.as_raw_fd();
+        let ret = unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+
+            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_DIRECT)
+        };
+
+        if ret == 0 {
+            return Ok(true);
+        }
+
+        let err = std::io::Error::last_os_error();
+        if self.buffered_fallback {
+            log::warn!(
+                "loop: {} doesn't support O_DIRECT({}), fallback to buffered IO",
+                self.back_file_path,
+                err
+            );
+            Ok(false)
+        } else {
+            Err(UblkError::OtherIOError(err))
+        }
+    }
+
+    /// Setup loop target, passed to `UblkSession::create_devices()`
+    ///
+    /// # Arguments:
+    ///
+    /// * `dev`: ublk device being created
+    ///
+    /// Open the backing file, register it as fixed file, and setup device
+    /// size, parameters and target json.
+    pub fn init_tgt(&mut self, dev: &mut UblkDev) -> Result<i32, UblkError> {
+        log::trace!("loop: init_tgt {}", dev.dev_info.dev_id);
+
+        if ((dev.flags & UBLK_DEV_F_ASYNC) != 0) != self.async_io || (self.split && !self.async_io)
+        {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(!self.read_only)
+            .open(&self.back_file_path)
+            .map_err(UblkError::OtherIOError)?;
+        let (size, lbs_shift, pbs_shift) = super::backing_file_size(&file)?;
+        let blkdev = super::is_block_device(&file);
+        let direct = self.direct_io && self.set_direct_io(&file)?;
+
+        let tgt = &mut dev.tgt;
+        if self.split {
+            // each io command may have two target IOs in flight
+            tgt.sq_depth *= 2;
+            tgt.cq_depth *= 2;
+        }
+        let nr_fds = tgt.nr_fds as usize;
+        if nr_fds >= tgt.fds.len() {
+            return Err(UblkError::OtherError(-libc::EMFILE));
+        }
+        tgt.fds[nr_fds] = file.as_raw_fd();
+        tgt.nr_fds = nr_fds as i32 + 1;
+
+        tgt.dev_size = size;
+        tgt.params = sys::ublk_params {
+            types: sys::UBLK_PARAM_TYPE_BASIC,
+            basic: sys::ublk_param_basic {
+                attrs: if self.read_only {
+                    sys::UBLK_ATTR_READ_ONLY
+                } else {
+                    0
+                } | sys::UBLK_ATTR_VOLATILE_CACHE,
+                logical_bs_shift: lbs_shift,
+                physical_bs_shift: pbs_shift,
+                io_opt_shift: 12,
+                io_min_shift: lbs_shift,
+                max_sectors: dev.dev_info.max_io_buf_bytes >> 9,
+                dev_sectors: size >> 9,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        if !self.read_only {
+            tgt.params.types |= sys::UBLK_PARAM_TYPE_DISCARD;
+            tgt.params.discard = sys::ublk_param_discard {
+                discard_granularity: 1 << pbs_shift,
+                max_discard_sectors: u32::MAX >> 9,
+                max_write_zeroes_sectors: u32::MAX >> 9,
+                max_discard_segments: 1,
+                ..Default::default()
+            };
+        }
+
+        self.io = LoopQueueIo {
+            fd_idx: nr_fds as u32,
+            fd: file.as_raw_fd(),
+            blkdev,
+            direct,
+            read_only: self.read_only,
+            zero_range: self.zero_range,
+            split: self.split,
+        };
+        self.back_file = Some(Arc::new(file));
+
+        if blkdev {
+            dev.set_max_dev_size(size);
+        }
+        let val = serde_json::json!({"loop": LoopJson {
+            back_file_path: self.back_file_path.clone(),
+            direct_io: direct,
+            read_only: self.read_only,
+            async_io: self.async_io,
+            split: self.split,
+        }});
+        dev.set_target_json(val);
+
+        Ok(0)
+    }
+
+    /// Handle IO of queue `qid`, called from queue closure
+    ///
+    /// # Arguments:
+    ///
+    /// * `qid`: queue id
+    /// * `dev`: ublk device
+    ///
+    /// Won't return until the queue is down.
+    pub fn run_queue(&self, qid: u16, dev: &UblkDev) -> Result<(), UblkError> {
+        let io = self.io;
+
+        if self.back_file.is_none() {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        if self.async_io {
+            super::run_async_queue(qid, dev, move |q, tag| async move {
+                io.handle_io_async(&q, tag).await
+            })
+        } else {
+            UblkQueue::new(qid, dev)?.wait_and_handle_io(
+                move |q: &UblkQueue, tag: u16, i: &UblkIOCtx| io.handle_io_sync(q, tag, i),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoopTgtBuilder;
+
+    #[test]
+    fn test_loop_builder() {
+        let tmp = tempfile::NamedTempFile::new().unwrap();
+        tmp.as_file().set_len(16 << 20).unwrap();
+
+        let lo = LoopTgtBuilder::default()
+            .back_file_path(tmp.path().to_str().unwrap())
+            .async_io(true)
+            .build()
+            .unwrap();
+        assert!(
+            lo.direct_io && lo.buffered_fallback && !lo.read_only && !lo.zero_range && !lo.split
+        );
+        assert!(lo.dev_flags() == crate::dev_flags::UBLK_DEV_F_ASYNC);
+        assert!(!lo.direct_io_enabled());
+
+        let (size, lbs, pbs) = crate::targets::backing_file_size(tmp.as_file()).unwrap();
+        assert!(size == 16 << 20 && lbs == 9 && pbs == 12);
+        assert!(!crate::targets::is_block_device(tmp.as_file()));
+
+        assert!(LoopTgtBuilder::default().build().is_err());
+    }
+}
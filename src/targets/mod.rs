@@ -0,0 +1,400 @@
+//! Reusable ublk targets
+//!
+//! Each target is configured by its builder, then `init_tgt()` is passed
+//! to `UblkSession::create_devices()` for setting up the device, and
+//! `run_queue()` is called from the queue closure of
+//! `UblkSession::run_target()` for handling IO.
+
+use super::exe::{Executor, UringOpFuture, UringOpFutureBatch};
+use super::io::{UblkDev, UblkIOCtx, UblkQueue};
+use super::{dev_flags::UBLK_DEV_F_ASYNC, sys, UblkError};
+use io_uring::squeue;
+use std::cell::Cell;
+use std::future::Future;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::rc::Rc;
+use std::sync::Arc;
+
+pub mod cow;
+pub mod fault;
+pub mod r#loop;
+pub mod nbd;
+pub mod null;
+pub mod qcow2;
+pub mod raid1;
+pub mod ramdisk;
+pub mod sparse;
+pub mod stripe;
+
+// block device ioctls defined in linux/fs.h
+const BLKGETSIZE64: u64 = 0x8008_1272;
+const BLKSSZGET: u64 = 0x1268;
+const BLKPBSZGET: u64 = 0x127b;
+const BLKDISCARD: u64 = 0x1277;
+const BLKZEROOUT: u64 = 0x127f;
+
+/// Per-queue pseudo random generator(xorshift64*), good enough for
+/// picking latency & IO to fail in test targets
+#[derive(Debug)]
+pub(crate) struct UblkRng(Cell<u64>);
+
+impl UblkRng {
+    pub(crate) fn new(qid: u16) -> Self {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+
+        UblkRng(Cell::new((seed ^ ((qid as u64 + 1) << 32)) | 1))
+    }
+
+    /// Return random value in [0.0, 1.0)
+    pub(crate) fn next_f64(&self) -> f64 {
+        let mut x = self.0.get();
+
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0.set(x);
+
+        (x.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1_u64 << 53) as f64
+    }
+}
+
+#[inline]
+fn errno() -> i32 {
+    std::io::Error::last_os_error()
+        .raw_os_error()
+        .unwrap_or(libc::EIO)
+}
+
+/// Return if `f` is one block device
+pub(crate) fn is_block_device(f: &std::fs::File) -> bool {
+    f.metadata()
+        .map(|m| m.file_type().is_block_device())
+        .unwrap_or(false)
+}
+
+/// Return (size, logical block size shift, physical block size shift) of
+/// backing file or block device
+pub(crate) fn backing_file_size(f: &std::fs::File) -> Result<(u64, u8, u8), UblkError> {
+    let meta = f.metadata().map_err(UblkError::OtherIOError)?;
+
+    if meta.file_type().is_block_device() {
+        let fd = f.as_raw_fd();
+        let mut cap = 0_u64;
+        let mut ssz = 0_i32;
+        let mut pbsz = 0_u32;
+
+        unsafe {
+            if libc::ioctl(fd, BLKGETSIZE64 as _, &mut cap as *mut u64) < 0
+                || libc::ioctl(fd, BLKSSZGET as _, &mut ssz as *mut i32) < 0
+                || libc::ioctl(fd, BLKPBSZGET as _, &mut pbsz as *mut u32) < 0
+            {
+                return Err(UblkError::OtherError(-errno()));
+            }
+        }
+
+        Ok((cap, ssz.trailing_zeros() as u8, pbsz.trailing_zeros() as u8))
+    } else if meta.file_type().is_file() {
+        Ok((meta.len(), 9, 12))
+    } else {
+        Err(UblkError::OtherError(-libc::EINVAL))
+    }
+}
+
+/// One backing file or block device registered as fixed file, used by
+/// targets built over multiple members
+#[derive(Debug, Clone)]
+pub(crate) struct TgtMember {
+    pub(crate) file: Arc<std::fs::File>,
+    /// index in registered fixed files
+    pub(crate) fd_idx: u32,
+    pub(crate) blkdev: bool,
+    pub(crate) direct: bool,
+    pub(crate) size: u64,
+    pub(crate) lbs_shift: u8,
+    pub(crate) pbs_shift: u8,
+}
+
+impl TgtMember {
+    /// Open `path` and register it as fixed file of `dev`
+    ///
+    /// O_DIRECT is tried if `direct` is true, and buffered IO is fallen
+    /// back if it isn't supported.
+    pub(crate) fn open(
+        dev: &mut UblkDev,
+        path: &str,
+        direct: bool,
+        read_only: bool,
+    ) -> Result<Self, UblkError> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(path)
+            .map_err(UblkError::OtherIOError)?;
+        let (size, lbs_shift, pbs_shift) = backing_file_size(&file)?;
+        let fd = file.as_raw_fd();
+        let direct = direct && {
+            let ret = unsafe {
+                let flags = libc::fcntl(fd, libc::F_GETFL);
+
+                libc::fcntl(fd, libc::F_SETFL, flags | libc::O_DIRECT)
+            };
+
+            if ret != 0 {
+                log::warn!("{} doesn't support O_DIRECT, fallback to buffered IO", path);
+            }
+            ret == 0
+        };
+
+        let tgt = &mut dev.tgt;
+        let nr_fds = tgt.nr_fds as usize;
+        if nr_fds >= tgt.fds.len() {
+            return Err(UblkError::OtherError(-libc::EMFILE));
+        }
+        tgt.fds[nr_fds] = fd;
+        tgt.nr_fds = nr_fds as i32 + 1;
+
+        Ok(TgtMember {
+            blkdev: is_block_device(&file),
+            file: Arc::new(file),
+            fd_idx: nr_fds as u32,
+            direct,
+            size,
+            lbs_shift,
+            pbs_shift,
+        })
+    }
+
+    /// Raw fd, only for sync operations like BLKDISCARD
+    pub(crate) fn fd(&self) -> i32 {
+        self.file.as_raw_fd()
+    }
+}
+
+/// IO buffer aligned for O_DIRECT, freed when it is dropped
+#[derive(Debug)]
+pub(crate) struct TgtBuf(*mut u8, usize);
+
+impl TgtBuf {
+    pub(crate) fn new(len: usize) -> Self {
+        TgtBuf(crate::ublk_alloc_buf(len, 4096), len)
+    }
+
+    pub(crate) fn as_mut_ptr(&self) -> *mut u8 {
+        self.0
+    }
+
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.0, self.1) }
+    }
+}
+
+impl Drop for TgtBuf {
+    fn drop(&mut self) {
+        crate::ublk_dealloc_buf(self.0, self.1, 4096);
+    }
+}
+
+/// Discard or zero range of block device synchronously
+///
+/// # Arguments:
+///
+/// * `fd`: raw fd of block device
+/// * `off`: start offset in bytes
+/// * `len`: length in bytes
+/// * `zero`: zero the range by BLKZEROOUT, otherwise discard it by BLKDISCARD
+///
+/// Return 0 on success, otherwise negative errno.
+pub(crate) fn blk_discard(fd: i32, off: u64, len: u64, zero: bool) -> i32 {
+    let range = [off, len];
+    let req = if zero { BLKZEROOUT } else { BLKDISCARD };
+
+    if unsafe { libc::ioctl(fd, req as _, range.as_ptr()) } < 0 {
+        -errno()
+    } else {
+        0
+    }
+}
+
+/// Return fallocate() mode for handling DISCARD or WRITE_ZEROES of regular
+/// file
+///
+/// # Arguments:
+///
+/// * `op_flags`: `op_flags` of io descriptor
+/// * `zero_range`: handle WRITE_ZEROES by FALLOC_FL_ZERO_RANGE always
+///
+/// Same with kernel loop, WRITE_ZEROES punches hole unless REQ_NOUNMAP is
+/// set or `zero_range` is true. Block device is handled by `blk_discard()`.
+pub(crate) fn discard_fallocate_mode(op_flags: u32, zero_range: bool) -> i32 {
+    let zero = (op_flags & 0xff) == sys::UBLK_IO_OP_WRITE_ZEROES;
+    let nounmap = zero_range || (op_flags & sys::UBLK_IO_F_NOUNMAP) != 0;
+    let mode = if zero && nounmap {
+        libc::FALLOC_FL_ZERO_RANGE
+    } else {
+        libc::FALLOC_FL_PUNCH_HOLE
+    };
+
+    mode | libc::FALLOC_FL_KEEP_SIZE
+}
+
+/// Bind unix socket listener on `path`
+///
+/// Stale socket left by previous run is removed first, and -EEXIST is
+/// returned if `path` exists and isn't one socket, so other file is never
+/// removed by wrong socket path.
+pub(crate) fn bind_unix_socket(path: &str) -> Result<std::os::unix::net::UnixListener, UblkError> {
+    match std::fs::symlink_metadata(path) {
+        Ok(m) if m.file_type().is_socket() => {
+            std::fs::remove_file(path).map_err(UblkError::OtherIOError)?;
+        }
+        Ok(_) => return Err(UblkError::OtherError(-libc::EEXIST)),
+        Err(_) => {}
+    }
+    std::os::unix::net::UnixListener::bind(path).map_err(UblkError::OtherIOError)
+}
+
+/// Push `sqe` to the queue ring, and submit queued sqes for making room
+/// if SQ is full
+///
+/// Used by targets which may issue more target IOs than queue depth.
+/// Return negative errno if no room can be made, such as submit failure,
+/// and the caller fails the IO with it.
+pub(crate) fn queue_sqe(q: &UblkQueue, sqe: &squeue::Entry) -> Result<(), i32> {
+    let mut r = q.q_ring.borrow_mut();
+
+    loop {
+        if unsafe { r.submission().push(sqe) }.is_ok() {
+            return Ok(());
+        }
+        match r.submit() {
+            Ok(0) => return Err(-libc::EBUSY),
+            Ok(_) => {}
+            Err(e) => return Err(-e.raw_os_error().unwrap_or(libc::EIO)),
+        }
+    }
+}
+
+/// Submit `sqes` from io task `tag` concurrently, and wait for all results
+///
+/// The i-th sqe is submitted with `i + 1` as op_id, and results are
+/// returned in the same order with `sqes`. If one sqe can't be queued, it
+/// and the following sqes get the error as result, and queued ones are
+/// still waited for.
+pub(crate) async fn uring_ops(q: &UblkQueue<'_>, tag: u16, sqes: &[squeue::Entry]) -> Vec<i32> {
+    let mut err = 0;
+    let mut nr = 0;
+
+    for (i, sqe) in sqes.iter().enumerate() {
+        let user_data = UblkIOCtx::build_user_data_async(tag, 0, i as u32 + 1);
+
+        if let Err(e) = queue_sqe(q, &sqe.clone().user_data(user_data)) {
+            err = e;
+            break;
+        }
+        nr += 1;
+    }
+    let mut res = UringOpFutureBatch::new(UblkIOCtx::build_user_data_async(tag, 0, 0), nr).await;
+    res.resize(sqes.len(), err);
+    res
+}
+
+/// Submit `sqe` from io task `tag`, and wait for its result
+///
+/// Uring ops issued via this helper by the same io task have to be
+/// serialized, since they share the same user_data. Negative errno is
+/// returned if the sqe can't be queued.
+pub(crate) async fn uring_op(q: &UblkQueue<'_>, tag: u16, sqe: squeue::Entry) -> i32 {
+    let user_data = UblkIOCtx::build_user_data_async(tag, 0, 0);
+
+    if let Err(e) = queue_sqe(q, &sqe.user_data(user_data)) {
+        return e;
+    }
+    UringOpFuture { user_data }.await
+}
+
+/// Run target future `f` for IO `tag` in the IO's span
+#[cfg(feature = "tracing")]
+async fn handle_io_traced<Fut: Future<Output = i32>>(q: &UblkQueue<'_>, tag: u16, f: Fut) -> i32 {
+    use tracing::Instrument;
+
+    q.trace_tgt_submit(tag);
+    f.instrument(q.io_span(tag)).await
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+async fn handle_io_traced<Fut: Future<Output = i32>>(_q: &UblkQueue<'_>, _tag: u16, f: Fut) -> i32 {
+    f.await
+}
+
+/// Run one queue by async/.await
+///
+/// # Arguments:
+///
+/// * `qid`: queue id
+/// * `dev`: ublk device, which has to be created with `UBLK_DEV_F_ASYNC`
+/// * `handle_io`: return future for handling the incoming IO command `tag`,
+///   and output of the future is the IO command result
+///
+/// One io task is spawned for each tag, and won't return until the queue
+/// is down.
+pub fn run_async_queue<'a, F, Fut>(
+    qid: u16,
+    dev: &'a UblkDev,
+    handle_io: F,
+) -> Result<(), UblkError>
+where
+    F: Fn(Rc<UblkQueue<'a>>, u16) -> Fut + 'a,
+    Fut: Future<Output = i32> + 'a,
+{
+    run_async_queue_with(qid, dev, handle_io, |_, _| {})
+}
+
+/// Same with `run_async_queue()`, and `spawn_extra` is called for spawning
+/// target's own tasks in the extra io slots, whose tag starts from queue
+/// depth, see `UblkTgt::extra_ios`
+pub(crate) fn run_async_queue_with<'a, F, Fut, E>(
+    qid: u16,
+    dev: &'a UblkDev,
+    handle_io: F,
+    spawn_extra: E,
+) -> Result<(), UblkError>
+where
+    F: Fn(Rc<UblkQueue<'a>>, u16) -> Fut + 'a,
+    Fut: Future<Output = i32> + 'a,
+    E: FnOnce(&Executor<'a>, &Rc<UblkQueue<'a>>),
+{
+    if (dev.flags & UBLK_DEV_F_ASYNC) == 0 {
+        return Err(UblkError::OtherError(-libc::EINVAL));
+    }
+
+    let q_rc = Rc::new(UblkQueue::new(qid, dev)?);
+    let exe = Executor::new(dev.get_nr_ios());
+    let handle_io = Rc::new(handle_io);
+
+    for tag in 0..dev.dev_info.queue_depth {
+        let q = q_rc.clone();
+        let h = handle_io.clone();
+
+        exe.spawn(tag, async move {
+            let buf_addr = q.get_io_buf_addr(tag);
+            let mut cmd_op = sys::UBLK_IO_FETCH_REQ;
+            let mut res = 0;
+            loop {
+                let cmd_res = q.submit_io_cmd(tag, cmd_op, buf_addr, res).await;
+                if cmd_res == sys::UBLK_IO_RES_ABORT {
+                    break;
+                }
+
+                res = handle_io_traced(&q, tag, h(q.clone(), tag)).await;
+                cmd_op = sys::UBLK_IO_COMMIT_AND_FETCH_REQ;
+            }
+        });
+    }
+    spawn_extra(&exe, &q_rc);
+    q_rc.wait_and_wake_io_tasks(&exe)
+}
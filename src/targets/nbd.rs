@@ -0,0 +1,890 @@
+//! NBD target: ublk device backed by remote NBD export
+//!
+//! One connection is made for each queue in `init_tgt()`, and the fixed
+//! newstyle handshake is done synchronously. Requests & replies are
+//! transferred by io_uring send/recv over the queue's io_uring, so the
+//! device has to be created with `UBLK_DEV_F_ASYNC`.
+//!
+//! In each queue, one extra io task receives replies and hands them to the
+//! io task of the request by posting NOP with the request's user_data, and
+//! sending is serialized among io tasks the same way.
+
+use crate::dev_flags::UBLK_DEV_F_ASYNC;
+use crate::exe::UringOpFuture;
+use crate::io::{UblkDev, UblkIOCtx, UblkQueue};
+use crate::{sys, UblkError};
+use io_uring::{opcode, squeue, types};
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+const NBD_IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const NBD_OPT_REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+const NBD_STRUCTURED_REPLY_MAGIC: u32 = 0x668e_33ef;
+
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+const NBD_OPT_GO: u32 = 7;
+const NBD_OPT_STRUCTURED_REPLY: u32 = 8;
+
+const NBD_REP_ACK: u32 = 1;
+const NBD_REP_INFO: u32 = 3;
+const NBD_REP_FLAG_ERROR: u32 = 1 << 31;
+const NBD_REP_ERR_UNSUP: u32 = NBD_REP_FLAG_ERROR | 1;
+const NBD_REP_ERR_UNKNOWN: u32 = NBD_REP_FLAG_ERROR | 6;
+const NBD_INFO_EXPORT: u16 = 0;
+
+/// transmission flags, see `NbdTgt::export_info()`
+pub const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+pub const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+pub const NBD_FLAG_SEND_FUA: u16 = 1 << 3;
+pub const NBD_FLAG_ROTATIONAL: u16 = 1 << 4;
+pub const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+pub const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+pub const NBD_FLAG_CAN_MULTI_CONN: u16 = 1 << 8;
+
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+const NBD_CMD_TRIM: u16 = 4;
+const NBD_CMD_WRITE_ZEROES: u16 = 6;
+const NBD_CMD_FLAG_FUA: u16 = 1 << 0;
+const NBD_CMD_FLAG_NO_HOLE: u16 = 1 << 1;
+
+const NBD_REPLY_FLAG_DONE: u16 = 1 << 0;
+const NBD_REPLY_TYPE_NONE: u16 = 0;
+const NBD_REPLY_TYPE_OFFSET_DATA: u16 = 1;
+const NBD_REPLY_TYPE_OFFSET_HOLE: u16 = 2;
+const NBD_REPLY_TYPE_ERROR_BIT: u16 = 1 << 15;
+
+/// user_data kinds of NOP for waking io task
+const NBD_WAKE_REPLY: u32 = 1;
+const NBD_WAKE_SEND: u32 = 2;
+
+#[derive(Debug, Serialize)]
+struct NbdJson {
+    addr: String,
+    export_name: String,
+    size: u64,
+    flags: u16,
+    structured_reply: bool,
+}
+
+#[inline]
+fn be16(buf: &[u8], off: usize) -> u16 {
+    u16::from_be_bytes(buf[off..off + 2].try_into().unwrap())
+}
+
+#[inline]
+fn be32(buf: &[u8], off: usize) -> u32 {
+    u32::from_be_bytes(buf[off..off + 4].try_into().unwrap())
+}
+
+#[inline]
+fn be64(buf: &[u8], off: usize) -> u64 {
+    u64::from_be_bytes(buf[off..off + 8].try_into().unwrap())
+}
+
+#[inline]
+fn proto_err() -> UblkError {
+    UblkError::OtherError(-libc::EPROTO)
+}
+
+fn nbd_request(cmd: u16, flags: u16, handle: u64, off: u64, len: u32) -> [u8; 28] {
+    let mut req = [0_u8; 28];
+
+    req[0..4].copy_from_slice(&NBD_REQUEST_MAGIC.to_be_bytes());
+    req[4..6].copy_from_slice(&flags.to_be_bytes());
+    req[6..8].copy_from_slice(&cmd.to_be_bytes());
+    req[8..16].copy_from_slice(&handle.to_be_bytes());
+    req[16..24].copy_from_slice(&off.to_be_bytes());
+    req[24..28].copy_from_slice(&len.to_be_bytes());
+    req
+}
+
+/// Export negotiated in handshake
+#[derive(Debug, Default, Clone, Copy)]
+struct NbdExport {
+    size: u64,
+    flags: u16,
+    structured: bool,
+}
+
+fn send_opt<S: Write>(s: &mut S, opt: u32, data: &[u8]) -> Result<(), UblkError> {
+    let mut buf = Vec::with_capacity(16 + data.len());
+
+    buf.extend_from_slice(&NBD_IHAVEOPT.to_be_bytes());
+    buf.extend_from_slice(&opt.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+    s.write_all(&buf).map_err(UblkError::OtherIOError)
+}
+
+/// Return (reply type, reply data) of option `opt`
+fn read_opt_reply<S: Read>(s: &mut S, opt: u32) -> Result<(u32, Vec<u8>), UblkError> {
+    let mut hdr = [0_u8; 20];
+
+    s.read_exact(&mut hdr).map_err(UblkError::OtherIOError)?;
+    let len = be32(&hdr, 16) as usize;
+    if be64(&hdr, 0) != NBD_OPT_REPLY_MAGIC || be32(&hdr, 8) != opt || len > 65536 {
+        return Err(proto_err());
+    }
+
+    let mut data = vec![0_u8; len];
+    s.read_exact(&mut data).map_err(UblkError::OtherIOError)?;
+    Ok((be32(&hdr, 12), data))
+}
+
+/// Fixed newstyle handshake, NBD_OPT_GO is tried first, and fallback to
+/// NBD_OPT_EXPORT_NAME if the server doesn't support it
+fn handshake<S: Read + Write>(
+    s: &mut S,
+    name: &str,
+    structured: bool,
+) -> Result<NbdExport, UblkError> {
+    let mut buf = [0_u8; 18];
+
+    s.read_exact(&mut buf).map_err(UblkError::OtherIOError)?;
+    let hs_flags = be16(&buf, 16);
+    if be64(&buf, 0) != NBD_MAGIC
+        || be64(&buf, 8) != NBD_IHAVEOPT
+        || (hs_flags & NBD_FLAG_FIXED_NEWSTYLE) == 0
+    {
+        return Err(proto_err());
+    }
+    let no_zeroes = (hs_flags & NBD_FLAG_NO_ZEROES) != 0;
+    let cflags = (hs_flags & (NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES)) as u32;
+    s.write_all(&cflags.to_be_bytes())
+        .map_err(UblkError::OtherIOError)?;
+
+    let mut export = NbdExport::default();
+    if structured {
+        send_opt(s, NBD_OPT_STRUCTURED_REPLY, &[])?;
+        let (rep, _) = read_opt_reply(s, NBD_OPT_STRUCTURED_REPLY)?;
+        export.structured = rep == NBD_REP_ACK;
+    }
+
+    let mut data = Vec::with_capacity(name.len() + 6);
+    data.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    data.extend_from_slice(name.as_bytes());
+    data.extend_from_slice(&0_u16.to_be_bytes());
+    send_opt(s, NBD_OPT_GO, &data)?;
+
+    let mut has_info = false;
+    loop {
+        let (rep, data) = read_opt_reply(s, NBD_OPT_GO)?;
+
+        match rep {
+            NBD_REP_ACK if has_info => return Ok(export),
+            NBD_REP_INFO if data.len() >= 12 && be16(&data, 0) == NBD_INFO_EXPORT => {
+                export.size = be64(&data, 2);
+                export.flags = be16(&data, 10);
+                has_info = true;
+            }
+            NBD_REP_INFO => {}
+            NBD_REP_ERR_UNSUP => break,
+            NBD_REP_ERR_UNKNOWN => return Err(UblkError::OtherError(-libc::ENOENT)),
+            _ => return Err(proto_err()),
+        }
+    }
+
+    // old server without NBD_OPT_GO
+    send_opt(s, NBD_OPT_EXPORT_NAME, name.as_bytes())?;
+    let mut buf = [0_u8; 10 + 124];
+    let len = if no_zeroes { 10 } else { buf.len() };
+    s.read_exact(&mut buf[..len])
+        .map_err(UblkError::OtherIOError)?;
+
+    Ok(NbdExport {
+        size: be64(&buf, 0),
+        flags: be16(&buf, 8),
+        structured: export.structured,
+    })
+}
+
+/// One connection, shared by the queue & target
+#[derive(Debug)]
+struct NbdConn {
+    fd: OwnedFd,
+    fd_idx: u32,
+}
+
+impl Drop for NbdConn {
+    fn drop(&mut self) {
+        let req = nbd_request(NBD_CMD_DISC, 0, 0, 0, 0);
+
+        unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                req.as_ptr() as *const libc::c_void,
+                req.len(),
+                libc::MSG_NOSIGNAL,
+            );
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct NbdReq {
+    off: u64,
+    len: u32,
+    read: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum NbdIoState {
+    Idle,
+    Sending(NbdReq),
+    /// waiting for reply
+    Sent(NbdReq),
+    Done(i32),
+}
+
+/// Per-queue state, only touched from the queue thread
+struct NbdQueue {
+    fd: i32,
+    fd_idx: u32,
+    depth: u16,
+    structured: bool,
+    dead: Cell<bool>,
+    send_busy: Cell<bool>,
+    send_waiters: RefCell<VecDeque<u16>>,
+    io: Vec<Cell<NbdIoState>>,
+
+    /// error collected from structured reply chunks
+    err: Vec<Cell<i32>>,
+}
+
+impl NbdQueue {
+    fn new(conn: &NbdConn, depth: u16, structured: bool) -> Self {
+        NbdQueue {
+            fd: conn.fd.as_raw_fd(),
+            fd_idx: conn.fd_idx,
+            depth,
+            structured,
+            dead: Cell::new(false),
+            send_busy: Cell::new(false),
+            send_waiters: RefCell::new(VecDeque::new()),
+            io: (0..depth).map(|_| Cell::new(NbdIoState::Idle)).collect(),
+            err: (0..depth).map(|_| Cell::new(0)).collect(),
+        }
+    }
+
+    /// Wake io task `tag` which is waiting for event `kind`
+    fn wake(q: &UblkQueue, tag: u16, kind: u32) {
+        let sqe = opcode::Nop::new()
+            .build()
+            .user_data(UblkIOCtx::build_user_data_async(tag, 0, kind));
+
+        if let Err(e) = super::queue_sqe(q, &sqe) {
+            log::error!(
+                "nbd: queue {} failed to wake tag {} {}",
+                q.get_qid(),
+                tag,
+                e
+            );
+        }
+    }
+
+    async fn wait(tag: u16, kind: u32) {
+        UringOpFuture {
+            user_data: UblkIOCtx::build_user_data_async(tag, 0, kind),
+        }
+        .await;
+    }
+
+    async fn send_lock(&self, tag: u16) {
+        if !self.send_busy.get() {
+            self.send_busy.set(true);
+            return;
+        }
+        self.send_waiters.borrow_mut().push_back(tag);
+        Self::wait(tag, NBD_WAKE_SEND).await;
+    }
+
+    /// Hand the send lock to the 1st waiter
+    fn send_unlock(&self, q: &UblkQueue) {
+        let next = self.send_waiters.borrow_mut().pop_front();
+
+        match next {
+            Some(t) => Self::wake(q, t, NBD_WAKE_SEND),
+            None => self.send_busy.set(false),
+        }
+    }
+
+    async fn send_all(&self, q: &UblkQueue<'_>, tag: u16, buf: *const u8, len: usize) -> i32 {
+        let mut done = 0;
+
+        while done < len {
+            let sqe = opcode::Send::new(
+                types::Fixed(self.fd_idx),
+                unsafe { buf.add(done) },
+                (len - done) as u32,
+            )
+            .flags(libc::MSG_NOSIGNAL)
+            .build()
+            .flags(squeue::Flags::FIXED_FILE);
+            let res = super::uring_op(q, tag, sqe).await;
+
+            if res <= 0 {
+                return if res < 0 { res } else { -libc::EPIPE };
+            }
+            done += res as usize;
+        }
+        0
+    }
+
+    async fn recv_all(&self, q: &UblkQueue<'_>, tag: u16, buf: *mut u8, len: usize) -> i32 {
+        let mut done = 0;
+
+        while done < len {
+            let sqe = opcode::Recv::new(
+                types::Fixed(self.fd_idx),
+                unsafe { buf.add(done) },
+                (len - done) as u32,
+            )
+            .build()
+            .flags(squeue::Flags::FIXED_FILE);
+            let res = super::uring_op(q, tag, sqe).await;
+
+            if res <= 0 {
+                return if res < 0 { res } else { -libc::ECONNRESET };
+            }
+            done += res as usize;
+        }
+        0
+    }
+
+    /// Connection is broken, fail all in-flight requests
+    fn kill(&self, q: &UblkQueue) {
+        if self.dead.replace(true) {
+            return;
+        }
+        unsafe { libc::shutdown(self.fd, libc::SHUT_RDWR) };
+
+        for tag in 0..self.depth {
+            self.complete(q, tag, -libc::EIO);
+        }
+    }
+
+    fn complete(&self, q: &UblkQueue, tag: u16, res: i32) {
+        let io = &self.io[tag as usize];
+
+        match io.get() {
+            NbdIoState::Sent(_) => {
+                io.set(NbdIoState::Done(res));
+                Self::wake(q, tag, NBD_WAKE_REPLY);
+            }
+            // the io task picks the result after sending is done
+            NbdIoState::Sending(_) => io.set(NbdIoState::Done(res)),
+            _ => {}
+        }
+    }
+
+    async fn handle_io(&self, q: &UblkQueue<'_>, tag: u16) -> i32 {
+        let iod = q.get_iod(tag);
+        let op = iod.op_flags & 0xff;
+        let off = iod.start_sector << 9;
+        let bytes = iod.nr_sectors << 9;
+        let fua = if (iod.op_flags & sys::UBLK_IO_F_FUA) != 0 {
+            NBD_CMD_FLAG_FUA
+        } else {
+            0
+        };
+        let (cmd, flags) = match op {
+            sys::UBLK_IO_OP_READ => (NBD_CMD_READ, 0),
+            sys::UBLK_IO_OP_WRITE => (NBD_CMD_WRITE, fua),
+            sys::UBLK_IO_OP_FLUSH => (NBD_CMD_FLUSH, 0),
+            sys::UBLK_IO_OP_DISCARD => (NBD_CMD_TRIM, fua),
+            sys::UBLK_IO_OP_WRITE_ZEROES => (
+                NBD_CMD_WRITE_ZEROES,
+                fua | if (iod.op_flags & sys::UBLK_IO_F_NOUNMAP) != 0 {
+                    NBD_CMD_FLAG_NO_HOLE
+                } else {
+                    0
+                },
+            ),
+            _ => return -libc::EINVAL,
+        };
+        let (off, len) = if cmd == NBD_CMD_FLUSH {
+            (0, 0)
+        } else {
+            (off, bytes)
+        };
+
+        if self.dead.get() {
+            return -libc::EIO;
+        }
+
+        let io = &self.io[tag as usize];
+        let req = NbdReq {
+            off,
+            len,
+            read: cmd == NBD_CMD_READ,
+        };
+        io.set(NbdIoState::Sending(req));
+        self.err[tag as usize].set(0);
+
+        let hdr = nbd_request(cmd, flags, tag as u64, off, len);
+        self.send_lock(tag).await;
+        let mut res = if self.dead.get() {
+            -libc::EIO
+        } else {
+            self.send_all(q, tag, hdr.as_ptr(), hdr.len()).await
+        };
+        if res == 0 && cmd == NBD_CMD_WRITE {
+            res = self
+                .send_all(q, tag, q.get_io_buf_addr(tag), len as usize)
+                .await;
+        }
+        self.send_unlock(q);
+
+        if res < 0 {
+            io.set(NbdIoState::Idle);
+            self.kill(q);
+            return res;
+        }
+
+        if let NbdIoState::Sending(r) = io.get() {
+            io.set(NbdIoState::Sent(r));
+            Self::wait(tag, NBD_WAKE_REPLY).await;
+        }
+
+        let res = match io.get() {
+            NbdIoState::Done(r) => r,
+            _ => -libc::EIO,
+        };
+        io.set(NbdIoState::Idle);
+
+        match res {
+            0 if cmd == NBD_CMD_READ || cmd == NBD_CMD_WRITE => bytes as i32,
+            _ => res,
+        }
+    }
+
+    /// Return (tag, request) of reply handle
+    fn get_req(&self, handle: u64) -> Result<(u16, NbdReq), i32> {
+        if handle < self.depth as u64 {
+            let tag = handle as u16;
+
+            match self.io[tag as usize].get() {
+                NbdIoState::Sending(r) | NbdIoState::Sent(r) => return Ok((tag, r)),
+                _ => {}
+            }
+        }
+        Err(-libc::EPROTO)
+    }
+
+    /// Receive one reply, or one chunk of structured reply
+    async fn recv_one(&self, q: &UblkQueue<'_>, rtag: u16, scratch: &mut [u8]) -> Result<(), i32> {
+        let mut hdr = [0_u8; 20];
+        let r = self.recv_all(q, rtag, hdr.as_mut_ptr(), 16).await;
+        if r < 0 {
+            return Err(r);
+        }
+
+        let magic = be32(&hdr, 0);
+        if magic == NBD_SIMPLE_REPLY_MAGIC {
+            let err = be32(&hdr, 4) as i32;
+            let (tag, req) = self.get_req(be64(&hdr, 8))?;
+
+            if err == 0 && req.read {
+                let r = self
+                    .recv_all(q, rtag, q.get_io_buf_addr(tag), req.len as usize)
+                    .await;
+                if r < 0 {
+                    return Err(r);
+                }
+            }
+            self.complete(q, tag, -err);
+            return Ok(());
+        }
+        if magic != NBD_STRUCTURED_REPLY_MAGIC || !self.structured {
+            return Err(-libc::EPROTO);
+        }
+
+        let r = self.recv_all(q, rtag, hdr[16..].as_mut_ptr(), 4).await;
+        if r < 0 {
+            return Err(r);
+        }
+        let flags = be16(&hdr, 4);
+        let ty = be16(&hdr, 6);
+        let len = be32(&hdr, 16) as usize;
+        let (tag, req) = self.get_req(be64(&hdr, 8))?;
+        let in_req = |off: u64, l: usize| {
+            req.read && off >= req.off && off + l as u64 <= req.off + req.len as u64
+        };
+
+        match ty {
+            NBD_REPLY_TYPE_NONE if len == 0 => {}
+            NBD_REPLY_TYPE_OFFSET_DATA if len >= 8 => {
+                let mut b = [0_u8; 8];
+                let r = self.recv_all(q, rtag, b.as_mut_ptr(), 8).await;
+                if r < 0 {
+                    return Err(r);
+                }
+                let off = u64::from_be_bytes(b);
+                if !in_req(off, len - 8) {
+                    return Err(-libc::EPROTO);
+                }
+                let buf = unsafe { q.get_io_buf_addr(tag).add((off - req.off) as usize) };
+                let r = self.recv_all(q, rtag, buf, len - 8).await;
+                if r < 0 {
+                    return Err(r);
+                }
+            }
+            NBD_REPLY_TYPE_OFFSET_HOLE if len == 12 => {
+                let r = self.recv_all(q, rtag, scratch.as_mut_ptr(), 12).await;
+                if r < 0 {
+                    return Err(r);
+                }
+                let off = be64(scratch, 0);
+                let size = be32(scratch, 8) as usize;
+                if !in_req(off, size) {
+                    return Err(-libc::EPROTO);
+                }
+                unsafe {
+                    let buf = q.get_io_buf_addr(tag).add((off - req.off) as usize);
+                    std::ptr::write_bytes(buf, 0, size);
+                }
+            }
+            // error chunk starts with error & message length, and unknown
+            // chunk is skipped
+            t if len <= scratch.len() && (t & NBD_REPLY_TYPE_ERROR_BIT != 0 || t > 2) => {
+                let r = self.recv_all(q, rtag, scratch.as_mut_ptr(), len).await;
+                if r < 0 {
+                    return Err(r);
+                }
+                if (t & NBD_REPLY_TYPE_ERROR_BIT) != 0 {
+                    let err = if len >= 4 { be32(scratch, 0) as i32 } else { 0 };
+
+                    self.err[tag as usize].set(if err > 0 { -err } else { -libc::EIO });
+                }
+            }
+            _ => return Err(-libc::EPROTO),
+        }
+
+        if (flags & NBD_REPLY_FLAG_DONE) != 0 {
+            self.complete(q, tag, self.err[tag as usize].get());
+        }
+        Ok(())
+    }
+
+    /// Receive replies until the connection is broken, run in the extra
+    /// io task
+    async fn recv_replies(&self, q: &UblkQueue<'_>) {
+        let mut scratch = vec![0_u8; 4096];
+
+        loop {
+            if let Err(e) = self.recv_one(q, self.depth, &mut scratch).await {
+                if !self.dead.get() {
+                    log::error!("nbd: queue {} connection broken {}", q.get_qid(), e);
+                }
+                self.kill(q);
+                break;
+            }
+        }
+    }
+}
+
+/// NBD target
+///
+/// Built by `NbdTgtBuilder`, and connections are made in `init_tgt()`.
+/// The target has to live in the whole device lifetime, and it is cheap to
+/// clone for moving into queue closure.
+#[derive(Default, Builder, Debug, Clone)]
+#[builder(setter(into))]
+pub struct NbdTgt {
+    /// server address, `host:port` for TCP, or path of unix socket which
+    /// starts with '/'
+    addr: String,
+
+    /// export name
+    #[builder(default)]
+    export_name: String,
+
+    /// negotiate structured replies
+    #[builder(default = "true")]
+    structured_reply: bool,
+
+    #[builder(setter(skip))]
+    export: NbdExport,
+
+    #[builder(setter(skip))]
+    conns: Vec<Arc<NbdConn>>,
+}
+
+impl NbdTgt {
+    /// Return `UBLK_DEV_F_*` flags required by this target
+    pub fn dev_flags(&self) -> u32 {
+        UBLK_DEV_F_ASYNC
+    }
+
+    /// Return size & transmission flags of the export
+    ///
+    /// Only meaningful after `init_tgt()` returns.
+    pub fn export_info(&self) -> (u64, u16) {
+        (self.export.size, self.export.flags)
+    }
+
+    fn connect(&self) -> Result<(OwnedFd, NbdExport), UblkError> {
+        let timeout = Some(Duration::from_secs(10));
+
+        if self.addr.starts_with('/') {
+            let mut s = std::os::unix::net::UnixStream::connect(&self.addr)
+                .map_err(UblkError::OtherIOError)?;
+
+            s.set_read_timeout(timeout)
+                .map_err(UblkError::OtherIOError)?;
+            let export = handshake(&mut s, &self.export_name, self.structured_reply)?;
+            s.set_read_timeout(None).map_err(UblkError::OtherIOError)?;
+            Ok((OwnedFd::from(s), export))
+        } else {
+            let mut s =
+                std::net::TcpStream::connect(&self.addr).map_err(UblkError::OtherIOError)?;
+
+            s.set_nodelay(true).map_err(UblkError::OtherIOError)?;
+            s.set_read_timeout(timeout)
+                .map_err(UblkError::OtherIOError)?;
+            let export = handshake(&mut s, &self.export_name, self.structured_reply)?;
+            s.set_read_timeout(None).map_err(UblkError::OtherIOError)?;
+            Ok((OwnedFd::from(s), export))
+        }
+    }
+
+    /// Setup NBD target, passed to `UblkSession::create_devices()`
+    ///
+    /// # Arguments:
+    ///
+    /// * `dev`: ublk device being created, which has to be created with
+    ///   `UBLK_DEV_F_ASYNC`
+    ///
+    /// Make one connection for each queue, register them as fixed files,
+    /// and setup device size, parameters and target json.
+    pub fn init_tgt(&mut self, dev: &mut UblkDev) -> Result<i32, UblkError> {
+        if (dev.flags & UBLK_DEV_F_ASYNC) == 0 {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        let nr_queues = dev.dev_info.nr_hw_queues as usize;
+        let tgt = &mut dev.tgt;
+        let nr_fds = tgt.nr_fds as usize;
+        if nr_fds + nr_queues > tgt.fds.len() {
+            return Err(UblkError::OtherError(-libc::EMFILE));
+        }
+
+        self.conns.clear();
+        for i in 0..nr_queues {
+            let (fd, export) = self.connect()?;
+
+            if i == 0 {
+                self.export = export;
+            } else if export.size != self.export.size || export.flags != self.export.flags {
+                return Err(proto_err());
+            }
+            tgt.fds[nr_fds + i] = fd.as_raw_fd();
+            self.conns.push(Arc::new(NbdConn {
+                fd,
+                fd_idx: (nr_fds + i) as u32,
+            }));
+        }
+        tgt.nr_fds = (nr_fds + nr_queues) as i32;
+
+        let flags = self.export.flags;
+        if nr_queues > 1 && (flags & NBD_FLAG_CAN_MULTI_CONN) == 0 {
+            log::warn!("nbd: server doesn't support multi-conn, flush only covers its own queue");
+        }
+
+        // reply receiver task
+        tgt.extra_ios = 1;
+        tgt.dev_size = self.export.size;
+        tgt.params = sys::ublk_params {
+            types: sys::UBLK_PARAM_TYPE_BASIC,
+            basic: sys::ublk_param_basic {
+                attrs: [
+                    (NBD_FLAG_READ_ONLY, sys::UBLK_ATTR_READ_ONLY),
+                    (NBD_FLAG_ROTATIONAL, sys::UBLK_ATTR_ROTATIONAL),
+                    (NBD_FLAG_SEND_FLUSH, sys::UBLK_ATTR_VOLATILE_CACHE),
+                    (NBD_FLAG_SEND_FUA, sys::UBLK_ATTR_FUA),
+                ]
+                .iter()
+                .filter(|(f, _)| (flags & f) != 0)
+                .fold(0, |attrs, (_, a)| attrs | a),
+                logical_bs_shift: 9,
+                physical_bs_shift: 12,
+                io_opt_shift: 12,
+                io_min_shift: 9,
+                max_sectors: dev.dev_info.max_io_buf_bytes >> 9,
+                dev_sectors: self.export.size >> 9,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        if (flags & (NBD_FLAG_SEND_TRIM | NBD_FLAG_SEND_WRITE_ZEROES)) != 0 {
+            let max = |f: u16| {
+                if (flags & f) != 0 {
+                    u32::MAX >> 9
+                } else {
+                    0
+                }
+            };
+
+            tgt.params.types |= sys::UBLK_PARAM_TYPE_DISCARD;
+            tgt.params.discard = sys::ublk_param_discard {
+                discard_granularity: 4096,
+                max_discard_sectors: max(NBD_FLAG_SEND_TRIM),
+                max_write_zeroes_sectors: max(NBD_FLAG_SEND_WRITE_ZEROES),
+                max_discard_segments: 1,
+                ..Default::default()
+            };
+        }
+
+        dev.set_max_dev_size(self.export.size);
+        let val = serde_json::json!({"nbd": NbdJson {
+            addr: self.addr.clone(),
+            export_name: self.export_name.clone(),
+            size: self.export.size,
+            flags,
+            structured_reply: self.export.structured,
+        }});
+        dev.set_target_json(val);
+
+        Ok(0)
+    }
+
+    /// Handle IO of queue `qid`, called from queue closure
+    ///
+    /// # Arguments:
+    ///
+    /// * `qid`: queue id
+    /// * `dev`: ublk device
+    ///
+    /// Won't return until the queue is down.
+    pub fn run_queue(&self, qid: u16, dev: &UblkDev) -> Result<(), UblkError> {
+        let conn = self
+            .conns
+            .get(qid as usize)
+            .ok_or(UblkError::OtherError(-libc::EINVAL))?;
+        let nq = Rc::new(NbdQueue::new(
+            conn,
+            dev.dev_info.queue_depth,
+            self.export.structured,
+        ));
+        let rq = nq.clone();
+
+        super::run_async_queue_with(
+            qid,
+            dev,
+            move |q, tag| {
+                let nq = nq.clone();
+                async move { nq.handle_io(&q, tag).await }
+            },
+            move |exe, q| {
+                let q = q.clone();
+
+                exe.spawn(rq.depth, async move { rq.recv_replies(&q).await });
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+
+    fn opt_reply(s: &mut UnixStream, opt: u32, rep: u32, data: &[u8]) {
+        let mut buf = Vec::new();
+
+        buf.extend_from_slice(&NBD_OPT_REPLY_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&opt.to_be_bytes());
+        buf.extend_from_slice(&rep.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(data);
+        s.write_all(&buf).unwrap();
+    }
+
+    /// Return (option, data) sent from client
+    fn read_opt(s: &mut UnixStream) -> (u32, Vec<u8>) {
+        let mut hdr = [0_u8; 16];
+
+        s.read_exact(&mut hdr).unwrap();
+        assert!(be64(&hdr, 0) == NBD_IHAVEOPT);
+        let mut data = vec![0_u8; be32(&hdr, 12) as usize];
+        s.read_exact(&mut data).unwrap();
+        (be32(&hdr, 8), data)
+    }
+
+    /// Fake server, which supports NBD_OPT_GO if `go` is true
+    fn serve_handshake(mut s: UnixStream, go: bool) {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&NBD_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&NBD_IHAVEOPT.to_be_bytes());
+        buf.extend_from_slice(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes());
+        s.write_all(&buf).unwrap();
+
+        let mut cflags = [0_u8; 4];
+        s.read_exact(&mut cflags).unwrap();
+        assert!(u32::from_be_bytes(cflags) == 3);
+
+        loop {
+            let (opt, data) = read_opt(&mut s);
+            match opt {
+                NBD_OPT_STRUCTURED_REPLY => opt_reply(&mut s, opt, NBD_REP_ACK, &[]),
+                NBD_OPT_GO if go => {
+                    assert!(be32(&data, 0) == 3 && &data[4..7] == b"foo");
+                    let mut info = Vec::new();
+                    info.extend_from_slice(&NBD_INFO_EXPORT.to_be_bytes());
+                    info.extend_from_slice(&(1_u64 << 30).to_be_bytes());
+                    info.extend_from_slice(&(1 | NBD_FLAG_SEND_FLUSH).to_be_bytes());
+                    opt_reply(&mut s, opt, NBD_REP_INFO, &info);
+                    opt_reply(&mut s, opt, NBD_REP_ACK, &[]);
+                    break;
+                }
+                NBD_OPT_GO => opt_reply(&mut s, opt, NBD_REP_ERR_UNSUP, &[]),
+                NBD_OPT_EXPORT_NAME => {
+                    assert!(data == b"foo");
+                    s.write_all(&(1_u64 << 20).to_be_bytes()).unwrap();
+                    s.write_all(&1_u16.to_be_bytes()).unwrap();
+                    break;
+                }
+                _ => panic!("unexpected option {}", opt),
+            }
+        }
+    }
+
+    #[test]
+    fn test_nbd_handshake() {
+        for go in [true, false] {
+            let (mut c, s) = UnixStream::pair().unwrap();
+            let h = std::thread::spawn(move || serve_handshake(s, go));
+            let export = handshake(&mut c, "foo", true).unwrap();
+
+            h.join().unwrap();
+            if go {
+                assert!(export.size == 1 << 30 && export.structured);
+                assert!(export.flags == 1 | NBD_FLAG_SEND_FLUSH);
+            } else {
+                assert!(export.size == 1 << 20 && export.flags == 1);
+            }
+        }
+
+        let req = nbd_request(NBD_CMD_WRITE, NBD_CMD_FLAG_FUA, 7, 4096, 512);
+        assert!(be32(&req, 0) == NBD_REQUEST_MAGIC && be16(&req, 4) == 1);
+        assert!(be16(&req, 6) == NBD_CMD_WRITE && be64(&req, 8) == 7);
+        assert!(be64(&req, 16) == 4096 && be32(&req, 24) == 512);
+
+        assert!(NbdTgtBuilder::default().build().is_err());
+    }
+}
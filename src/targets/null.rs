@@ -0,0 +1,224 @@
+//! Null target with latency and error injection
+//!
+//! IO is completed without touching data. Fixed or random per-IO latency
+//! can be added by io_uring timeout, and a fraction of IOs can be failed
+//! with a chosen errno, so behavior over slow or failing disk can be
+//! tested.
+
+use super::UblkRng;
+use crate::dev_flags::UBLK_DEV_F_ASYNC;
+use crate::exe::UringOpFuture;
+use crate::io::{UblkDev, UblkIOCtx, UblkQueue};
+use crate::{sys, UblkError, UblkIORes};
+use io_uring::{opcode, types};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+#[derive(Debug, Serialize)]
+struct NullJson {
+    size: u64,
+    lat_min_us: u64,
+    lat_max_us: u64,
+    error_rate: f64,
+    errno: i32,
+}
+
+/// Null target
+///
+/// Built by `NullTgtBuilder`, and it is cheap to clone for moving into
+/// queue closure.
+#[derive(Builder, Debug, Clone, Copy)]
+#[builder(setter(into))]
+pub struct NullTgt {
+    /// device size in bytes
+    #[builder(default = "250_u64 << 30")]
+    size: u64,
+
+    /// min latency added to each IO
+    #[builder(default = "Duration::ZERO")]
+    lat_min: Duration,
+
+    /// max latency added to each IO, the latency is picked randomly in
+    /// [lat_min, lat_max], and it is fixed if `lat_max` isn't bigger than
+    /// `lat_min`
+    #[builder(default = "Duration::ZERO")]
+    lat_max: Duration,
+
+    /// fraction of IOs failed, in range of [0.0, 1.0]
+    #[builder(default = "0.0")]
+    error_rate: f64,
+
+    /// positive errno for failing IO
+    #[builder(default = "libc::EIO")]
+    errno: i32,
+}
+
+impl NullTgt {
+    /// Return (result, latency) of io command `tag`
+    fn prep_io(&self, q: &UblkQueue, tag: u16, rng: &UblkRng) -> (i32, Duration) {
+        let iod = q.get_iod(tag);
+        let res = match iod.op_flags & 0xff {
+            sys::UBLK_IO_OP_READ | sys::UBLK_IO_OP_WRITE => (iod.nr_sectors << 9) as i32,
+            sys::UBLK_IO_OP_FLUSH | sys::UBLK_IO_OP_DISCARD | sys::UBLK_IO_OP_WRITE_ZEROES => 0,
+            _ => -libc::EINVAL,
+        };
+        let res = if res >= 0 && self.error_rate > 0.0 && rng.next_f64() < self.error_rate {
+            -self.errno
+        } else {
+            res
+        };
+        let lat = if self.lat_max > self.lat_min {
+            self.lat_min + (self.lat_max - self.lat_min).mul_f64(rng.next_f64())
+        } else {
+            self.lat_min
+        };
+
+        (res, lat)
+    }
+
+    /// Queue timeout for adding latency, the latency is skipped if the
+    /// timeout can't be queued
+    fn queue_timeout(q: &UblkQueue, ts: &types::Timespec, user_data: u64) -> bool {
+        let sqe = opcode::Timeout::new(ts as *const types::Timespec)
+            .flags(types::TimeoutFlags::ETIME_SUCCESS)
+            .build()
+            .user_data(user_data);
+
+        super::queue_sqe(q, &sqe).is_ok()
+    }
+
+    async fn handle_io_async(&self, q: &UblkQueue<'_>, tag: u16, rng: &UblkRng) -> i32 {
+        let (res, lat) = self.prep_io(q, tag, rng);
+
+        if !lat.is_zero() {
+            let op = q.get_iod(tag).op_flags & 0xff;
+            let user_data = UblkIOCtx::build_user_data_async(tag, op, 0);
+            let ts = types::Timespec::from(lat);
+
+            if Self::queue_timeout(q, &ts, user_data) {
+                UringOpFuture { user_data }.await;
+            }
+        }
+        res
+    }
+
+    /// Setup null target, passed to `UblkSession::create_devices()`
+    ///
+    /// # Arguments:
+    ///
+    /// * `dev`: ublk device being created
+    pub fn init_tgt(&self, dev: &mut UblkDev) -> Result<i32, UblkError> {
+        if !(0.0..=1.0).contains(&self.error_rate) || self.errno <= 0 {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        dev.set_default_params(self.size);
+        dev.tgt.params.types |= sys::UBLK_PARAM_TYPE_DISCARD;
+        dev.tgt.params.discard = sys::ublk_param_discard {
+            discard_granularity: 4096,
+            max_discard_sectors: u32::MAX >> 9,
+            max_write_zeroes_sectors: u32::MAX >> 9,
+            max_discard_segments: 1,
+            ..Default::default()
+        };
+
+        let val = serde_json::json!({"null": NullJson {
+            size: self.size,
+            lat_min_us: self.lat_min.as_micros() as u64,
+            lat_max_us: self.lat_max.as_micros() as u64,
+            error_rate: self.error_rate,
+            errno: self.errno,
+        }});
+        dev.set_target_json(val);
+
+        Ok(0)
+    }
+
+    /// Handle IO of queue `qid`, called from queue closure
+    ///
+    /// # Arguments:
+    ///
+    /// * `qid`: queue id
+    /// * `dev`: ublk device
+    ///
+    /// IO is handled by async/.await if the device is created with
+    /// `UBLK_DEV_F_ASYNC`. Won't return until the queue is down.
+    pub fn run_queue(&self, qid: u16, dev: &UblkDev) -> Result<(), UblkError> {
+        let null = *self;
+        let rng = Rc::new(UblkRng::new(qid));
+
+        if (dev.flags & UBLK_DEV_F_ASYNC) != 0 {
+            return super::run_async_queue(qid, dev, move |q, tag| {
+                let rng = rng.clone();
+                async move { null.handle_io_async(&q, tag, &rng).await }
+            });
+        }
+
+        // timespec & result of each tag, which has to live until the
+        // timeout is completed
+        let depth = dev.dev_info.queue_depth as usize;
+        let pending = RefCell::new(vec![(types::Timespec::new(), 0_i32); depth]);
+        let io_handler = move |q: &UblkQueue, tag: u16, io: &UblkIOCtx| {
+            if io.is_tgt_io() {
+                let res = pending.borrow()[tag as usize].1;
+
+                q.complete_io_cmd(tag, Ok(UblkIORes::Result(res)));
+                return;
+            }
+
+            let (res, lat) = null.prep_io(q, tag, &rng);
+            if lat.is_zero() {
+                q.complete_io_cmd(tag, Ok(UblkIORes::Result(res)));
+            } else {
+                let op = q.get_iod(tag).op_flags & 0xff;
+                let mut p = pending.borrow_mut();
+
+                p[tag as usize] = (types::Timespec::from(lat), res);
+                if !Self::queue_timeout(
+                    q,
+                    &p[tag as usize].0,
+                    UblkIOCtx::build_user_data(tag, op, 0, true),
+                ) {
+                    q.complete_io_cmd(tag, Ok(UblkIORes::Result(res)));
+                }
+            }
+        };
+
+        UblkQueue::new(qid, dev)?.wait_and_handle_io(io_handler)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NullTgtBuilder;
+    use crate::targets::UblkRng;
+    use std::time::Duration;
+
+    #[test]
+    fn test_null_tgt() {
+        let rng = UblkRng::new(0);
+        let nr = 10000;
+        let mut hit = 0;
+
+        for _ in 0..nr {
+            let v = rng.next_f64();
+
+            assert!((0.0..1.0).contains(&v));
+            if v < 0.25 {
+                hit += 1;
+            }
+        }
+        // roughly a quarter
+        assert!(hit > nr / 5 && hit < nr * 3 / 10);
+
+        let null = NullTgtBuilder::default()
+            .lat_min(Duration::from_micros(100))
+            .error_rate(0.5)
+            .build()
+            .unwrap();
+        assert!(null.size == 250_u64 << 30 && null.errno == libc::EIO);
+        assert!(null.lat_max.is_zero());
+    }
+}
@@ -0,0 +1,1336 @@
+//! Qcow2 target: expose qcow2 image as ublk device
+//!
+//! L1 table is loaded in `init_tgt()`, and L2 tables are loaded on demand
+//! and cached. Clusters are allocated at the end of image on the first
+//! write. Refcount is written and synced before the new cluster is linked
+//! into L2 table, and new L2 table is synced before it is linked into L1
+//! table, so crash may leak clusters, but never corrupt the image.
+//! Unallocated clusters are read from the backing chain, or as zeros if
+//! there isn't backing file.
+//!
+//! Both data and metadata IO is issued via the queue's io_uring, so the
+//! device has to be created with `UBLK_DEV_F_ASYNC`.
+//!
+//! Compressed clusters, encryption, external data file, extended L2 entry
+//! and refcount width smaller than 8 bits aren't supported, and image with
+//! internal snapshots can only be exported as read-only.
+
+use crate::dev_flags::UBLK_DEV_F_ASYNC;
+use crate::io::{UblkDev, UblkQueue};
+use crate::{sys, UblkError};
+use io_uring::{opcode, squeue, types};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const QCOW2_MAGIC: u32 = 0x5146_49fb;
+const QCOW2_EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+
+const QCOW2_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const QCOW2_RT_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+const QCOW2_OFLAG_COPIED: u64 = 1 << 63;
+const QCOW2_OFLAG_COMPRESSED: u64 = 1 << 62;
+const QCOW2_OFLAG_ZERO: u64 = 1;
+
+/// max images in backing chain, including the top image
+const QCOW2_MAX_CHAIN: usize = 16;
+
+#[derive(Debug, Serialize)]
+struct Qcow2Json {
+    image_path: String,
+    backing_files: Vec<String>,
+    size: u64,
+    cluster_size: u64,
+    read_only: bool,
+}
+
+#[inline]
+fn be32(buf: &[u8], off: usize) -> u32 {
+    u32::from_be_bytes(buf[off..off + 4].try_into().unwrap())
+}
+
+#[inline]
+fn be64(buf: &[u8], off: usize) -> u64 {
+    u64::from_be_bytes(buf[off..off + 8].try_into().unwrap())
+}
+
+fn be64_table(buf: &[u8]) -> Box<[u64]> {
+    buf.chunks_exact(8).map(|c| be64(c, 0)).collect()
+}
+
+#[derive(Debug, Default, Clone)]
+struct Qcow2Header {
+    version: u32,
+    backing_file_offset: u64,
+    backing_file_size: u32,
+    cluster_bits: u32,
+    size: u64,
+    crypt_method: u32,
+    l1_size: u32,
+    l1_table_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u32,
+    nb_snapshots: u32,
+    incompatible_features: u64,
+    refcount_order: u32,
+    header_length: u32,
+}
+
+impl Qcow2Header {
+    const V2_LEN: usize = 72;
+    const V3_LEN: usize = 104;
+
+    fn parse(buf: &[u8]) -> Result<Self, UblkError> {
+        if buf.len() < Self::V2_LEN || be32(buf, 0) != QCOW2_MAGIC {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        let mut h = Qcow2Header {
+            version: be32(buf, 4),
+            backing_file_offset: be64(buf, 8),
+            backing_file_size: be32(buf, 16),
+            cluster_bits: be32(buf, 20),
+            size: be64(buf, 24),
+            crypt_method: be32(buf, 32),
+            l1_size: be32(buf, 36),
+            l1_table_offset: be64(buf, 40),
+            refcount_table_offset: be64(buf, 48),
+            refcount_table_clusters: be32(buf, 56),
+            nb_snapshots: be32(buf, 60),
+            incompatible_features: 0,
+            refcount_order: 4,
+            header_length: Self::V2_LEN as u32,
+        };
+
+        match h.version {
+            2 => {}
+            3 if buf.len() >= Self::V3_LEN => {
+                h.incompatible_features = be64(buf, 72);
+                h.refcount_order = be32(buf, 96);
+                h.header_length = be32(buf, 100);
+            }
+            _ => return Err(UblkError::OtherError(-libc::EINVAL)),
+        }
+        Ok(h)
+    }
+
+    /// Serialize as version 3 header
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::V3_LEN);
+
+        buf.extend_from_slice(&QCOW2_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&3_u32.to_be_bytes());
+        buf.extend_from_slice(&self.backing_file_offset.to_be_bytes());
+        buf.extend_from_slice(&self.backing_file_size.to_be_bytes());
+        buf.extend_from_slice(&self.cluster_bits.to_be_bytes());
+        buf.extend_from_slice(&self.size.to_be_bytes());
+        buf.extend_from_slice(&0_u32.to_be_bytes());
+        buf.extend_from_slice(&self.l1_size.to_be_bytes());
+        buf.extend_from_slice(&self.l1_table_offset.to_be_bytes());
+        buf.extend_from_slice(&self.refcount_table_offset.to_be_bytes());
+        buf.extend_from_slice(&self.refcount_table_clusters.to_be_bytes());
+        buf.extend_from_slice(&0_u32.to_be_bytes());
+        buf.extend_from_slice(&0_u64.to_be_bytes());
+        // incompatible, compatible & autoclear features
+        buf.extend_from_slice(&[0_u8; 24]);
+        buf.extend_from_slice(&self.refcount_order.to_be_bytes());
+        buf.extend_from_slice(&(Self::V3_LEN as u32).to_be_bytes());
+        buf
+    }
+}
+
+/// Image file, `fd_idx` is its index in registered fixed files
+#[derive(Debug)]
+struct Qcow2File {
+    /// kept open for `fd_idx`, and only read directly by unit test
+    #[cfg_attr(not(test), allow(dead_code))]
+    file: std::fs::File,
+    fd_idx: u32,
+}
+
+/// How image IO is issued
+///
+/// Blocking pread/pwrite is only used by unit test, and IO is issued via
+/// queue's io_uring from io task `tag` otherwise.
+#[derive(Clone, Copy)]
+enum Qcow2Io<'a, 'b> {
+    #[cfg(test)]
+    Sync,
+    Uring(&'a UblkQueue<'b>, u16),
+}
+
+impl Qcow2Io<'_, '_> {
+    async fn rw(&self, f: &Qcow2File, write: bool, buf: *mut u8, len: usize, off: u64) -> i32 {
+        match *self {
+            #[cfg(test)]
+            Qcow2Io::Sync => {
+                let fd = f.file.as_raw_fd();
+                let res = unsafe {
+                    if write {
+                        libc::pwrite(fd, buf as *const libc::c_void, len, off as i64)
+                    } else {
+                        libc::pread(fd, buf as *mut libc::c_void, len, off as i64)
+                    }
+                };
+                if res < 0 {
+                    -super::errno()
+                } else {
+                    res as i32
+                }
+            }
+            Qcow2Io::Uring(q, tag) => {
+                let fd = types::Fixed(f.fd_idx);
+                let sqe = if write {
+                    opcode::Write::new(fd, buf, len as u32).offset(off).build()
+                } else {
+                    opcode::Read::new(fd, buf, len as u32).offset(off).build()
+                };
+
+                super::uring_op(q, tag, sqe.flags(squeue::Flags::FIXED_FILE)).await
+            }
+        }
+    }
+
+    /// Read `buf` from offset `off`, and the part beyond EOF is zeroed
+    async fn read_at(&self, f: &Qcow2File, buf: &mut [u8], off: u64) -> Result<(), i32> {
+        let mut done = 0;
+
+        while done < buf.len() {
+            let b = &mut buf[done..];
+            let res = self
+                .rw(f, false, b.as_mut_ptr(), b.len(), off + done as u64)
+                .await;
+
+            if res < 0 {
+                return Err(res);
+            }
+            if res == 0 {
+                b.fill(0);
+                break;
+            }
+            done += res as usize;
+        }
+        Ok(())
+    }
+
+    async fn write_at(&self, f: &Qcow2File, buf: &[u8], off: u64) -> Result<(), i32> {
+        let mut done = 0;
+
+        while done < buf.len() {
+            let b = &buf[done..];
+            let res = self
+                .rw(f, true, b.as_ptr() as *mut u8, b.len(), off + done as u64)
+                .await;
+
+            if res <= 0 {
+                return Err(if res < 0 { res } else { -libc::EIO });
+            }
+            done += res as usize;
+        }
+        Ok(())
+    }
+
+    async fn fsync(&self, f: &Qcow2File) -> Result<(), i32> {
+        let res = match *self {
+            #[cfg(test)]
+            Qcow2Io::Sync => f
+                .file
+                .sync_data()
+                .map_or_else(|e| -e.raw_os_error().unwrap_or(libc::EIO), |_| 0),
+            Qcow2Io::Uring(q, tag) => {
+                let sqe = opcode::Fsync::new(types::Fixed(f.fd_idx))
+                    .flags(types::FsyncFlags::DATASYNC)
+                    .build()
+                    .flags(squeue::Flags::FIXED_FILE);
+
+                super::uring_op(q, tag, sqe).await
+            }
+        };
+
+        if res < 0 {
+            Err(res)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Back off for a while when the cluster is being allocated by others
+    async fn backoff(&self) {
+        let delay = Duration::from_micros(20);
+
+        match *self {
+            #[cfg(test)]
+            Qcow2Io::Sync => std::thread::sleep(delay),
+            Qcow2Io::Uring(q, tag) => {
+                let ts = types::Timespec::from(delay);
+                let sqe = opcode::Timeout::new(&ts as *const types::Timespec)
+                    .flags(types::TimeoutFlags::ETIME_SUCCESS)
+                    .build();
+
+                super::uring_op(q, tag, sqe).await;
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Qcow2Backing {
+    Raw {
+        path: PathBuf,
+        file: Qcow2File,
+        size: u64,
+    },
+    Qcow2(Box<Qcow2Image>),
+}
+
+/// Mutable metadata, shared by all queues
+///
+/// The lock is never held across .await, and in-memory metadata is only
+/// updated after the on-disk update is done, except for allocation state.
+#[derive(Debug, Default)]
+struct Qcow2Meta {
+    l1: Vec<u64>,
+    l2_cache: HashMap<u64, Box<[u64]>>,
+
+    /// bumped when L1 or L2 entry is changed, for detecting stale lookup
+    l2_gen: u64,
+
+    refcount_table: Vec<u64>,
+
+    /// next free cluster offset, new cluster is always allocated at the
+    /// end of image
+    next_free: u64,
+
+    /// virtual clusters being allocated
+    busy_clusters: HashSet<u64>,
+
+    /// L1 entries whose L2 table is being allocated
+    busy_l2: HashSet<usize>,
+
+    /// new refcount block is being written, allocation has to wait
+    rb_inflight: bool,
+
+    /// metadata update failed, no more allocation
+    broken: bool,
+}
+
+/// Planned cluster allocation, made under the meta lock
+#[derive(Debug, Default)]
+struct Qcow2AllocPlan {
+    host: u64,
+    l1_idx: usize,
+    l2_idx: usize,
+    l2_off: u64,
+    new_l2: bool,
+
+    /// refcount updates in order: (image offset, data)
+    writes: Vec<(u64, Vec<u8>)>,
+
+    /// refcount table entries set by this allocation
+    new_rbs: Vec<usize>,
+}
+
+enum Qcow2Alloc {
+    Plan(Qcow2AllocPlan),
+    /// the cluster or metadata is being allocated by others
+    Busy,
+    /// L2 entry is changed after lookup
+    Stale,
+}
+
+#[derive(Debug)]
+struct Qcow2Image {
+    path: PathBuf,
+    file: Qcow2File,
+    read_only: bool,
+    cluster_bits: u32,
+    refcount_order: u32,
+    size: u64,
+    l1_table_offset: u64,
+    refcount_table_offset: u64,
+    l2_cache_max: usize,
+
+    /// min cluster bits of the whole backing chain
+    min_cluster_bits: u32,
+    backing: Option<Qcow2Backing>,
+    meta: Mutex<Qcow2Meta>,
+}
+
+impl Qcow2Image {
+    /// Open image and its backing chain
+    ///
+    /// Raw fd of each opened file is appended to `fds`, and `fd_base` is
+    /// the fixed file index of `fds[0]`.
+    fn open(
+        path: &Path,
+        read_only: bool,
+        l2_cache_size: usize,
+        fd_base: u32,
+        fds: &mut Vec<i32>,
+    ) -> Result<Self, UblkError> {
+        if fds.len() >= QCOW2_MAX_CHAIN {
+            return Err(UblkError::OtherError(-libc::ELOOP));
+        }
+
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(path)
+            .map_err(UblkError::OtherIOError)?;
+        let mut hbuf = [0_u8; Qcow2Header::V3_LEN];
+        file.read_exact_at(&mut hbuf, 0)
+            .map_err(UblkError::OtherIOError)?;
+        let h = Qcow2Header::parse(&hbuf)?;
+
+        if !(9..=21).contains(&h.cluster_bits)
+            || (h.size & 511) != 0
+            || (h.header_length as usize) < Qcow2Header::V2_LEN
+        {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+        if h.crypt_method != 0
+            || h.incompatible_features != 0
+            || !(3..=6).contains(&h.refcount_order)
+            || (!read_only && h.nb_snapshots != 0)
+        {
+            return Err(UblkError::OtherError(-libc::EOPNOTSUPP));
+        }
+
+        let cluster_size = 1_u64 << h.cluster_bits;
+        let l2_bits = h.cluster_bits - 3;
+        let nr_clusters = (h.size + cluster_size - 1) >> h.cluster_bits;
+        if (h.l1_size as u64) << l2_bits < nr_clusters {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        let read_table = |off: u64, len: usize| -> Result<Box<[u64]>, UblkError> {
+            let mut buf = vec![0_u8; len];
+
+            file.read_exact_at(&mut buf, off)
+                .map_err(UblkError::OtherIOError)?;
+            Ok(be64_table(&buf))
+        };
+        let l1 = read_table(h.l1_table_offset, h.l1_size as usize * 8)?;
+        let refcount_table = read_table(
+            h.refcount_table_offset,
+            (h.refcount_table_clusters as usize) << h.cluster_bits,
+        )?;
+
+        // header extensions & backing file name are in the 1st cluster
+        let mut c0 = vec![0_u8; cluster_size as usize];
+        file.read_exact_at(&mut c0, 0)
+            .map_err(UblkError::OtherIOError)?;
+
+        let fd_idx = fd_base + fds.len() as u32;
+        fds.push(file.as_raw_fd());
+
+        let backing = if h.backing_file_offset != 0 {
+            let start = h.backing_file_offset as usize;
+            let end = start + h.backing_file_size as usize;
+            if end > c0.len() {
+                return Err(UblkError::OtherError(-libc::EINVAL));
+            }
+            let name = String::from_utf8_lossy(&c0[start..end]).to_string();
+            let fmt = Self::backing_format(&c0[h.header_length as usize..start]);
+
+            Some(Self::open_backing(
+                path,
+                &name,
+                fmt.as_deref(),
+                l2_cache_size,
+                fd_base,
+                fds,
+            )?)
+        } else {
+            None
+        };
+
+        let min_cluster_bits = match &backing {
+            Some(Qcow2Backing::Qcow2(b)) => std::cmp::min(h.cluster_bits, b.min_cluster_bits),
+            _ => h.cluster_bits,
+        };
+        let file_len = file.metadata().map_err(UblkError::OtherIOError)?.len();
+        let meta = Qcow2Meta {
+            l1: l1.into_vec(),
+            refcount_table: refcount_table.into_vec(),
+            next_free: (file_len + cluster_size - 1) & !(cluster_size - 1),
+            ..Default::default()
+        };
+
+        Ok(Qcow2Image {
+            path: path.to_path_buf(),
+            file: Qcow2File { file, fd_idx },
+            read_only,
+            cluster_bits: h.cluster_bits,
+            refcount_order: h.refcount_order,
+            size: h.size,
+            l1_table_offset: h.l1_table_offset,
+            refcount_table_offset: h.refcount_table_offset,
+            l2_cache_max: std::cmp::max(l2_cache_size >> h.cluster_bits, 16),
+            min_cluster_bits,
+            backing,
+            meta: Mutex::new(meta),
+        })
+    }
+
+    /// Return backing format stored in header extensions
+    fn backing_format(exts: &[u8]) -> Option<String> {
+        let mut pos = 0;
+
+        while pos + 8 <= exts.len() {
+            let ext_type = be32(exts, pos);
+            let len = be32(exts, pos + 4) as usize;
+
+            if ext_type == 0 || pos + 8 + len > exts.len() {
+                break;
+            }
+            if ext_type == QCOW2_EXT_BACKING_FORMAT {
+                return Some(String::from_utf8_lossy(&exts[pos + 8..pos + 8 + len]).to_string());
+            }
+            pos += 8 + ((len + 7) & !7);
+        }
+        None
+    }
+
+    /// Open backing file read-only, relative path is against the directory
+    /// of `path`, and format is probed if it isn't recorded in image
+    fn open_backing(
+        path: &Path,
+        name: &str,
+        fmt: Option<&str>,
+        l2_cache_size: usize,
+        fd_base: u32,
+        fds: &mut Vec<i32>,
+    ) -> Result<Qcow2Backing, UblkError> {
+        let bpath = match path.parent() {
+            Some(dir) if Path::new(name).is_relative() => dir.join(name),
+            _ => PathBuf::from(name),
+        };
+        let is_qcow2 = match fmt {
+            Some("qcow2") => true,
+            Some("raw") => false,
+            Some(_) => return Err(UblkError::OtherError(-libc::EOPNOTSUPP)),
+            None => {
+                let f = std::fs::File::open(&bpath).map_err(UblkError::OtherIOError)?;
+                let mut magic = [0_u8; 4];
+
+                f.read_exact_at(&mut magic, 0).is_ok() && u32::from_be_bytes(magic) == QCOW2_MAGIC
+            }
+        };
+
+        if is_qcow2 {
+            let img = Self::open(&bpath, true, l2_cache_size, fd_base, fds)?;
+
+            return Ok(Qcow2Backing::Qcow2(Box::new(img)));
+        }
+
+        if fds.len() >= QCOW2_MAX_CHAIN {
+            return Err(UblkError::OtherError(-libc::ELOOP));
+        }
+        let file = std::fs::File::open(&bpath).map_err(UblkError::OtherIOError)?;
+        let (size, _, _) = super::backing_file_size(&file)?;
+        let fd_idx = fd_base + fds.len() as u32;
+
+        fds.push(file.as_raw_fd());
+        Ok(Qcow2Backing::Raw {
+            path: bpath,
+            file: Qcow2File { file, fd_idx },
+            size,
+        })
+    }
+
+    /// Return paths of backing chain, not including this image
+    fn backing_files(&self) -> Vec<String> {
+        let mut files = Vec::new();
+        let mut img = self;
+
+        loop {
+            match &img.backing {
+                Some(Qcow2Backing::Qcow2(b)) => {
+                    files.push(b.path.display().to_string());
+                    img = b;
+                }
+                Some(Qcow2Backing::Raw { path, .. }) => {
+                    files.push(path.display().to_string());
+                    break;
+                }
+                None => break,
+            }
+        }
+        files
+    }
+
+    #[inline]
+    fn cluster_size(&self) -> u64 {
+        1_u64 << self.cluster_bits
+    }
+
+    /// Return (L1 index, L2 index) of virtual cluster
+    #[inline]
+    fn l2_index(&self, cluster: u64) -> (usize, usize) {
+        let l2_bits = self.cluster_bits - 3;
+
+        (
+            (cluster >> l2_bits) as usize,
+            (cluster & ((1 << l2_bits) - 1)) as usize,
+        )
+    }
+
+    fn l2_cache_insert(&self, meta: &mut Qcow2Meta, l2_off: u64, table: Box<[u64]>) {
+        if meta.l2_cache.len() >= self.l2_cache_max {
+            // cached tables are always clean, so any one can be dropped
+            if let Some(k) = meta.l2_cache.keys().next().copied() {
+                meta.l2_cache.remove(&k);
+            }
+        }
+        meta.l2_cache.insert(l2_off, table);
+    }
+
+    /// Return (L2 entry, generation) of virtual cluster, and entry is 0 if
+    /// the L2 table isn't allocated
+    async fn get_l2_entry(&self, io: &Qcow2Io<'_, '_>, cluster: u64) -> Result<(u64, u64), i32> {
+        let (l1_idx, l2_idx) = self.l2_index(cluster);
+
+        loop {
+            let (l2_off, gen) = {
+                let meta = self.meta.lock().unwrap();
+                let l2_off = meta.l1[l1_idx] & QCOW2_OFFSET_MASK;
+
+                if l2_off == 0 {
+                    return Ok((0, meta.l2_gen));
+                }
+                if let Some(t) = meta.l2_cache.get(&l2_off) {
+                    return Ok((t[l2_idx], meta.l2_gen));
+                }
+                (l2_off, meta.l2_gen)
+            };
+
+            let mut buf = vec![0_u8; self.cluster_size() as usize];
+            io.read_at(&self.file, &mut buf, l2_off).await?;
+
+            // the table is stale if any entry is updated during loading
+            let mut meta = self.meta.lock().unwrap();
+            if meta.l2_gen == gen {
+                let table = be64_table(&buf);
+                let entry = table[l2_idx];
+
+                self.l2_cache_insert(&mut meta, l2_off, table);
+                return Ok((entry, gen));
+            }
+        }
+    }
+
+    /// Read range [off, off + buf.len()) of this image
+    ///
+    /// Each piece is aligned with the min cluster size of the backing
+    /// chain, so it is covered by single cluster in every image.
+    async fn read(&self, io: &Qcow2Io<'_, '_>, off: u64, buf: &mut [u8]) -> Result<(), i32> {
+        let step = 1_u64 << self.min_cluster_bits;
+        let mut pos = 0;
+
+        while pos < buf.len() {
+            let start = off + pos as u64;
+            let mut len = std::cmp::min(step - (start & (step - 1)), (buf.len() - pos) as u64);
+            let mut img = self;
+
+            loop {
+                if start >= img.size {
+                    buf[pos..pos + len as usize].fill(0);
+                    break;
+                }
+                if start + len > img.size {
+                    // split at the end of smaller backing image, and
+                    // walk the chain again
+                    len = img.size - start;
+                    img = self;
+                    continue;
+                }
+
+                let dst = &mut buf[pos..pos + len as usize];
+                let (entry, _) = img.get_l2_entry(io, start >> img.cluster_bits).await?;
+                let host = entry & QCOW2_OFFSET_MASK;
+
+                if (entry & QCOW2_OFLAG_COMPRESSED) != 0 {
+                    return Err(-libc::EOPNOTSUPP);
+                } else if (entry & QCOW2_OFLAG_ZERO) != 0 {
+                    dst.fill(0);
+                } else if host != 0 {
+                    let c_off = start & (img.cluster_size() - 1);
+
+                    io.read_at(&img.file, dst, host + c_off).await?;
+                } else {
+                    match &img.backing {
+                        Some(Qcow2Backing::Qcow2(b)) => {
+                            img = b;
+                            continue;
+                        }
+                        Some(Qcow2Backing::Raw { file, size, .. }) => {
+                            Self::read_raw(io, file, *size, start, dst).await?
+                        }
+                        None => dst.fill(0),
+                    }
+                }
+                break;
+            }
+            pos += len as usize;
+        }
+        Ok(())
+    }
+
+    async fn read_raw(
+        io: &Qcow2Io<'_, '_>,
+        file: &Qcow2File,
+        size: u64,
+        off: u64,
+        buf: &mut [u8],
+    ) -> Result<(), i32> {
+        let len = if off >= size {
+            0
+        } else {
+            std::cmp::min(size - off, buf.len() as u64) as usize
+        };
+
+        buf[len..].fill(0);
+        if len > 0 {
+            io.read_at(file, &mut buf[..len], off).await?;
+        }
+        Ok(())
+    }
+
+    /// Read range of backing chain for filling new cluster
+    async fn read_backing(
+        &self,
+        io: &Qcow2Io<'_, '_>,
+        off: u64,
+        buf: &mut [u8],
+    ) -> Result<(), i32> {
+        match &self.backing {
+            Some(Qcow2Backing::Qcow2(b)) => b.read(io, off, buf).await,
+            Some(Qcow2Backing::Raw { file, size, .. }) => {
+                Self::read_raw(io, file, *size, off, buf).await
+            }
+            None => {
+                buf.fill(0);
+                Ok(())
+            }
+        }
+    }
+
+    /// Write range [off, off + buf.len()) of this image
+    async fn write(&self, io: &Qcow2Io<'_, '_>, off: u64, buf: &[u8]) -> Result<(), i32> {
+        let cs = self.cluster_size();
+        let mut pos = 0;
+
+        while pos < buf.len() {
+            let start = off + pos as u64;
+            let len = std::cmp::min(cs - (start & (cs - 1)), (buf.len() - pos) as u64) as usize;
+
+            self.write_cluster(io, start, &buf[pos..pos + len]).await?;
+            pos += len;
+        }
+        Ok(())
+    }
+
+    async fn write_cluster(&self, io: &Qcow2Io<'_, '_>, off: u64, data: &[u8]) -> Result<(), i32> {
+        let cluster = off >> self.cluster_bits;
+        let c_off = off & (self.cluster_size() - 1);
+
+        loop {
+            let (entry, gen) = self.get_l2_entry(io, cluster).await?;
+            let host = entry & QCOW2_OFFSET_MASK;
+
+            if (entry & QCOW2_OFLAG_COMPRESSED) != 0 {
+                return Err(-libc::EOPNOTSUPP);
+            }
+            if host != 0 && (entry & QCOW2_OFLAG_ZERO) == 0 {
+                // shared cluster needs COW, which is only for snapshot
+                if (entry & QCOW2_OFLAG_COPIED) == 0 {
+                    return Err(-libc::EOPNOTSUPP);
+                }
+                return io.write_at(&self.file, data, host + c_off).await;
+            }
+
+            let plan = match self.alloc_cluster(cluster, entry, gen)? {
+                Qcow2Alloc::Plan(plan) => plan,
+                Qcow2Alloc::Busy => {
+                    io.backoff().await;
+                    continue;
+                }
+                Qcow2Alloc::Stale => continue,
+            };
+            let res = self.do_alloc(io, off, data, entry, &plan).await;
+
+            self.finish_alloc(cluster, &plan, res);
+            return res.map_err(|(e, _)| e);
+        }
+    }
+
+    /// Allocate one host cluster at the end of image, and plan its refcount
+    /// update
+    fn alloc_host(&self, meta: &mut Qcow2Meta, plan: &mut Qcow2AllocPlan) -> Result<u64, i32> {
+        let host = meta.next_free;
+
+        meta.next_free += self.cluster_size();
+        self.set_refcount(meta, host, plan)?;
+        Ok(host)
+    }
+
+    /// Plan setting refcount of host cluster `host` as 1, and new refcount
+    /// block is allocated if it isn't there
+    fn set_refcount(
+        &self,
+        meta: &mut Qcow2Meta,
+        host: u64,
+        plan: &mut Qcow2AllocPlan,
+    ) -> Result<(), i32> {
+        let cs = self.cluster_size();
+        let width = 1_usize << (self.refcount_order - 3);
+        let rb_shift = self.cluster_bits + 3 - self.refcount_order;
+        let rt_idx = ((host >> self.cluster_bits) >> rb_shift) as usize;
+        let rb_entry = |h: u64| ((h >> self.cluster_bits) & ((1 << rb_shift) - 1)) as usize * width;
+        let mut one = vec![0_u8; width];
+
+        one[width - 1] = 1;
+        if rt_idx >= meta.refcount_table.len() {
+            return Err(-libc::ENOSPC);
+        }
+
+        let rb = meta.refcount_table[rt_idx] & QCOW2_RT_OFFSET_MASK;
+        if rb != 0 {
+            plan.writes.push((rb + rb_entry(host) as u64, one));
+            return Ok(());
+        }
+
+        // new refcount block covers itself if possible
+        let rb = meta.next_free;
+        let mut blk = vec![0_u8; cs as usize];
+        let covered = (((rb >> self.cluster_bits) >> rb_shift) as usize) == rt_idx;
+
+        meta.next_free += cs;
+        blk[rb_entry(host)..rb_entry(host) + width].copy_from_slice(&one);
+        if covered {
+            blk[rb_entry(rb)..rb_entry(rb) + width].copy_from_slice(&one);
+        }
+        plan.writes.push((rb, blk));
+        if !covered {
+            self.set_refcount(meta, rb, plan)?;
+        }
+        plan.writes.push((
+            self.refcount_table_offset + rt_idx as u64 * 8,
+            rb.to_be_bytes().to_vec(),
+        ));
+        meta.refcount_table[rt_idx] = rb;
+        plan.new_rbs.push(rt_idx);
+
+        Ok(())
+    }
+
+    /// Plan allocation of virtual cluster whose L2 entry is `entry` at
+    /// generation `gen`
+    fn alloc_cluster(&self, cluster: u64, entry: u64, gen: u64) -> Result<Qcow2Alloc, i32> {
+        let mut meta = self.meta.lock().unwrap();
+        let (l1_idx, l2_idx) = self.l2_index(cluster);
+
+        if meta.broken {
+            return Err(-libc::EIO);
+        }
+        if meta.l2_gen != gen {
+            return Ok(Qcow2Alloc::Stale);
+        }
+        if meta.rb_inflight
+            || meta.busy_clusters.contains(&cluster)
+            || meta.busy_l2.contains(&l1_idx)
+        {
+            return Ok(Qcow2Alloc::Busy);
+        }
+
+        let l2_off = meta.l1[l1_idx] & QCOW2_OFFSET_MASK;
+        let mut plan = Qcow2AllocPlan {
+            l1_idx,
+            l2_idx,
+            l2_off,
+            new_l2: l2_off == 0,
+            ..Default::default()
+        };
+        let reuse = entry & QCOW2_OFFSET_MASK;
+        let res = (|| {
+            // preallocated zero cluster is reused
+            plan.host = if reuse != 0 && (entry & QCOW2_OFLAG_COPIED) != 0 {
+                reuse
+            } else {
+                self.alloc_host(&mut meta, &mut plan)?
+            };
+            if plan.new_l2 {
+                plan.l2_off = self.alloc_host(&mut meta, &mut plan)?;
+            }
+            Ok(())
+        })();
+
+        if let Err(e) = res {
+            for idx in plan.new_rbs.iter() {
+                meta.refcount_table[*idx] = 0;
+            }
+            return Err(e);
+        }
+
+        meta.busy_clusters.insert(cluster);
+        if plan.new_l2 {
+            meta.busy_l2.insert(l1_idx);
+        }
+        if !plan.new_rbs.is_empty() {
+            meta.rb_inflight = true;
+        }
+        Ok(Qcow2Alloc::Plan(plan))
+    }
+
+    /// Write data cluster, refcount, then link it into L2 table
+    ///
+    /// Error is returned together with whether on-disk metadata has been
+    /// touched.
+    async fn do_alloc(
+        &self,
+        io: &Qcow2Io<'_, '_>,
+        off: u64,
+        data: &[u8],
+        entry: u64,
+        plan: &Qcow2AllocPlan,
+    ) -> Result<(), (i32, bool)> {
+        let cs = self.cluster_size() as usize;
+
+        if data.len() == cs {
+            io.write_at(&self.file, data, plan.host)
+                .await
+                .map_err(|e| (e, false))?;
+        } else {
+            let c_off = (off as usize) & (cs - 1);
+            let mut buf = vec![0_u8; cs];
+
+            // zero cluster is never read from backing
+            if (entry & QCOW2_OFLAG_ZERO) == 0 {
+                self.read_backing(io, off - c_off as u64, &mut buf)
+                    .await
+                    .map_err(|e| (e, false))?;
+            }
+            buf[c_off..c_off + data.len()].copy_from_slice(data);
+            io.write_at(&self.file, &buf, plan.host)
+                .await
+                .map_err(|e| (e, false))?;
+        }
+
+        for (o, d) in plan.writes.iter() {
+            io.write_at(&self.file, d, *o)
+                .await
+                .map_err(|e| (e, true))?;
+        }
+
+        // refcount has to be stable before the cluster is linked
+        if !plan.writes.is_empty() {
+            io.fsync(&self.file).await.map_err(|e| (e, true))?;
+        }
+
+        let l2e = (plan.host | QCOW2_OFLAG_COPIED).to_be_bytes();
+        if plan.new_l2 {
+            let mut table = vec![0_u8; cs];
+            let l1e = (plan.l2_off | QCOW2_OFLAG_COPIED).to_be_bytes();
+
+            table[plan.l2_idx * 8..plan.l2_idx * 8 + 8].copy_from_slice(&l2e);
+            io.write_at(&self.file, &table, plan.l2_off)
+                .await
+                .map_err(|e| (e, true))?;
+            io.fsync(&self.file).await.map_err(|e| (e, true))?;
+            io.write_at(
+                &self.file,
+                &l1e,
+                self.l1_table_offset + plan.l1_idx as u64 * 8,
+            )
+            .await
+            .map_err(|e| (e, true))?;
+        } else {
+            io.write_at(&self.file, &l2e, plan.l2_off + plan.l2_idx as u64 * 8)
+                .await
+                .map_err(|e| (e, true))?;
+        }
+        Ok(())
+    }
+
+    fn finish_alloc(&self, cluster: u64, plan: &Qcow2AllocPlan, res: Result<(), (i32, bool)>) {
+        let mut meta = self.meta.lock().unwrap();
+
+        meta.busy_clusters.remove(&cluster);
+        if plan.new_l2 {
+            meta.busy_l2.remove(&plan.l1_idx);
+        }
+        if !plan.new_rbs.is_empty() {
+            meta.rb_inflight = false;
+        }
+
+        match res {
+            Ok(_) => {
+                let entry = plan.host | QCOW2_OFLAG_COPIED;
+
+                if plan.new_l2 {
+                    let mut table = vec![0_u64; (self.cluster_size() >> 3) as usize];
+
+                    table[plan.l2_idx] = entry;
+                    meta.l1[plan.l1_idx] = plan.l2_off | QCOW2_OFLAG_COPIED;
+                    self.l2_cache_insert(&mut meta, plan.l2_off, table.into_boxed_slice());
+                } else if let Some(t) = meta.l2_cache.get_mut(&plan.l2_off) {
+                    t[plan.l2_idx] = entry;
+                }
+                meta.l2_gen += 1;
+            }
+            // nothing is written to metadata, and the host cluster is
+            // just left unused
+            Err((_, false)) => {
+                for idx in plan.new_rbs.iter() {
+                    meta.refcount_table[*idx] = 0;
+                }
+            }
+            Err((e, true)) => {
+                log::error!(
+                    "qcow2: {} metadata update failed {}",
+                    self.path.display(),
+                    e
+                );
+                meta.broken = true;
+            }
+        }
+    }
+}
+
+/// Create one empty qcow2(version 3) image
+///
+/// # Arguments:
+///
+/// * `path`: image path
+/// * `size`: virtual size in bytes
+/// * `cluster_bits`: cluster size shift, in range of [12, 21]
+/// * `backing_file`: backing file path stored in image, relative path is
+///   against directory of `path`
+pub fn create_image(
+    path: &str,
+    size: u64,
+    cluster_bits: u32,
+    backing_file: Option<&str>,
+) -> Result<(), UblkError> {
+    let cs = 1_u64 << cluster_bits;
+    let l2_bits = cluster_bits - 3;
+
+    if !(12..=21).contains(&cluster_bits) || (size & 511) != 0 {
+        return Err(UblkError::OtherError(-libc::EINVAL));
+    }
+
+    // header, refcount table, refcount block, then L1 table
+    let l1_size = ((size + cs - 1) >> cluster_bits).div_ceil(1 << l2_bits);
+    let l1_clusters = (l1_size * 8).div_ceil(cs);
+    let nr_clusters = 3 + l1_clusters;
+    if nr_clusters > cs * 8 / 16 {
+        return Err(UblkError::OtherError(-libc::EINVAL));
+    }
+
+    let mut h = Qcow2Header {
+        cluster_bits,
+        size,
+        l1_size: l1_size as u32,
+        l1_table_offset: 3 * cs,
+        refcount_table_offset: cs,
+        refcount_table_clusters: 1,
+        refcount_order: 4,
+        ..Default::default()
+    };
+    let name = backing_file.unwrap_or("");
+    if !name.is_empty() {
+        // leave room for the header extension end marker
+        h.backing_file_offset = Qcow2Header::V3_LEN as u64 + 8;
+        h.backing_file_size = name.len() as u32;
+        if h.backing_file_offset + name.len() as u64 > cs {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+    }
+
+    let mut img = vec![0_u8; (nr_clusters * cs) as usize];
+    let hdr = h.to_bytes();
+    img[..hdr.len()].copy_from_slice(&hdr);
+    let bf_off = h.backing_file_offset as usize;
+    img[bf_off..bf_off + name.len()].copy_from_slice(name.as_bytes());
+
+    let rt = cs as usize;
+    img[rt..rt + 8].copy_from_slice(&(2 * cs).to_be_bytes());
+    let rb = 2 * cs as usize;
+    for i in 0..nr_clusters as usize {
+        img[rb + i * 2..rb + i * 2 + 2].copy_from_slice(&1_u16.to_be_bytes());
+    }
+
+    std::fs::write(path, img).map_err(UblkError::OtherIOError)
+}
+
+/// Qcow2 target
+///
+/// Built by `Qcow2TgtBuilder`, and the image is opened in `init_tgt()`.
+/// The image is shared by all queues, so it is cheap to clone for moving
+/// into queue closure.
+#[derive(Default, Builder, Debug, Clone)]
+#[builder(setter(into))]
+pub struct Qcow2Tgt {
+    /// path of qcow2 image
+    image_path: String,
+
+    /// expose read-only ublk device, and image is opened read-only
+    #[builder(default = "false")]
+    read_only: bool,
+
+    /// max bytes of cached L2 tables of each image in backing chain
+    #[builder(default = "32_usize << 20")]
+    l2_cache_size: usize,
+
+    #[builder(setter(skip))]
+    image: Option<Arc<Qcow2Image>>,
+}
+
+impl Qcow2Tgt {
+    /// Return `UBLK_DEV_F_*` flags required by this target
+    pub fn dev_flags(&self) -> u32 {
+        UBLK_DEV_F_ASYNC
+    }
+
+    async fn handle_io(img: &Qcow2Image, q: &UblkQueue<'_>, tag: u16) -> i32 {
+        let iod = q.get_iod(tag);
+        let op = iod.op_flags & 0xff;
+        let off = iod.start_sector << 9;
+        let bytes = (iod.nr_sectors << 9) as usize;
+        let buf_addr = q.get_io_buf_addr(tag);
+        let io = Qcow2Io::Uring(q, tag);
+
+        let res = match op {
+            sys::UBLK_IO_OP_FLUSH => io.fsync(&img.file).await.map(|_| 0),
+            sys::UBLK_IO_OP_READ => {
+                let buf = unsafe { std::slice::from_raw_parts_mut(buf_addr, bytes) };
+
+                img.read(&io, off, buf).await.map(|_| bytes as i32)
+            }
+            sys::UBLK_IO_OP_WRITE if img.read_only => Err(-libc::EROFS),
+            sys::UBLK_IO_OP_WRITE => {
+                let buf = unsafe { std::slice::from_raw_parts(buf_addr, bytes) };
+
+                img.write(&io, off, buf).await.map(|_| bytes as i32)
+            }
+            _ => Err(-libc::EINVAL),
+        };
+
+        match res {
+            Ok(r) => r,
+            Err(e) => e,
+        }
+    }
+
+    /// Setup qcow2 target, passed to `UblkSession::create_devices()`
+    ///
+    /// # Arguments:
+    ///
+    /// * `dev`: ublk device being created, which has to be created with
+    ///   `UBLK_DEV_F_ASYNC`
+    ///
+    /// Open the image and its backing chain, register them as fixed files,
+    /// and setup device size, parameters and target json.
+    pub fn init_tgt(&mut self, dev: &mut UblkDev) -> Result<i32, UblkError> {
+        if (dev.flags & UBLK_DEV_F_ASYNC) == 0 {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        let tgt = &mut dev.tgt;
+        let nr_fds = tgt.nr_fds as usize;
+        let mut fds = Vec::new();
+        let img = Qcow2Image::open(
+            Path::new(&self.image_path),
+            self.read_only,
+            self.l2_cache_size,
+            nr_fds as u32,
+            &mut fds,
+        )?;
+        if nr_fds + fds.len() > tgt.fds.len() {
+            return Err(UblkError::OtherError(-libc::EMFILE));
+        }
+        tgt.fds[nr_fds..nr_fds + fds.len()].copy_from_slice(&fds);
+        tgt.nr_fds = (nr_fds + fds.len()) as i32;
+
+        tgt.dev_size = img.size;
+        tgt.params = sys::ublk_params {
+            types: sys::UBLK_PARAM_TYPE_BASIC,
+            basic: sys::ublk_param_basic {
+                attrs: if self.read_only {
+                    sys::UBLK_ATTR_READ_ONLY
+                } else {
+                    0
+                } | sys::UBLK_ATTR_VOLATILE_CACHE,
+                logical_bs_shift: 9,
+                physical_bs_shift: 12,
+                io_opt_shift: img.cluster_bits as u8,
+                io_min_shift: 9,
+                max_sectors: dev.dev_info.max_io_buf_bytes >> 9,
+                dev_sectors: img.size >> 9,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        dev.set_max_dev_size(img.size);
+        let val = serde_json::json!({"qcow2": Qcow2Json {
+            image_path: self.image_path.clone(),
+            backing_files: img.backing_files(),
+            size: img.size,
+            cluster_size: img.cluster_size(),
+            read_only: self.read_only,
+        }});
+        dev.set_target_json(val);
+        self.image = Some(Arc::new(img));
+
+        Ok(0)
+    }
+
+    /// Handle IO of queue `qid`, called from queue closure
+    ///
+    /// # Arguments:
+    ///
+    /// * `qid`: queue id
+    /// * `dev`: ublk device
+    ///
+    /// Won't return until the queue is down.
+    pub fn run_queue(&self, qid: u16, dev: &UblkDev) -> Result<(), UblkError> {
+        let img = match &self.image {
+            Some(img) => img.clone(),
+            None => return Err(UblkError::OtherError(-libc::EINVAL)),
+        };
+
+        super::run_async_queue(qid, dev, move |q, tag| {
+            let img = img.clone();
+            async move { Self::handle_io(&img, &q, tag).await }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_image, Qcow2Image, Qcow2Io, QCOW2_OFFSET_MASK};
+    use futures::executor::block_on;
+    use std::os::unix::fs::FileExt;
+    use std::path::Path;
+
+    fn open(path: &Path) -> Qcow2Image {
+        Qcow2Image::open(path, false, 1 << 20, 0, &mut Vec::new()).unwrap()
+    }
+
+    /// every cluster of the image file has refcount 1, and no others
+    fn check_refcount(img: &Qcow2Image) {
+        let f = &img.file.file;
+        let cs = img.cluster_size();
+        let nr = f.metadata().unwrap().len().div_ceil(cs);
+        let meta = img.meta.lock().unwrap();
+        let per_rb = cs / 2;
+
+        for (i, e) in meta.refcount_table.iter().enumerate() {
+            let rb = e & QCOW2_OFFSET_MASK;
+            if rb == 0 {
+                assert!(i as u64 * per_rb >= nr);
+                continue;
+            }
+            let mut blk = vec![0_u8; cs as usize];
+            f.read_exact_at(&mut blk, rb).unwrap();
+            for (j, r) in blk.chunks_exact(2).enumerate() {
+                let exp = u16::from(i as u64 * per_rb + (j as u64) < nr);
+                assert!(u16::from_be_bytes([r[0], r[1]]) == exp);
+            }
+        }
+    }
+
+    #[test]
+    fn test_qcow2_rw() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("top.qcow2");
+        let size = 8_u64 << 20;
+        let io = Qcow2Io::Sync;
+
+        create_image(path.to_str().unwrap(), size, 16, None).unwrap();
+        let img = open(&path);
+        assert!(img.size == size && img.cluster_size() == 65536);
+
+        // unallocated cluster is read as zeros
+        let mut buf = vec![0xff_u8; 131072];
+        block_on(img.read(&io, 4096, &mut buf)).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+
+        // partial & cross-cluster write, then whole cluster write
+        let data: Vec<u8> = (0..8192_u32).map(|i| (i % 251) as u8).collect();
+        block_on(img.write(&io, 65536 - 4096, &data)).unwrap();
+        block_on(img.write(&io, 4 << 20, &[0x5a_u8; 65536])).unwrap();
+
+        for img in [img, open(&path)] {
+            let mut buf = vec![0xff_u8; 16384];
+            block_on(img.read(&io, 65536 - 8192, &mut buf)).unwrap();
+            assert!(buf[..4096].iter().all(|b| *b == 0));
+            assert!(buf[4096..12288] == data[..]);
+            assert!(buf[12288..].iter().all(|b| *b == 0));
+
+            let mut buf = vec![0_u8; 65536];
+            block_on(img.read(&io, 4 << 20, &mut buf)).unwrap();
+            assert!(buf.iter().all(|b| *b == 0x5a));
+            check_refcount(&img);
+        }
+    }
+
+    #[test]
+    fn test_qcow2_backing_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base.raw");
+        let mid = dir.path().join("mid.qcow2");
+        let top = dir.path().join("top.qcow2");
+        let io = Qcow2Io::Sync;
+
+        // raw base is smaller than the overlay
+        let pattern: Vec<u8> = (0..(1_u32 << 20)).map(|i| (i % 241) as u8).collect();
+        std::fs::write(&base, &pattern).unwrap();
+        create_image(mid.to_str().unwrap(), 2 << 20, 12, Some("base.raw")).unwrap();
+        create_image(top.to_str().unwrap(), 4 << 20, 16, Some("mid.qcow2")).unwrap();
+
+        // mid overrides part of base
+        let mid_img = open(&mid);
+        block_on(mid_img.write(&io, 8192, &[0x11_u8; 4096])).unwrap();
+        drop(mid_img);
+
+        let img = open(&top);
+        assert!(img.min_cluster_bits == 12);
+        assert!(img.backing_files().len() == 2);
+
+        let mut buf = vec![0xff_u8; 2 << 20];
+        block_on(img.read(&io, 0, &mut buf)).unwrap();
+        assert!(buf[..8192] == pattern[..8192]);
+        assert!(buf[8192..12288].iter().all(|b| *b == 0x11));
+        assert!(buf[12288..1 << 20] == pattern[12288..]);
+        assert!(buf[1 << 20..].iter().all(|b| *b == 0));
+
+        // COW fills the rest of new cluster from backing chain
+        block_on(img.write(&io, 10240, &[0x22_u8; 512])).unwrap();
+        let mut buf = vec![0_u8; 65536];
+        block_on(img.read(&io, 0, &mut buf)).unwrap();
+        assert!(buf[..8192] == pattern[..8192]);
+        assert!(buf[8192..10240].iter().all(|b| *b == 0x11));
+        assert!(buf[10240..10752].iter().all(|b| *b == 0x22));
+        assert!(buf[10752..12288].iter().all(|b| *b == 0x11));
+        assert!(buf[12288..] == pattern[12288..65536]);
+        check_refcount(&img);
+
+        // backing chain is never written
+        let mut buf = vec![0_u8; 4096];
+        std::fs::File::open(&base)
+            .unwrap()
+            .read_exact_at(&mut buf, 8192)
+            .unwrap();
+        assert!(buf == pattern[8192..12288]);
+
+        // bad magic
+        assert!(Qcow2Image::open(&base, true, 1 << 20, 0, &mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,911 @@
+//! RAID1 target: mirror one ublk device over multiple files or block devices
+//!
+//! Writes go to all working replicas, and reads are served by the
+//! least-loaded active replica. A replica which fails IO is marked as
+//! failed, and the device keeps serving in degraded mode, meantime regions
+//! written in degraded mode are recorded in the dirty-region bitmap. After
+//! the failed replica returns, dirty regions are copied to it by one
+//! background resync task running in queue 0.
+//!
+//! The bitmap and replica states are stored in `target_data` when the
+//! device is started, and checkpointed to the device's target state file
+//! whenever they are changed, so both can be passed back via
+//! `Raid1TgtBuilder::saved()` for restoring the mirror. Checkpoint is
+//! written by one saver thread of each queue, and IO which changes the
+//! state waits until the change is saved.
+
+use super::TgtMember;
+use crate::dev_flags::UBLK_DEV_F_ASYNC;
+use crate::io::{UblkDev, UblkQueue};
+use crate::{sys, UblkError};
+use io_uring::{opcode, squeue, types};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+/// State of one replica
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Raid1MemberState {
+    /// in sync, serves both read and write
+    Active,
+
+    /// failed, no IO is sent to it
+    Failed,
+
+    /// returned and being resynced, only serves write
+    Resync,
+}
+
+impl Raid1MemberState {
+    fn from_u8(v: u8) -> Self {
+        match v {
+            0 => Raid1MemberState::Active,
+            1 => Raid1MemberState::Failed,
+            _ => Raid1MemberState::Resync,
+        }
+    }
+}
+
+/// Persisted mirror metadata, the bitmap has one bit for each region
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Raid1Meta {
+    region_size: u32,
+    nr_regions: u64,
+    /// replica isn't in sync
+    failed: Vec<bool>,
+    dirty: Vec<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct Raid1Json {
+    members: Vec<String>,
+    region_size: u32,
+    direct_io: bool,
+    bitmap: Raid1Meta,
+}
+
+#[derive(Debug)]
+struct Raid1Member {
+    state: AtomicU8,
+    inflight: AtomicU32,
+}
+
+#[derive(Default, Debug)]
+struct Raid1Regions {
+    dirty: Vec<u64>,
+    /// in-flight writes of each region
+    writing: HashMap<u64, u32>,
+    resyncing: Option<u64>,
+}
+
+/// Mirror state shared by all queues
+#[derive(Debug)]
+struct Raid1State {
+    members: Vec<Raid1Member>,
+    region_shift: u32,
+    nr_regions: u64,
+    regions: Mutex<Raid1Regions>,
+
+    /// generation of state change, and the generation covered by the
+    /// last saved checkpoint
+    gen: AtomicU64,
+    saved_gen: AtomicU64,
+}
+
+impl Raid1State {
+    fn new(nr_members: usize, region_shift: u32, nr_regions: u64) -> Self {
+        Raid1State {
+            members: (0..nr_members)
+                .map(|_| Raid1Member {
+                    state: AtomicU8::new(Raid1MemberState::Active as u8),
+                    inflight: AtomicU32::new(0),
+                })
+                .collect(),
+            region_shift,
+            nr_regions,
+            regions: Mutex::new(Raid1Regions {
+                dirty: vec![0; nr_regions.div_ceil(64) as usize],
+                ..Default::default()
+            }),
+            gen: AtomicU64::new(0),
+            saved_gen: AtomicU64::new(0),
+        }
+    }
+
+    /// Save target state of `dev` for checkpoint requests from `rx`,
+    /// requests queued during one save are covered by the next one
+    ///
+    /// Run in the saver thread of one queue until `true` is received.
+    fn save_state(&self, dev: &UblkDev, rx: mpsc::Receiver<bool>) {
+        while let Ok(false) = rx.recv() {
+            let stop = rx.try_iter().fold(false, |stop, s| stop | s);
+            let gen = self.gen.load(Ordering::Acquire);
+
+            // skip it if the change has been saved by other queue
+            if self.saved_gen.load(Ordering::Acquire) < gen {
+                if let Err(e) = dev.checkpoint_state() {
+                    log::error!("raid1: failed to save bitmap {:?}", e);
+                }
+                self.saved_gen.fetch_max(gen, Ordering::AcqRel);
+            }
+            if stop {
+                break;
+            }
+        }
+    }
+
+    fn state(&self, m: usize) -> Raid1MemberState {
+        Raid1MemberState::from_u8(self.members[m].state.load(Ordering::Acquire))
+    }
+
+    /// Change state of member `m` from `old` to `new`, return if it is
+    /// changed
+    fn switch(&self, m: usize, old: Raid1MemberState, new: Raid1MemberState) -> bool {
+        self.members[m]
+            .state
+            .compare_exchange(old as u8, new as u8, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+
+    /// Mark member `m` as failed, return if its state is changed
+    fn set_failed(&self, m: usize) -> bool {
+        let old = self.members[m]
+            .state
+            .swap(Raid1MemberState::Failed as u8, Ordering::AcqRel);
+
+        if old != Raid1MemberState::Failed as u8 {
+            log::warn!("raid1: member {} failed, running in degraded mode", m);
+        }
+        old != Raid1MemberState::Failed as u8
+    }
+
+    fn any_failed(&self) -> bool {
+        (0..self.members.len()).any(|m| self.state(m) == Raid1MemberState::Failed)
+    }
+
+    fn regions(&self, off: u64, len: u64) -> std::ops::RangeInclusive<u64> {
+        (off >> self.region_shift)..=((off + len.max(1) - 1) >> self.region_shift)
+    }
+
+    /// Set dirty bits of `regions`, return if any bit is newly set
+    fn mark_dirty(r: &mut Raid1Regions, regions: std::ops::RangeInclusive<u64>) -> bool {
+        let mut changed = false;
+
+        for i in regions {
+            let (w, b) = ((i / 64) as usize, 1_u64 << (i % 64));
+
+            changed |= (r.dirty[w] & b) == 0;
+            r.dirty[w] |= b;
+        }
+        changed
+    }
+
+    /// Account write over [off, off + len), regions are marked as dirty
+    /// if any replica is failed
+    ///
+    /// Return None if the write has to wait for resync of the same region,
+    /// otherwise return if any dirty bit is newly set.
+    fn start_write(&self, off: u64, len: u64) -> Option<bool> {
+        let regions = self.regions(off, len);
+        let mut r = self.regions.lock().unwrap();
+
+        if let Some(i) = r.resyncing {
+            if regions.contains(&i) {
+                return None;
+            }
+        }
+        for i in regions.clone() {
+            *r.writing.entry(i).or_insert(0) += 1;
+        }
+        Some(self.any_failed() && Self::mark_dirty(&mut r, regions))
+    }
+
+    /// Finish write over [off, off + len), regions are marked as dirty if
+    /// any replica failed the write, and return if any dirty bit is newly
+    /// set
+    fn end_write(&self, off: u64, len: u64, failed: bool) -> bool {
+        let regions = self.regions(off, len);
+        let mut r = self.regions.lock().unwrap();
+
+        for i in regions.clone() {
+            if let Some(cnt) = r.writing.get_mut(&i) {
+                *cnt -= 1;
+                if *cnt == 0 {
+                    r.writing.remove(&i);
+                }
+            }
+        }
+        failed && Self::mark_dirty(&mut r, regions)
+    }
+
+    /// Start to resync `region` if there isn't in-flight write on it
+    fn lock_resync(&self, region: u64) -> bool {
+        let mut r = self.regions.lock().unwrap();
+
+        if r.writing.contains_key(&region) {
+            return false;
+        }
+        r.resyncing = Some(region);
+        true
+    }
+
+    /// Finish resync of current region, and clear its dirty bit if `clean`
+    fn unlock_resync(&self, clean: bool) {
+        let mut r = self.regions.lock().unwrap();
+
+        if let Some(i) = r.resyncing.take() {
+            if clean {
+                r.dirty[(i / 64) as usize] &= !(1_u64 << (i % 64));
+            }
+        }
+    }
+
+    fn dirty_regions(&self) -> Vec<u64> {
+        let r = self.regions.lock().unwrap();
+
+        (0..self.nr_regions)
+            .filter(|i| (r.dirty[(i / 64) as usize] & (1_u64 << (i % 64))) != 0)
+            .collect()
+    }
+
+    fn to_meta(&self) -> Raid1Meta {
+        Raid1Meta {
+            region_size: 1 << self.region_shift,
+            nr_regions: self.nr_regions,
+            failed: (0..self.members.len())
+                .map(|m| self.state(m) != Raid1MemberState::Active)
+                .collect(),
+            dirty: self.regions.lock().unwrap().dirty.clone(),
+        }
+    }
+
+    /// Restore bitmap and replica states from `meta`, and replica which
+    /// was failed and is available now is resynced
+    fn restore(&self, meta: &Raid1Meta, present: &[bool]) -> Result<(), UblkError> {
+        if meta.region_size != 1 << self.region_shift
+            || meta.nr_regions != self.nr_regions
+            || meta.failed.len() != self.members.len()
+            || meta.dirty.len() != self.regions.lock().unwrap().dirty.len()
+        {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        self.regions.lock().unwrap().dirty = meta.dirty.clone();
+        for (m, failed) in meta.failed.iter().enumerate() {
+            let st = match (*failed, present[m]) {
+                (_, false) => Raid1MemberState::Failed,
+                (true, true) => Raid1MemberState::Resync,
+                (false, true) => Raid1MemberState::Active,
+            };
+            self.members[m].state.store(st as u8, Ordering::Release);
+        }
+        Ok(())
+    }
+}
+
+/// Per-queue view of raid1 target
+#[derive(Debug)]
+struct Raid1QueueIo {
+    state: Arc<Raid1State>,
+    /// (fixed file index, raw fd, is block device) of each replica
+    members: Vec<(u32, i32, bool)>,
+    /// checkpoint request sent to saver thread of this queue
+    saver: mpsc::Sender<bool>,
+}
+
+impl Raid1QueueIo {
+    /// Build target IO for replica `m`, `Err(res)` is returned if it is
+    /// handled synchronously
+    fn member_io(
+        &self,
+        m: usize,
+        op: u32,
+        op_flags: u32,
+        off: u64,
+        len: u32,
+        buf: *mut u8,
+    ) -> Result<squeue::Entry, i32> {
+        let (fd_idx, fd, blkdev) = self.members[m];
+        let fd_idx = types::Fixed(fd_idx);
+
+        let sqe = match op {
+            sys::UBLK_IO_OP_FLUSH => opcode::Fsync::new(fd_idx)
+                .flags(types::FsyncFlags::DATASYNC)
+                .build(),
+            sys::UBLK_IO_OP_READ => opcode::Read::new(fd_idx, buf, len).offset(off).build(),
+            sys::UBLK_IO_OP_WRITE => opcode::Write::new(fd_idx, buf, len).offset(off).build(),
+            sys::UBLK_IO_OP_DISCARD | sys::UBLK_IO_OP_WRITE_ZEROES => {
+                let zero = op == sys::UBLK_IO_OP_WRITE_ZEROES;
+
+                if blkdev {
+                    return Err(super::blk_discard(fd, off, len as u64, zero));
+                }
+
+                opcode::Fallocate::new(fd_idx, len as u64)
+                    .offset(off)
+                    .mode(super::discard_fallocate_mode(op_flags, false))
+                    .build()
+            }
+            _ => return Err(-libc::EINVAL),
+        };
+        Ok(sqe.flags(squeue::Flags::FIXED_FILE))
+    }
+
+    /// Return the least-loaded active replica which isn't in `tried`
+    fn pick_reader(&self, tried: &[bool]) -> Option<usize> {
+        (0..self.members.len())
+            .filter(|m| !tried[*m] && self.state.state(*m) == Raid1MemberState::Active)
+            .min_by_key(|m| self.state.members[*m].inflight.load(Ordering::Relaxed))
+    }
+
+    /// Ask the saver thread to checkpoint state changed by this IO, and
+    /// wait until the change is saved
+    async fn checkpoint(&self, q: &UblkQueue<'_>, tag: u16) {
+        let gen = self.state.gen.fetch_add(1, Ordering::AcqRel) + 1;
+
+        if self.saver.send(false).is_err() {
+            return;
+        }
+        while self.state.saved_gen.load(Ordering::Acquire) < gen {
+            sleep(q, tag, Duration::from_micros(20)).await;
+        }
+    }
+
+    async fn fail_member(&self, q: &UblkQueue<'_>, tag: u16, m: usize, res: i32) {
+        log::error!(
+            "raid1: queue {} member {} io failed {}",
+            q.get_qid(),
+            m,
+            res
+        );
+        if self.state.set_failed(m) {
+            self.checkpoint(q, tag).await;
+        }
+    }
+
+    async fn read(&self, q: &UblkQueue<'_>, tag: u16, off: u64, len: u32, buf: *mut u8) -> i32 {
+        let mut tried = vec![false; self.members.len()];
+
+        while let Some(m) = self.pick_reader(&tried) {
+            let inflight = &self.state.members[m].inflight;
+            let sqe = self
+                .member_io(m, sys::UBLK_IO_OP_READ, 0, off, len, buf)
+                .unwrap();
+
+            inflight.fetch_add(1, Ordering::Relaxed);
+            let res = super::uring_op(q, tag, sqe).await;
+            inflight.fetch_sub(1, Ordering::Relaxed);
+
+            if res == len as i32 {
+                return res;
+            }
+            tried[m] = true;
+            self.fail_member(q, tag, m, res).await;
+        }
+        -libc::EIO
+    }
+
+    /// Send write, flush, discard or write zeroes to all working replicas,
+    /// and it is successful if any replica completes it
+    async fn write_all(&self, q: &UblkQueue<'_>, tag: u16, op: u32, op_flags: u32) -> i32 {
+        let iod = q.get_iod(tag);
+        let off = iod.start_sector << 9;
+        let len = iod.nr_sectors << 9;
+        let buf = q.get_io_buf_addr(tag);
+        let expected = if op == sys::UBLK_IO_OP_WRITE {
+            len as i32
+        } else {
+            0
+        };
+        let track = op != sys::UBLK_IO_OP_FLUSH;
+
+        if track {
+            let newly_dirty = loop {
+                match self.state.start_write(off, len as u64) {
+                    Some(d) => break d,
+                    None => sleep(q, tag, Duration::from_micros(20)).await,
+                }
+            };
+            if newly_dirty {
+                self.checkpoint(q, tag).await;
+            }
+        }
+
+        let mut targets = Vec::new();
+        let mut sqes = Vec::new();
+        let mut res = Vec::new();
+        for m in 0..self.members.len() {
+            if self.state.state(m) == Raid1MemberState::Failed {
+                continue;
+            }
+            match self.member_io(m, op, op_flags, off, len, buf) {
+                Ok(sqe) => {
+                    targets.push(m);
+                    sqes.push(sqe);
+                }
+                Err(r) => res.push((m, r)),
+            }
+        }
+
+        for m in targets.iter() {
+            self.state.members[*m]
+                .inflight
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        let uring_res = super::uring_ops(q, tag, &sqes).await;
+        for m in targets.iter() {
+            self.state.members[*m]
+                .inflight
+                .fetch_sub(1, Ordering::Relaxed);
+        }
+        res.extend(targets.into_iter().zip(uring_res));
+
+        let mut done = false;
+        let mut failed = false;
+        for (m, r) in res {
+            if r == expected {
+                done = true;
+            } else {
+                failed = true;
+                self.fail_member(q, tag, m, r).await;
+            }
+        }
+
+        if track && self.state.end_write(off, len as u64, failed) {
+            self.checkpoint(q, tag).await;
+        }
+        if done {
+            expected
+        } else {
+            -libc::EIO
+        }
+    }
+
+    async fn handle_io(&self, q: &UblkQueue<'_>, tag: u16) -> i32 {
+        let iod = q.get_iod(tag);
+        let op = iod.op_flags & 0xff;
+
+        match op {
+            sys::UBLK_IO_OP_READ => {
+                let off = iod.start_sector << 9;
+                let len = iod.nr_sectors << 9;
+
+                self.read(q, tag, off, len, q.get_io_buf_addr(tag)).await
+            }
+            sys::UBLK_IO_OP_WRITE
+            | sys::UBLK_IO_OP_FLUSH
+            | sys::UBLK_IO_OP_DISCARD
+            | sys::UBLK_IO_OP_WRITE_ZEROES => self.write_all(q, tag, op, iod.op_flags).await,
+            _ => -libc::EINVAL,
+        }
+    }
+
+    /// Copy `region` from one active replica to replicas in `pass`
+    async fn copy_region(
+        &self,
+        q: &UblkQueue<'_>,
+        tag: u16,
+        region: u64,
+        pass: &[usize],
+        buf: *mut u8,
+        buf_len: u32,
+    ) -> bool {
+        let start = region << self.state.region_shift;
+        let end = std::cmp::min(start + (1 << self.state.region_shift), q.dev.get_dev_size());
+        let mut off = start;
+
+        while off < end {
+            let len = std::cmp::min(buf_len as u64, end - off) as u32;
+
+            if self.read(q, tag, off, len, buf).await != len as i32 {
+                return false;
+            }
+            for m in pass {
+                let sqe = self
+                    .member_io(*m, sys::UBLK_IO_OP_WRITE, 0, off, len, buf)
+                    .unwrap();
+                let res = super::uring_op(q, tag, sqe).await;
+
+                if res != len as i32 {
+                    self.fail_member(q, tag, *m, res).await;
+                }
+            }
+            off += len as u64;
+        }
+        true
+    }
+
+    /// Background resync, run in the extra io slot of queue 0
+    ///
+    /// Each pass copies all dirty regions to replicas in resync state when
+    /// the pass is started, and these replicas become active after the
+    /// pass is done. Dirty bit is cleared if all replicas are in sync.
+    async fn resync(&self, q: &UblkQueue<'_>, tag: u16) {
+        const BUF_LEN: u32 = 256 << 10;
+        let buf = super::TgtBuf::new(BUF_LEN as usize);
+        let nr = self.members.len();
+
+        loop {
+            let mut pass: Vec<usize> = (0..nr)
+                .filter(|m| self.state.state(*m) == Raid1MemberState::Resync)
+                .collect();
+
+            if pass.is_empty() {
+                sleep(q, tag, Duration::from_millis(100)).await;
+                continue;
+            }
+
+            log::info!("raid1: queue {} resync {:?}", q.get_qid(), pass);
+            let mut done = true;
+            for region in self.state.dirty_regions() {
+                pass.retain(|m| self.state.state(*m) == Raid1MemberState::Resync);
+                if pass.is_empty() {
+                    break;
+                }
+
+                while !self.state.lock_resync(region) {
+                    sleep(q, tag, Duration::from_micros(20)).await;
+                }
+                let ok = self
+                    .copy_region(q, tag, region, &pass, buf.as_mut_ptr(), BUF_LEN)
+                    .await;
+                done &= ok;
+                let clean = ok
+                    && (0..nr).all(|m| {
+                        let st = self.state.state(m);
+
+                        st == Raid1MemberState::Active
+                            || (st == Raid1MemberState::Resync && pass.contains(&m))
+                    });
+                self.state.unlock_resync(clean);
+            }
+
+            // no active replica to copy from, retry later
+            if !done {
+                sleep(q, tag, Duration::from_secs(1)).await;
+                continue;
+            }
+            for m in pass {
+                if self
+                    .state
+                    .switch(m, Raid1MemberState::Resync, Raid1MemberState::Active)
+                {
+                    log::info!("raid1: member {} is in sync", m);
+                }
+            }
+            self.checkpoint(q, tag).await;
+        }
+    }
+}
+
+async fn sleep(q: &UblkQueue<'_>, tag: u16, delay: Duration) {
+    let ts = types::Timespec::from(delay);
+    let sqe = opcode::Timeout::new(&ts as *const types::Timespec)
+        .flags(types::TimeoutFlags::ETIME_SUCCESS)
+        .build();
+
+    super::uring_op(q, tag, sqe).await;
+}
+
+/// RAID1 target
+///
+/// Built by `Raid1TgtBuilder`, and replicas are opened in `init_tgt()`.
+/// The target has to live in the whole device lifetime, and it is cheap
+/// to clone for moving into queue closure, and all clones share the same
+/// mirror state.
+///
+/// Discard and write zeroes on block device replica are handled by
+/// synchronous BLKDISCARD/BLKZEROOUT in the queue thread, so other IOs of
+/// the queue are blocked until the ioctl returns.
+#[derive(Default, Builder, Debug, Clone)]
+#[builder(setter(into))]
+pub struct Raid1Tgt {
+    /// paths of replica files or block devices
+    members: Vec<String>,
+
+    /// size covered by one bit of the dirty-region bitmap, has to be power
+    /// of 2 and not less than 4096
+    #[builder(default = "4 << 20")]
+    region_size: u32,
+
+    /// open replicas with O_DIRECT, fallback to buffered IO if it isn't
+    /// supported
+    #[builder(default = "true")]
+    direct_io: bool,
+
+    /// `target_data` or target state saved by previous run, for restoring
+    /// the bitmap and replica states; replica which was failed is resynced
+    /// if it is available now
+    ///
+    /// `target_data` is only the snapshot when the device is started, and
+    /// live updates go to the target state file, see
+    /// `UblkCtrl::get_target_state()`, so pass the target state if it
+    /// exists.
+    #[builder(default, setter(into, strip_option))]
+    saved: Option<serde_json::Value>,
+
+    #[builder(setter(skip))]
+    state: Option<Arc<Raid1State>>,
+
+    #[builder(setter(skip))]
+    files: Vec<Option<TgtMember>>,
+}
+
+impl Raid1Tgt {
+    /// Return `UBLK_DEV_F_*` flags required by this target
+    pub fn dev_flags(&self) -> u32 {
+        UBLK_DEV_F_ASYNC
+    }
+
+    fn state(&self) -> Result<&Arc<Raid1State>, UblkError> {
+        self.state
+            .as_ref()
+            .ok_or(UblkError::OtherError(-libc::EINVAL))
+    }
+
+    /// Return state of all replicas
+    pub fn member_states(&self) -> Result<Vec<Raid1MemberState>, UblkError> {
+        let s = self.state()?;
+
+        Ok((0..s.members.len()).map(|m| s.state(m)).collect())
+    }
+
+    /// Return how many regions are dirty
+    pub fn nr_dirty_regions(&self) -> Result<u64, UblkError> {
+        Ok(self.state()?.dirty_regions().len() as u64)
+    }
+
+    /// Mark replica `idx` as failed, such as for taking it offline
+    pub fn fail_member(&self, idx: usize) -> Result<(), UblkError> {
+        let s = self.state()?;
+
+        if idx >= s.members.len() {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+        s.set_failed(idx);
+        Ok(())
+    }
+
+    /// Re-add failed replica `idx` after it returns
+    ///
+    /// The replica is resynced in background, and becomes active after
+    /// all dirty regions are copied to it. The replica has to be opened
+    /// in `init_tgt()`, since it is accessed via the registered file.
+    pub fn readd_member(&self, idx: usize) -> Result<(), UblkError> {
+        let s = self.state()?;
+
+        if idx >= s.members.len()
+            || self.files[idx].is_none()
+            || !s.switch(idx, Raid1MemberState::Failed, Raid1MemberState::Resync)
+        {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+        Ok(())
+    }
+
+    fn saved_meta(&self) -> Result<Option<Raid1Meta>, UblkError> {
+        match self.saved.as_ref().map(|v| &v["raid1"]["bitmap"]) {
+            Some(v) => serde_json::from_value(v.clone())
+                .map(Some)
+                .map_err(UblkError::JsonError),
+            None => Ok(None),
+        }
+    }
+
+    /// Setup raid1 target, passed to `UblkSession::create_devices()`
+    ///
+    /// # Arguments:
+    ///
+    /// * `dev`: ublk device being created
+    ///
+    /// Open all replicas and register them as fixed files, and replica
+    /// which can't be opened is failed. Device size is the smallest replica
+    /// size, and the bitmap is exported as target state.
+    pub fn init_tgt(&mut self, dev: &mut UblkDev) -> Result<i32, UblkError> {
+        log::trace!("raid1: init_tgt {}", dev.dev_info.dev_id);
+
+        let rs = self.region_size;
+        if (dev.flags & UBLK_DEV_F_ASYNC) == 0
+            || self.members.is_empty()
+            || !rs.is_power_of_two()
+            || rs < 4096
+        {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        self.files.clear();
+        for p in self.members.iter() {
+            let m = match TgtMember::open(dev, p, self.direct_io, false) {
+                Ok(m) => Some(m),
+                Err(e) => {
+                    log::error!("raid1: failed to open member {} {:?}", p, e);
+
+                    // keep the slot of fixed file
+                    let tgt = &mut dev.tgt;
+                    let nr_fds = tgt.nr_fds as usize;
+                    if nr_fds >= tgt.fds.len() {
+                        return Err(UblkError::OtherError(-libc::EMFILE));
+                    }
+                    tgt.fds[nr_fds] = -1;
+                    tgt.nr_fds += 1;
+                    None
+                }
+            };
+            self.files.push(m);
+        }
+
+        let opened: Vec<&TgtMember> = self.files.iter().flatten().collect();
+        let lbs_shift = opened.iter().map(|m| m.lbs_shift).max().unwrap_or(9);
+        let pbs_shift = opened.iter().map(|m| m.pbs_shift).max().unwrap_or(9);
+        let size = opened.iter().map(|m| m.size).min().unwrap_or(0) & !((1 << lbs_shift) - 1);
+        if size == 0 {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        let state = Arc::new(Raid1State::new(
+            self.files.len(),
+            rs.trailing_zeros(),
+            size.div_ceil(rs as u64),
+        ));
+        let present: Vec<bool> = self.files.iter().map(|m| m.is_some()).collect();
+        match self.saved_meta()? {
+            Some(meta) => state.restore(&meta, &present)?,
+            None => {
+                for (m, p) in present.iter().enumerate() {
+                    if !p {
+                        state.set_failed(m);
+                    }
+                }
+            }
+        }
+
+        let tgt = &mut dev.tgt;
+        tgt.extra_ios = 1;
+        tgt.dev_size = size;
+        tgt.params = sys::ublk_params {
+            types: sys::UBLK_PARAM_TYPE_BASIC | sys::UBLK_PARAM_TYPE_DISCARD,
+            basic: sys::ublk_param_basic {
+                attrs: sys::UBLK_ATTR_VOLATILE_CACHE,
+                logical_bs_shift: lbs_shift,
+                physical_bs_shift: pbs_shift,
+                io_opt_shift: 12,
+                io_min_shift: lbs_shift,
+                max_sectors: dev.dev_info.max_io_buf_bytes >> 9,
+                dev_sectors: size >> 9,
+                ..Default::default()
+            },
+            discard: sys::ublk_param_discard {
+                discard_granularity: 1 << pbs_shift,
+                max_discard_sectors: u32::MAX >> 9,
+                max_write_zeroes_sectors: u32::MAX >> 9,
+                max_discard_segments: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // the bitmap covers initial size only
+        dev.set_max_dev_size(size);
+        let val = serde_json::json!({"raid1": Raid1Json {
+            members: self.members.clone(),
+            region_size: rs,
+            direct_io: opened.iter().all(|m| m.direct),
+            bitmap: state.to_meta(),
+        }});
+        dev.set_target_json(val);
+
+        let s = state.clone();
+        dev.set_state_serializer(move |_| {
+            Ok(serde_json::json!({"raid1": {"bitmap": s.to_meta()}}))
+        });
+        self.state = Some(state);
+
+        Ok(0)
+    }
+
+    /// Handle IO of queue `qid`, called from queue closure
+    ///
+    /// # Arguments:
+    ///
+    /// * `qid`: queue id
+    /// * `dev`: ublk device
+    ///
+    /// Won't return until the queue is down, and queue 0 runs the resync
+    /// task too.
+    pub fn run_queue(&self, qid: u16, dev: &UblkDev) -> Result<(), UblkError> {
+        let state = self.state()?;
+        let (tx, rx) = mpsc::channel();
+        let io = Rc::new(Raid1QueueIo {
+            state: state.clone(),
+            members: self
+                .files
+                .iter()
+                .map(|m| match m {
+                    Some(m) => (m.fd_idx, m.fd(), m.blkdev),
+                    // never accessed since it can't be re-added
+                    None => (0, -1, false),
+                })
+                .collect(),
+            saver: tx.clone(),
+        });
+        let rio = io.clone();
+        let depth = dev.dev_info.queue_depth;
+
+        std::thread::scope(|s| {
+            s.spawn(|| state.save_state(dev, rx));
+
+            let res = super::run_async_queue_with(
+                qid,
+                dev,
+                move |q, tag| {
+                    let io = io.clone();
+
+                    async move { io.handle_io(&q, tag).await }
+                },
+                move |exe, q| {
+                    if qid == 0 {
+                        let q = q.clone();
+
+                        exe.spawn(depth, async move { rio.resync(&q, depth).await });
+                    }
+                },
+            );
+            let _ = tx.send(true);
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Raid1MemberState, Raid1State, Raid1TgtBuilder};
+
+    #[test]
+    fn test_raid1_bitmap() {
+        let s = Raid1State::new(2, 20, 100);
+
+        // no dirty region if all replicas are active
+        assert!(s.start_write(0, 4096) == Some(false));
+        assert!(!s.end_write(0, 4096, false));
+        assert!(s.dirty_regions().is_empty());
+
+        // replica failed in the middle of write
+        assert!(s.start_write((1 << 20) - 512, 1024) == Some(false));
+        assert!(s.set_failed(1) && !s.set_failed(1));
+        assert!(s.end_write((1 << 20) - 512, 1024, true));
+        assert!(s.dirty_regions() == vec![0, 1]);
+
+        // write in degraded mode
+        assert!(s.start_write(70 << 20, 4096) == Some(true));
+        assert!(s.dirty_regions() == vec![0, 1, 70]);
+
+        // resync waits for in-flight write, and write waits for resync
+        assert!(!s.lock_resync(70));
+        assert!(!s.end_write(70 << 20, 4096, false));
+        assert!(s.lock_resync(70));
+        assert!(s.start_write(70 << 20, 512).is_none());
+        s.unlock_resync(true);
+        assert!(s.dirty_regions() == vec![0, 1]);
+
+        // restore from saved meta, and the failed replica is resynced
+        let meta = s.to_meta();
+        assert!(meta.failed == vec![false, true] && meta.dirty.len() == 2);
+        let s2 = Raid1State::new(2, 20, 100);
+        s2.restore(&meta, &[true, true]).unwrap();
+        assert!(s2.state(1) == Raid1MemberState::Resync);
+        assert!(s2.dirty_regions() == vec![0, 1]);
+        assert!(Raid1State::new(2, 21, 50)
+            .restore(&meta, &[true, true])
+            .is_err());
+
+        let r = Raid1TgtBuilder::default()
+            .members(vec!["a".to_string(), "b".to_string()])
+            .build()
+            .unwrap();
+        assert!(r.region_size == 4 << 20 && r.direct_io && r.saved.is_none());
+        assert!(r.member_states().is_err());
+    }
+}
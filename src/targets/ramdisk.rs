@@ -0,0 +1,371 @@
+//! Ramdisk target: in-memory ublk device with sparse allocation
+//!
+//! Memory is allocated in chunks on the first write, unwritten area is
+//! read as zeros, and chunks are freed by DISCARD & WRITE_ZEROES. The
+//! allocated memory can be capped, and write needing new chunk beyond the
+//! cap fails with ENOSPC.
+
+use crate::dev_flags::UBLK_DEV_F_ASYNC;
+use crate::io::{UblkDev, UblkIOCtx, UblkQueue};
+use crate::{sys, UblkError, UblkIORes};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug, Serialize)]
+struct RamdiskJson {
+    size: u64,
+    chunk_size: u32,
+    mem_cap: u64,
+}
+
+/// Chunk map is split into shards by chunk index, so IOs on different
+/// chunks don't contend on one lock
+const RAMDISK_SHARDS: u64 = 64;
+
+type RamdiskShard = RwLock<HashMap<u64, Box<[u8]>>>;
+
+/// Chunks of ramdisk, shared by all queues
+#[derive(Debug, Default)]
+struct RamdiskStore {
+    chunk_shift: u32,
+    /// max number of chunks
+    max_chunks: u64,
+    /// allocated chunks, including ones reserved by in-progress write
+    nr_chunks: AtomicU64,
+    shards: Vec<RamdiskShard>,
+}
+
+impl RamdiskStore {
+    fn new(chunk_size: u32, mem_cap: u64) -> Self {
+        let chunk_shift = chunk_size.trailing_zeros();
+
+        RamdiskStore {
+            chunk_shift,
+            max_chunks: mem_cap >> chunk_shift,
+            nr_chunks: AtomicU64::new(0),
+            shards: (0..RAMDISK_SHARDS).map(|_| Default::default()).collect(),
+        }
+    }
+
+    #[inline]
+    fn chunk_size(&self) -> u64 {
+        1_u64 << self.chunk_shift
+    }
+
+    #[inline]
+    fn shard(&self, idx: u64) -> &RamdiskShard {
+        &self.shards[(idx % RAMDISK_SHARDS) as usize]
+    }
+
+    /// Iterate over (chunk index, offset in chunk, offset in range, length)
+    /// of each chunk covered by range [off, off + len)
+    fn for_each_chunk<F>(&self, off: u64, len: u64, mut f: F)
+    where
+        F: FnMut(u64, usize, usize, usize),
+    {
+        let mut pos = off;
+
+        while pos < off + len {
+            let idx = pos >> self.chunk_shift;
+            let c_off = pos & (self.chunk_size() - 1);
+            let c_len = std::cmp::min(self.chunk_size() - c_off, off + len - pos);
+
+            f(idx, c_off as usize, (pos - off) as usize, c_len as usize);
+            pos += c_len;
+        }
+    }
+
+    fn mem_used(&self) -> u64 {
+        self.nr_chunks.load(Ordering::Relaxed) * self.chunk_size()
+    }
+
+    /// Reserve `nr` new chunks, return false if the memory cap is reached
+    fn reserve(&self, nr: u64) -> bool {
+        self.nr_chunks
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n + nr <= self.max_chunks).then_some(n + nr)
+            })
+            .is_ok()
+    }
+
+    fn read(&self, off: u64, buf: &mut [u8]) {
+        self.for_each_chunk(off, buf.len() as u64, |idx, c_off, b_off, len| {
+            let dst = &mut buf[b_off..b_off + len];
+
+            match self.shard(idx).read().unwrap().get(&idx) {
+                Some(c) => dst.copy_from_slice(&c[c_off..c_off + len]),
+                None => dst.fill(0),
+            }
+        });
+    }
+
+    /// Write `buf` to offset `off`, return -ENOSPC if new chunks can't be
+    /// allocated because of the memory cap, and nothing is written
+    ///
+    /// New chunks are reserved before writing. If one chunk is freed by
+    /// concurrent discard after the reservation, and no more chunk can be
+    /// allocated for it, the write fails with -ENOSPC after part of data
+    /// is written.
+    fn write(&self, off: u64, buf: &[u8]) -> Result<(), i32> {
+        let mut nr_new = 0;
+
+        self.for_each_chunk(off, buf.len() as u64, |idx, _, _, _| {
+            if !self.shard(idx).read().unwrap().contains_key(&idx) {
+                nr_new += 1;
+            }
+        });
+        if !self.reserve(nr_new) {
+            return Err(-libc::ENOSPC);
+        }
+
+        let chunk_size = self.chunk_size() as usize;
+        let mut res = Ok(());
+        self.for_each_chunk(off, buf.len() as u64, |idx, c_off, b_off, len| {
+            if res.is_err() {
+                return;
+            }
+
+            let mut shard = self.shard(idx).write().unwrap();
+            if !shard.contains_key(&idx) {
+                if nr_new > 0 {
+                    nr_new -= 1;
+                } else if !self.reserve(1) {
+                    res = Err(-libc::ENOSPC);
+                    return;
+                }
+            }
+            let c = shard
+                .entry(idx)
+                .or_insert_with(|| vec![0_u8; chunk_size].into_boxed_slice());
+
+            c[c_off..c_off + len].copy_from_slice(&buf[b_off..b_off + len]);
+        });
+
+        // chunks allocated by concurrent write
+        self.nr_chunks.fetch_sub(nr_new, Ordering::Relaxed);
+        res
+    }
+
+    /// Free chunks covered by range [off, off + len) completely, and zero
+    /// the partially covered part
+    fn discard(&self, off: u64, len: u64) {
+        let chunk_size = self.chunk_size() as usize;
+
+        self.for_each_chunk(off, len, |idx, c_off, _, len| {
+            let mut shard = self.shard(idx).write().unwrap();
+
+            if len == chunk_size {
+                if shard.remove(&idx).is_some() {
+                    self.nr_chunks.fetch_sub(1, Ordering::Relaxed);
+                }
+            } else if let Some(c) = shard.get_mut(&idx) {
+                c[c_off..c_off + len].fill(0);
+            }
+        });
+    }
+}
+
+/// Ramdisk target
+///
+/// Built by `RamdiskTgtBuilder`, and memory is shared by all queues, so
+/// it is cheap to clone for moving into queue closure.
+#[derive(Default, Builder, Debug, Clone)]
+#[builder(setter(into))]
+pub struct RamdiskTgt {
+    /// device size in bytes
+    size: u64,
+
+    /// allocation unit, has to be power of 2 and at least 4096
+    #[builder(default = "65536_u32")]
+    chunk_size: u32,
+
+    /// max bytes of allocated chunks, default is device size
+    #[builder(default, setter(into, strip_option))]
+    mem_cap: Option<u64>,
+
+    #[builder(setter(skip))]
+    store: Arc<RamdiskStore>,
+}
+
+impl RamdiskTgt {
+    /// Return bytes of allocated chunks
+    pub fn mem_used(&self) -> u64 {
+        self.store.mem_used()
+    }
+
+    fn handle_io(store: &RamdiskStore, q: &UblkQueue, tag: u16) -> i32 {
+        let iod = q.get_iod(tag);
+        let off = iod.start_sector << 9;
+        let bytes = (iod.nr_sectors << 9) as usize;
+        let op = iod.op_flags & 0xff;
+        let buf_addr = q.get_io_buf_addr(tag);
+
+        match op {
+            sys::UBLK_IO_OP_FLUSH => 0,
+            sys::UBLK_IO_OP_READ => {
+                let buf = unsafe { std::slice::from_raw_parts_mut(buf_addr, bytes) };
+
+                store.read(off, buf);
+                bytes as i32
+            }
+            sys::UBLK_IO_OP_WRITE => {
+                let buf = unsafe { std::slice::from_raw_parts(buf_addr, bytes) };
+
+                match store.write(off, buf) {
+                    Ok(_) => bytes as i32,
+                    Err(e) => e,
+                }
+            }
+            sys::UBLK_IO_OP_DISCARD | sys::UBLK_IO_OP_WRITE_ZEROES => {
+                store.discard(off, bytes as u64);
+                0
+            }
+            _ => -libc::EINVAL,
+        }
+    }
+
+    /// Setup ramdisk target, passed to `UblkSession::create_devices()`
+    ///
+    /// # Arguments:
+    ///
+    /// * `dev`: ublk device being created
+    pub fn init_tgt(&mut self, dev: &mut UblkDev) -> Result<i32, UblkError> {
+        let mem_cap = self.mem_cap.unwrap_or(self.size);
+
+        if !self.chunk_size.is_power_of_two() || self.chunk_size < 4096 || (self.size & 511) != 0 {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        self.store = Arc::new(RamdiskStore::new(self.chunk_size, mem_cap));
+
+        let tgt = &mut dev.tgt;
+        tgt.dev_size = self.size;
+        tgt.params = sys::ublk_params {
+            types: sys::UBLK_PARAM_TYPE_BASIC | sys::UBLK_PARAM_TYPE_DISCARD,
+            basic: sys::ublk_param_basic {
+                logical_bs_shift: 9,
+                physical_bs_shift: 12,
+                io_opt_shift: 12,
+                io_min_shift: 9,
+                max_sectors: dev.dev_info.max_io_buf_bytes >> 9,
+                dev_sectors: self.size >> 9,
+                ..Default::default()
+            },
+            discard: sys::ublk_param_discard {
+                discard_granularity: self.chunk_size,
+                max_discard_sectors: u32::MAX >> 9,
+                max_write_zeroes_sectors: u32::MAX >> 9,
+                max_discard_segments: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        dev.set_max_dev_size(self.size);
+        let val = serde_json::json!({"ramdisk": RamdiskJson {
+            size: self.size,
+            chunk_size: self.chunk_size,
+            mem_cap,
+        }});
+        dev.set_target_json(val);
+
+        Ok(0)
+    }
+
+    /// Handle IO of queue `qid`, called from queue closure
+    ///
+    /// # Arguments:
+    ///
+    /// * `qid`: queue id
+    /// * `dev`: ublk device
+    ///
+    /// IO is handled by async/.await if the device is created with
+    /// `UBLK_DEV_F_ASYNC`. Won't return until the queue is down.
+    pub fn run_queue(&self, qid: u16, dev: &UblkDev) -> Result<(), UblkError> {
+        if (dev.flags & UBLK_DEV_F_ASYNC) != 0 {
+            let store = self.store.clone();
+
+            super::run_async_queue(qid, dev, move |q, tag| {
+                let res = Self::handle_io(&store, &q, tag);
+                async move { res }
+            })
+        } else {
+            let store = &self.store;
+
+            UblkQueue::new(qid, dev)?.wait_and_handle_io(
+                move |q: &UblkQueue, tag: u16, _io: &UblkIOCtx| {
+                    let res = Self::handle_io(store, q, tag);
+                    q.complete_io_cmd(tag, Ok(UblkIORes::Result(res)));
+                },
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RamdiskStore, RamdiskTgtBuilder};
+
+    #[test]
+    fn test_ramdisk_store() {
+        let store = RamdiskStore::new(4096, 3 * 4096);
+        let mut buf = vec![0xff_u8; 8192];
+
+        // unwritten area is read as zeros
+        store.read(1024, &mut buf);
+        assert!(buf.iter().all(|b| *b == 0));
+        assert!(store.mem_used() == 0);
+
+        // cross two chunks
+        let data = vec![0x5a_u8; 6144];
+        store.write(2048, &data).unwrap();
+        assert!(store.mem_used() == 8192);
+        let mut buf = vec![0_u8; 6144];
+        store.read(2048, &mut buf);
+        assert!(buf == data);
+
+        // one new chunk is allowed, and nothing is written on ENOSPC
+        assert!(store.write(8192, &[1_u8; 512]).is_ok());
+        assert!(store.write(3 * 4096, &[1_u8; 512]) == Err(-libc::ENOSPC));
+        assert!(store.mem_used() == 3 * 4096);
+
+        // whole chunk is freed, and partial chunk is zeroed
+        store.discard(2048, 8192 - 2048);
+        assert!(store.mem_used() == 2 * 4096);
+        let mut buf = vec![0xff_u8; 8192];
+        store.read(0, &mut buf);
+        assert!(buf.iter().all(|b| *b == 0));
+
+        // concurrent writers on different chunks, and the cap is kept
+        let store = std::sync::Arc::new(RamdiskStore::new(4096, 64 * 4096));
+        let handles: Vec<_> = (0..4_u8)
+            .map(|t| {
+                let store = store.clone();
+
+                std::thread::spawn(move || {
+                    for i in 0..32_u64 {
+                        let _ = store.write((i * 4 + t as u64) * 4096, &[t + 1; 4096]);
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert!(store.mem_used() == 64 * 4096);
+        let mut buf = vec![0_u8; 4096];
+        for i in 0..128_u64 {
+            store.read(i * 4096, &mut buf);
+            assert!(buf.iter().all(|b| *b == 0 || *b == (i % 4) as u8 + 1));
+        }
+
+        let rd = RamdiskTgtBuilder::default()
+            .size(1_u64 << 30)
+            .mem_cap(16_u64 << 20)
+            .build()
+            .unwrap();
+        assert!(rd.chunk_size == 65536 && rd.mem_cap == Some(16 << 20));
+    }
+}
@@ -0,0 +1,244 @@
+//! Sparse file target: thin provisioned ublk device stored in sparse file
+//!
+//! Built on the loop target, DISCARD punches hole and WRITE_ZEROES is
+//! handled by `FALLOC_FL_ZERO_RANGE`, and discard parameters are set from
+//! the file system's block size. Which ranges are allocated can be queried
+//! by SEEK_DATA/SEEK_HOLE.
+
+use super::r#loop::{LoopTgt, LoopTgtBuilder};
+use crate::io::UblkDev;
+use crate::UblkError;
+use serde::Serialize;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+
+#[derive(Debug, Serialize)]
+struct SparseJson {
+    file_path: String,
+    size: u64,
+    allocated: u64,
+    discard_granularity: u32,
+    direct_io: bool,
+    #[serde(rename = "async")]
+    async_io: bool,
+}
+
+/// Return data extents (offset, length) of `f` in range [off, off + len),
+/// found by SEEK_DATA/SEEK_HOLE
+fn data_extents(f: &std::fs::File, off: u64, len: u64) -> Result<Vec<(u64, u64)>, UblkError> {
+    let fd = f.as_raw_fd();
+    let end = off + len;
+    let mut pos = off;
+    let mut map = Vec::new();
+
+    while pos < end {
+        let data = unsafe { libc::lseek(fd, pos as i64, libc::SEEK_DATA) };
+        if data < 0 {
+            let err = super::errno();
+
+            // no data after `pos`
+            if err == libc::ENXIO {
+                break;
+            }
+            return Err(UblkError::OtherError(-err));
+        }
+        let data = data as u64;
+        if data >= end {
+            break;
+        }
+
+        let hole = unsafe { libc::lseek(fd, data as i64, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(UblkError::OtherError(-super::errno()));
+        }
+        let e = std::cmp::min(hole as u64, end);
+
+        map.push((data, e - data));
+        pos = e;
+    }
+    Ok(map)
+}
+
+/// Sparse file target
+///
+/// Built by `SparseTgtBuilder`, and the file is created or extended in
+/// `init_tgt()`. The target has to live in the whole device lifetime, and
+/// it is cheap to clone for moving into queue closure.
+#[derive(Default, Builder, Debug, Clone)]
+#[builder(setter(into))]
+pub struct SparseTgt {
+    /// path of sparse file, created if it doesn't exist
+    file_path: String,
+
+    /// device size in bytes, the file is extended to this size if it is
+    /// smaller, default is the file size
+    #[builder(default, setter(into, strip_option))]
+    size: Option<u64>,
+
+    /// open the file with O_DIRECT, fallback to buffered IO if it isn't
+    /// supported
+    #[builder(default = "true")]
+    direct_io: bool,
+
+    /// handle IO by async/.await, the device has to be created with
+    /// `UBLK_DEV_F_ASYNC`
+    #[builder(default = "false")]
+    async_io: bool,
+
+    #[builder(setter(skip))]
+    lo: LoopTgt,
+
+    #[builder(setter(skip))]
+    file: Option<Arc<std::fs::File>>,
+}
+
+impl SparseTgt {
+    /// Return `UBLK_DEV_F_*` flags required by this target
+    pub fn dev_flags(&self) -> u32 {
+        self.lo_builder()
+            .build()
+            .map(|lo| lo.dev_flags())
+            .unwrap_or(0)
+    }
+
+    fn lo_builder(&self) -> LoopTgtBuilder {
+        let mut b = LoopTgtBuilder::default();
+
+        b.back_file_path(self.file_path.clone())
+            .direct_io(self.direct_io)
+            .async_io(self.async_io)
+            .zero_range(true);
+        b
+    }
+
+    fn file(&self) -> Result<&std::fs::File, UblkError> {
+        self.file
+            .as_deref()
+            .ok_or(UblkError::OtherError(-libc::EINVAL))
+    }
+
+    /// Return allocated extents (offset, length) in device range
+    /// [off, off + len)
+    ///
+    /// Only available after `init_tgt()` returns. Extents are aligned with
+    /// the file system's block size.
+    pub fn allocation_map(&self, off: u64, len: u64) -> Result<Vec<(u64, u64)>, UblkError> {
+        data_extents(self.file()?, off, len)
+    }
+
+    /// Return bytes allocated by the sparse file
+    pub fn allocated_bytes(&self) -> Result<u64, UblkError> {
+        let meta = self.file()?.metadata().map_err(UblkError::OtherIOError)?;
+
+        Ok(meta.blocks() << 9)
+    }
+
+    /// Setup sparse file target, passed to `UblkSession::create_devices()`
+    ///
+    /// # Arguments:
+    ///
+    /// * `dev`: ublk device being created
+    ///
+    /// Create or extend the sparse file, check if hole punching is
+    /// supported, then setup the device as loop target.
+    pub fn init_tgt(&mut self, dev: &mut UblkDev) -> Result<i32, UblkError> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.file_path)
+            .map_err(UblkError::OtherIOError)?;
+        let meta = file.metadata().map_err(UblkError::OtherIOError)?;
+        let size = std::cmp::max(self.size.unwrap_or(0), meta.len());
+
+        if !meta.file_type().is_file() || size == 0 || (size & 511) != 0 {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+        if meta.len() < size {
+            file.set_len(size).map_err(UblkError::OtherIOError)?;
+        }
+
+        // punch one block beyond EOF for checking if it is supported
+        let blksz = std::cmp::max(meta.blksize(), 512).next_power_of_two();
+        let probe_off = (size + blksz - 1) & !(blksz - 1);
+        let ret = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                probe_off as i64,
+                blksz as i64,
+            )
+        };
+        if ret < 0 {
+            return Err(UblkError::OtherError(-super::errno()));
+        }
+
+        self.lo = self
+            .lo_builder()
+            .build()
+            .map_err(|_| UblkError::OtherError(-libc::EINVAL))?;
+        self.lo.init_tgt(dev)?;
+        dev.tgt.params.discard.discard_granularity = blksz as u32;
+
+        let val = serde_json::json!({"sparse": SparseJson {
+            file_path: self.file_path.clone(),
+            size,
+            allocated: meta.blocks() << 9,
+            discard_granularity: blksz as u32,
+            direct_io: self.lo.direct_io_enabled(),
+            async_io: self.async_io,
+        }});
+        dev.set_target_json(val);
+        self.file = Some(Arc::new(file));
+
+        Ok(0)
+    }
+
+    /// Handle IO of queue `qid`, called from queue closure
+    ///
+    /// # Arguments:
+    ///
+    /// * `qid`: queue id
+    /// * `dev`: ublk device
+    ///
+    /// Won't return until the queue is down.
+    pub fn run_queue(&self, qid: u16, dev: &UblkDev) -> Result<(), UblkError> {
+        self.lo.run_queue(qid, dev)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{data_extents, SparseTgtBuilder};
+    use std::os::unix::fs::FileExt;
+
+    #[test]
+    fn test_sparse_extents() {
+        let tmp = tempfile::NamedTempFile::new().unwrap();
+        let f = tmp.as_file();
+
+        f.set_len(8 << 20).unwrap();
+        assert!(data_extents(f, 0, 8 << 20).unwrap().is_empty());
+
+        f.write_all_at(&[1_u8; 65536], 1 << 20).unwrap();
+        f.write_all_at(&[1_u8; 4096], 4 << 20).unwrap();
+        let map = data_extents(f, 0, 8 << 20).unwrap();
+        assert!(map.len() == 2);
+        assert!(map[0].0 <= 1 << 20 && map[0].0 + map[0].1 >= (1 << 20) + 65536);
+        assert!(map[1].0 <= 4 << 20 && map[1].0 + map[1].1 >= (4 << 20) + 4096);
+
+        // clipped by the range
+        let map = data_extents(f, (1 << 20) + 4096, 4096).unwrap();
+        assert!(map == vec![((1 << 20) + 4096, 4096)]);
+
+        let sp = SparseTgtBuilder::default()
+            .file_path(tmp.path().to_str().unwrap())
+            .async_io(true)
+            .build()
+            .unwrap();
+        assert!(sp.dev_flags() == crate::dev_flags::UBLK_DEV_F_ASYNC);
+        assert!(sp.size.is_none() && sp.allocation_map(0, 4096).is_err());
+    }
+}
@@ -0,0 +1,535 @@
+//! Stripe target: spread one ublk device over multiple files or block devices
+//!
+//! Members are either striped with fixed chunk size(RAID0), or concatenated
+//! linearly. IO crossing chunk or member boundary is split into per-member
+//! target IOs issued on the queue ring, and the IO command is completed
+//! after all of them are done.
+
+use super::TgtMember;
+use crate::dev_flags::UBLK_DEV_F_ASYNC;
+use crate::io::{UblkDev, UblkIOCtx, UblkQueue};
+use crate::{sys, UblkError, UblkIORes};
+use io_uring::{opcode, squeue, types};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// How the device is laid out over members
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StripeLayout {
+    /// striped by `chunk_size`, same with RAID0
+    #[default]
+    Striped,
+
+    /// members are concatenated one by one
+    Concat,
+}
+
+#[derive(Debug, Serialize)]
+struct StripeJson {
+    members: Vec<String>,
+    layout: StripeLayout,
+    chunk_size: u32,
+    direct_io: bool,
+    read_only: bool,
+    #[serde(rename = "async")]
+    async_io: bool,
+}
+
+/// Part of one IO which is handled by single member
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct StripeSeg {
+    member: usize,
+    /// offset in member
+    off: u64,
+    /// offset in IO buffer
+    buf_off: u64,
+    len: u64,
+}
+
+/// Map device offset to members
+#[derive(Default, Debug, Clone)]
+struct StripeMap {
+    layout: StripeLayout,
+    nr_members: u64,
+    chunk_shift: u32,
+    /// device offset of each member for concat layout, the last one is
+    /// device size, and empty member is skipped by the mapping
+    starts: Vec<u64>,
+}
+
+impl StripeMap {
+    fn map(&self, off: u64, len: u64) -> Vec<StripeSeg> {
+        let end = off + len;
+        let mut pos = off;
+        let mut segs = Vec::new();
+
+        while pos < end {
+            let seg = match self.layout {
+                StripeLayout::Striped => {
+                    let chunk = pos >> self.chunk_shift;
+                    let in_off = pos & ((1 << self.chunk_shift) - 1);
+
+                    StripeSeg {
+                        member: (chunk % self.nr_members) as usize,
+                        off: ((chunk / self.nr_members) << self.chunk_shift) | in_off,
+                        buf_off: pos - off,
+                        len: std::cmp::min((1 << self.chunk_shift) - in_off, end - pos),
+                    }
+                }
+                StripeLayout::Concat => {
+                    let m = self.starts.partition_point(|s| *s <= pos) - 1;
+
+                    StripeSeg {
+                        member: m,
+                        off: pos - self.starts[m],
+                        buf_off: pos - off,
+                        len: std::cmp::min(self.starts[m + 1] - pos, end - pos),
+                    }
+                }
+            };
+            pos += seg.len;
+            segs.push(seg);
+        }
+        segs
+    }
+}
+
+/// Target IOs of one IO command, and expected bytes of each
+#[derive(Default, Debug)]
+struct StripeIo {
+    sqes: Vec<squeue::Entry>,
+    lens: Vec<u32>,
+    res: Vec<i32>,
+    pending: usize,
+}
+
+impl StripeIo {
+    /// Return IO command result from results of all target IOs
+    fn result(lens: &[u32], res: &[i32]) -> i32 {
+        let mut bytes = 0;
+
+        for (len, r) in lens.iter().zip(res) {
+            if *r < 0 {
+                return *r;
+            }
+            // short read or write can't be reported for split IO
+            if *r as u32 != *len {
+                return -libc::EIO;
+            }
+            bytes += *len as i32;
+        }
+        bytes
+    }
+}
+
+/// Per-queue view of stripe target
+#[derive(Debug)]
+struct StripeQueueIo {
+    map: StripeMap,
+    members: Vec<(u32, i32, bool)>,
+    read_only: bool,
+}
+
+impl StripeQueueIo {
+    /// Build target IOs for handling io command `tag`
+    ///
+    /// Discard & write zeroes of block device member is done synchronously,
+    /// and error is returned as `Err(res)`.
+    fn prep_io(&self, q: &UblkQueue, tag: u16) -> Result<StripeIo, i32> {
+        let iod = q.get_iod(tag);
+        let op = iod.op_flags & 0xff;
+        let off = iod.start_sector << 9;
+        let bytes = (iod.nr_sectors << 9) as u64;
+        let buf = q.get_io_buf_addr(tag);
+        let mut io = StripeIo::default();
+
+        if self.read_only && op != sys::UBLK_IO_OP_READ && op != sys::UBLK_IO_OP_FLUSH {
+            return Err(-libc::EROFS);
+        }
+
+        if op == sys::UBLK_IO_OP_FLUSH {
+            for (fd_idx, _, _) in self.members.iter() {
+                let sqe = opcode::Fsync::new(types::Fixed(*fd_idx))
+                    .flags(types::FsyncFlags::DATASYNC)
+                    .build();
+
+                io.sqes.push(sqe.flags(squeue::Flags::FIXED_FILE));
+                io.lens.push(0);
+            }
+            return Ok(io);
+        }
+
+        for seg in self.map.map(off, bytes) {
+            let (fd_idx, fd, blkdev) = self.members[seg.member];
+            let fd_idx = types::Fixed(fd_idx);
+            let addr = unsafe { buf.add(seg.buf_off as usize) };
+            let len = seg.len as u32;
+
+            let (sqe, len) = match op {
+                sys::UBLK_IO_OP_READ => (
+                    opcode::Read::new(fd_idx, addr, len).offset(seg.off).build(),
+                    len,
+                ),
+                sys::UBLK_IO_OP_WRITE => (
+                    opcode::Write::new(fd_idx, addr, len)
+                        .offset(seg.off)
+                        .build(),
+                    len,
+                ),
+                sys::UBLK_IO_OP_DISCARD | sys::UBLK_IO_OP_WRITE_ZEROES => {
+                    let zero = op == sys::UBLK_IO_OP_WRITE_ZEROES;
+
+                    if blkdev {
+                        let res = super::blk_discard(fd, seg.off, seg.len, zero);
+
+                        if res < 0 {
+                            return Err(res);
+                        }
+                        continue;
+                    }
+
+                    let sqe = opcode::Fallocate::new(fd_idx, seg.len)
+                        .offset(seg.off)
+                        .mode(super::discard_fallocate_mode(iod.op_flags, false))
+                        .build();
+                    (sqe, 0)
+                }
+                _ => return Err(-libc::EINVAL),
+            };
+            io.sqes.push(sqe.flags(squeue::Flags::FIXED_FILE));
+            io.lens.push(len);
+        }
+        Ok(io)
+    }
+
+    async fn handle_io_async(&self, q: &UblkQueue<'_>, tag: u16) -> i32 {
+        match self.prep_io(q, tag) {
+            Ok(io) => {
+                let res = super::uring_ops(q, tag, &io.sqes).await;
+
+                StripeIo::result(&io.lens, &res)
+            }
+            Err(res) => res,
+        }
+    }
+
+    fn handle_io_sync(&self, q: &UblkQueue, tag: u16, i: &UblkIOCtx, ios: &RefCell<Vec<StripeIo>>) {
+        let mut ios = ios.borrow_mut();
+        let io = &mut ios[tag as usize];
+
+        if i.is_tgt_io() {
+            // target IO index is stored in tgt_data of user_data
+            let idx = ((i.user_data() >> 24) & 0xffff) as usize - 1;
+
+            io.res[idx] = i.result();
+            io.pending -= 1;
+            if io.pending == 0 {
+                let res = StripeIo::result(&io.lens, &io.res);
+
+                q.complete_io_cmd(tag, Ok(UblkIORes::Result(res)));
+            }
+            return;
+        }
+
+        *io = match self.prep_io(q, tag) {
+            Ok(io) => io,
+            Err(res) => {
+                q.complete_io_cmd(tag, Ok(UblkIORes::Result(res)));
+                return;
+            }
+        };
+        if io.sqes.is_empty() {
+            q.complete_io_cmd(tag, Ok(UblkIORes::Result(0)));
+            return;
+        }
+
+        let op = q.get_iod(tag).op_flags & 0xff;
+        io.res = vec![0; io.sqes.len()];
+        io.pending = io.sqes.len();
+        for idx in 0..io.sqes.len() {
+            let user_data = UblkIOCtx::build_user_data(tag, op, idx as u32 + 1, true);
+
+            if let Err(e) = super::queue_sqe(q, &io.sqes[idx].clone().user_data(user_data)) {
+                // fail the rest, and wait for the queued ones
+                io.res[idx..].fill(e);
+                io.pending = idx;
+                if io.pending == 0 {
+                    q.complete_io_cmd(tag, Ok(UblkIORes::Result(e)));
+                }
+                break;
+            }
+        }
+    }
+}
+
+/// Striped or concatenated multi-member target
+///
+/// Built by `StripeTgtBuilder`, and members are opened in `init_tgt()`.
+/// The target has to live in the whole device lifetime, and it is cheap
+/// to clone for moving into queue closure.
+///
+/// Discard & write zeroes of block device member is handled by synchronous
+/// BLKDISCARD & BLKZEROOUT ioctl in the queue thread, so other IOs of the
+/// queue are blocked until the ioctl returns.
+#[derive(Default, Builder, Debug, Clone)]
+#[builder(setter(into))]
+pub struct StripeTgt {
+    /// paths of member files or block devices
+    members: Vec<String>,
+
+    /// striped or concatenated
+    #[builder(default)]
+    layout: StripeLayout,
+
+    /// chunk size in bytes for striped layout, has to be power of 2 and
+    /// not less than logical block size of any member
+    #[builder(default = "65536")]
+    chunk_size: u32,
+
+    /// open members with O_DIRECT, fallback to buffered IO if it isn't
+    /// supported
+    #[builder(default = "true")]
+    direct_io: bool,
+
+    /// expose read-only ublk device
+    #[builder(default = "false")]
+    read_only: bool,
+
+    /// handle IO by async/.await, the device has to be created with
+    /// `UBLK_DEV_F_ASYNC`
+    #[builder(default = "false")]
+    async_io: bool,
+
+    #[builder(setter(skip))]
+    files: Vec<TgtMember>,
+
+    #[builder(setter(skip))]
+    map: StripeMap,
+}
+
+impl StripeTgt {
+    /// Return `UBLK_DEV_F_*` flags required by this target
+    pub fn dev_flags(&self) -> u32 {
+        if self.async_io {
+            UBLK_DEV_F_ASYNC
+        } else {
+            0
+        }
+    }
+
+    fn build_map(&self, lbs_shift: u8) -> Result<(StripeMap, u64), UblkError> {
+        let nr = self.files.len() as u64;
+        let mut map = StripeMap {
+            layout: self.layout,
+            nr_members: nr,
+            ..Default::default()
+        };
+
+        let size = match self.layout {
+            StripeLayout::Striped => {
+                let cs = self.chunk_size as u64;
+
+                if !cs.is_power_of_two() || cs < (1 << lbs_shift) {
+                    return Err(UblkError::OtherError(-libc::EINVAL));
+                }
+                map.chunk_shift = cs.trailing_zeros();
+                self.files
+                    .iter()
+                    .map(|m| m.size & !(cs - 1))
+                    .min()
+                    .unwrap_or(0)
+                    * nr
+            }
+            StripeLayout::Concat => {
+                let mut start = 0;
+
+                map.starts.push(0);
+                for m in self.files.iter() {
+                    start += m.size & !((1 << lbs_shift) - 1);
+                    map.starts.push(start);
+                }
+                start
+            }
+        };
+
+        if size == 0 {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+        Ok((map, size))
+    }
+
+    /// Setup stripe target, passed to `UblkSession::create_devices()`
+    ///
+    /// # Arguments:
+    ///
+    /// * `dev`: ublk device being created
+    ///
+    /// Open all members and register them as fixed files, then setup device
+    /// size, parameters and target json.
+    pub fn init_tgt(&mut self, dev: &mut UblkDev) -> Result<i32, UblkError> {
+        log::trace!("stripe: init_tgt {}", dev.dev_info.dev_id);
+
+        if ((dev.flags & UBLK_DEV_F_ASYNC) != 0) != self.async_io || self.members.is_empty() {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        self.files = self
+            .members
+            .iter()
+            .map(|p| TgtMember::open(dev, p, self.direct_io, self.read_only))
+            .collect::<Result<Vec<_>, _>>()?;
+        let lbs_shift = self.files.iter().map(|m| m.lbs_shift).max().unwrap_or(9);
+        let pbs_shift = self.files.iter().map(|m| m.pbs_shift).max().unwrap_or(9);
+        let (map, size) = self.build_map(lbs_shift)?;
+        let io_shift = match self.layout {
+            StripeLayout::Striped => map.chunk_shift as u8,
+            StripeLayout::Concat => 12,
+        };
+        self.map = map;
+
+        let tgt = &mut dev.tgt;
+        tgt.dev_size = size;
+        tgt.params = sys::ublk_params {
+            types: sys::UBLK_PARAM_TYPE_BASIC,
+            basic: sys::ublk_param_basic {
+                attrs: if self.read_only {
+                    sys::UBLK_ATTR_READ_ONLY
+                } else {
+                    0
+                } | sys::UBLK_ATTR_VOLATILE_CACHE,
+                logical_bs_shift: lbs_shift,
+                physical_bs_shift: pbs_shift,
+                io_opt_shift: io_shift,
+                io_min_shift: lbs_shift,
+                max_sectors: dev.dev_info.max_io_buf_bytes >> 9,
+                dev_sectors: size >> 9,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        if !self.read_only {
+            tgt.params.types |= sys::UBLK_PARAM_TYPE_DISCARD;
+            tgt.params.discard = sys::ublk_param_discard {
+                discard_granularity: 1 << pbs_shift,
+                max_discard_sectors: u32::MAX >> 9,
+                max_write_zeroes_sectors: u32::MAX >> 9,
+                max_discard_segments: 1,
+                ..Default::default()
+            };
+        }
+
+        dev.set_max_dev_size(size);
+        let val = serde_json::json!({"stripe": StripeJson {
+            members: self.members.clone(),
+            layout: self.layout,
+            chunk_size: self.chunk_size,
+            direct_io: self.files.iter().all(|m| m.direct),
+            read_only: self.read_only,
+            async_io: self.async_io,
+        }});
+        dev.set_target_json(val);
+
+        Ok(0)
+    }
+
+    /// Handle IO of queue `qid`, called from queue closure
+    ///
+    /// # Arguments:
+    ///
+    /// * `qid`: queue id
+    /// * `dev`: ublk device
+    ///
+    /// Won't return until the queue is down.
+    pub fn run_queue(&self, qid: u16, dev: &UblkDev) -> Result<(), UblkError> {
+        if self.files.is_empty() {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        let io = Rc::new(StripeQueueIo {
+            map: self.map.clone(),
+            members: self
+                .files
+                .iter()
+                .map(|m| (m.fd_idx, m.fd(), m.blkdev))
+                .collect(),
+            read_only: self.read_only,
+        });
+
+        if self.async_io {
+            super::run_async_queue(qid, dev, move |q, tag| {
+                let io = io.clone();
+
+                async move { io.handle_io_async(&q, tag).await }
+            })
+        } else {
+            let depth = dev.dev_info.queue_depth as usize;
+            let ios = RefCell::new((0..depth).map(|_| StripeIo::default()).collect());
+
+            UblkQueue::new(qid, dev)?.wait_and_handle_io(
+                move |q: &UblkQueue, tag: u16, i: &UblkIOCtx| io.handle_io_sync(q, tag, i, &ios),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StripeLayout, StripeMap, StripeSeg, StripeTgtBuilder};
+
+    fn seg(member: usize, off: u64, buf_off: u64, len: u64) -> StripeSeg {
+        StripeSeg {
+            member,
+            off,
+            buf_off,
+            len,
+        }
+    }
+
+    #[test]
+    fn test_stripe_map() {
+        let striped = StripeMap {
+            layout: StripeLayout::Striped,
+            nr_members: 3,
+            chunk_shift: 12,
+            starts: Vec::new(),
+        };
+        assert!(striped.map(4096, 4096) == vec![seg(1, 0, 0, 4096)]);
+        assert!(
+            striped.map(10240, 8192)
+                == vec![
+                    seg(2, 2048, 0, 2048),
+                    seg(0, 4096, 2048, 4096),
+                    seg(1, 4096, 6144, 2048)
+                ]
+        );
+
+        let concat = StripeMap {
+            layout: StripeLayout::Concat,
+            nr_members: 2,
+            chunk_shift: 0,
+            starts: vec![0, 1 << 20, 3 << 20],
+        };
+        assert!(concat.map(4096, 512) == vec![seg(0, 4096, 0, 512)]);
+        assert!(
+            concat.map((1 << 20) - 1024, 4096)
+                == vec![seg(0, (1 << 20) - 1024, 0, 1024), seg(1, 0, 1024, 3072)]
+        );
+
+        // empty member is skipped
+        let concat = StripeMap {
+            starts: vec![0, 1 << 20, 1 << 20, 3 << 20],
+            ..concat
+        };
+        assert!(concat.map(1 << 20, 512) == vec![seg(2, 0, 0, 512)]);
+
+        let st = StripeTgtBuilder::default()
+            .members(vec!["a".to_string(), "b".to_string()])
+            .build()
+            .unwrap();
+        assert!(st.layout == StripeLayout::Striped && st.chunk_size == 65536);
+        assert!(st.direct_io && !st.read_only && st.dev_flags() == 0);
+        assert!(StripeTgtBuilder::default().build().is_err());
+    }
+}
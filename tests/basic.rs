@@ -1,22 +1,25 @@
 #[cfg(test)]
 mod integration {
     use io_uring::opcode;
+    use libublk::ctrl::{UblkCtrl, UblkDevState};
     use libublk::dev_flags::*;
     use libublk::exe::{Executor, UringOpFuture};
     use libublk::io::{UblkDev, UblkIOCtx, UblkQueue};
-    use libublk::{ctrl::UblkCtrl, UblkError, UblkIORes};
     use libublk::{sys, UblkSessionBuilder};
+    use libublk::{UblkError, UblkIORes};
     use std::env;
     use std::path::Path;
     use std::process::{Command, Stdio};
     use std::rc::Rc;
     use std::sync::{Arc, Mutex};
+    use std::time::Duration;
 
     fn run_ublk_disk_sanity_test(ctrl: &mut UblkCtrl, dev_flags: u32) {
         use std::os::unix::fs::PermissionsExt;
         let dev_path = ctrl.get_cdev_path();
 
-        std::thread::sleep(std::time::Duration::from_millis(500));
+        let bdev = ctrl.wait_for_bdev(Duration::from_secs(3)).unwrap();
+        assert!(Path::new(&bdev.path).exists());
 
         assert!(ctrl.get_target_flags_from_json().unwrap() == dev_flags);
 
@@ -48,6 +51,46 @@ mod integration {
         println!("{:?}", Command::new("dd").args(arg_list).output().unwrap());
     }
 
+    /// Open ublk disk `dev_id` with O_DIRECT, run `test` with the disk and
+    /// one 64K aligned buffer, then remove the device
+    fn __test_ublk_direct_disk<F>(dev_id: i32, test: F)
+    where
+        F: FnOnce(&mut UblkCtrl, &mut std::fs::File, &mut [u8]),
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+        let bdev = ctrl.wait_for_bdev(Duration::from_secs(3)).unwrap();
+        let mut f = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(&bdev.path)
+            .unwrap();
+        let buf = libublk::ublk_alloc_buf(65536, 4096);
+        let data = unsafe { std::slice::from_raw_parts_mut(buf, 65536) };
+
+        test(&mut ctrl, &mut f, data);
+        libublk::ublk_dealloc_buf(buf, 65536, 4096);
+
+        ctrl.kill_dev().unwrap();
+    }
+
+    fn disk_read(f: &mut std::fs::File, off: u64, data: &mut [u8]) {
+        use std::io::{Read, Seek, SeekFrom};
+
+        f.seek(SeekFrom::Start(off)).unwrap();
+        f.read_exact(data).unwrap();
+    }
+
+    fn disk_write(f: &mut std::fs::File, off: u64, data: &[u8]) {
+        use std::io::{Seek, SeekFrom, Write};
+
+        f.seek(SeekFrom::Start(off)).unwrap();
+        f.write_all(data).unwrap();
+        f.sync_all().unwrap();
+    }
+
     fn __test_ublk_null(dev_flags: u32, q_handler: fn(u16, &UblkDev)) {
         let sess = UblkSessionBuilder::default()
             .name("null")
@@ -92,7 +135,8 @@ mod integration {
 
             UblkQueue::new(qid, _dev)
                 .unwrap()
-                .wait_and_handle_io(io_handler);
+                .wait_and_handle_io(io_handler)
+                .unwrap();
         }
 
         __test_ublk_null(
@@ -118,7 +162,8 @@ mod integration {
 
             UblkQueue::new(qid, _dev)
                 .unwrap()
-                .wait_and_handle_io(io_handler);
+                .wait_and_handle_io(io_handler)
+                .unwrap();
         }
 
         __test_ublk_null(
@@ -213,7 +258,7 @@ mod integration {
                     }
                 });
             }
-            q_rc.wait_and_wake_io_tasks(&exe);
+            q_rc.wait_and_wake_io_tasks(&exe).unwrap();
         };
 
         // kick off our targets
@@ -320,7 +365,8 @@ mod integration {
             };
             UblkQueue::new(qid, _dev)
                 .unwrap()
-                .wait_and_handle_io(io_handler);
+                .wait_and_handle_io(io_handler)
+                .unwrap();
         };
 
         sess.run_target(&mut ctrl, &dev, q_fn, move |dev_id| {
@@ -330,6 +376,796 @@ mod integration {
         libublk::ublk_dealloc_buf(buf, size as usize, 4096);
     }
 
+    fn __test_ublk_loop(async_io: bool) {
+        use libublk::targets::r#loop::LoopTgtBuilder;
+        use std::io::{Read, Write};
+
+        let back = tempfile::NamedTempFile::new().unwrap();
+        back.as_file().set_len(32_u64 << 20).unwrap();
+        let back_path = back.path().to_str().unwrap().to_string();
+
+        let mut lo = LoopTgtBuilder::default()
+            .back_file_path(back_path.clone())
+            .async_io(async_io)
+            .build()
+            .unwrap();
+        let sess = UblkSessionBuilder::default()
+            .name("loop")
+            .nr_queues(2_u32)
+            .dev_flags(UBLK_DEV_F_ADD_DEV | lo.dev_flags())
+            .build()
+            .unwrap();
+
+        let (mut ctrl, dev) = sess.create_devices(|dev| lo.init_tgt(dev)).unwrap();
+        let q_fn = move |qid: u16, dev: &UblkDev| {
+            lo.run_queue(qid, dev).unwrap();
+        };
+
+        sess.run_target(&mut ctrl, &dev, q_fn, move |dev_id| {
+            let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+            let flags = UBLK_DEV_F_ADD_DEV | if async_io { UBLK_DEV_F_ASYNC } else { 0 };
+
+            run_ublk_disk_sanity_test(&mut ctrl, flags);
+
+            // data written to ublk disk has to land in the backing file
+            let data: Vec<u8> = (0..65536_u32).map(|i| (i % 251) as u8).collect();
+            let mut bdev = std::fs::OpenOptions::new()
+                .write(true)
+                .open(ctrl.get_bdev_path())
+                .unwrap();
+            bdev.write_all(&data).unwrap();
+            bdev.sync_all().unwrap();
+
+            let mut buf = vec![0_u8; data.len()];
+            std::fs::File::open(&back_path)
+                .unwrap()
+                .read_exact(&mut buf)
+                .unwrap();
+            assert!(buf == data);
+
+            ctrl.kill_dev().unwrap();
+        })
+        .unwrap();
+    }
+
+    /// make one ublk-loop over regular file, and test if data written
+    /// to ublk disk is stored in the backing file
+    #[test]
+    fn test_ublk_loop() {
+        __test_ublk_loop(false);
+        __test_ublk_loop(true);
+    }
+
+    /// make one ublk-qcow2 over image with raw backing file, and test if
+    /// written data is read back and backing data is kept
+    #[test]
+    fn test_ublk_qcow2() {
+        use libublk::targets::qcow2::{create_image, Qcow2TgtBuilder};
+
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base.raw");
+        let img = dir.path().join("top.qcow2");
+        let pattern: Vec<u8> = (0..(4_u32 << 20)).map(|i| (i % 251) as u8).collect();
+
+        std::fs::write(&base, &pattern).unwrap();
+        create_image(img.to_str().unwrap(), 64 << 20, 16, Some("base.raw")).unwrap();
+
+        let mut qcow2 = Qcow2TgtBuilder::default()
+            .image_path(img.to_str().unwrap())
+            .build()
+            .unwrap();
+        let sess = UblkSessionBuilder::default()
+            .name("qcow2")
+            .nr_queues(2_u32)
+            .dev_flags(UBLK_DEV_F_ADD_DEV | qcow2.dev_flags())
+            .build()
+            .unwrap();
+
+        let (mut ctrl, dev) = sess.create_devices(|dev| qcow2.init_tgt(dev)).unwrap();
+        let q_fn = move |qid: u16, dev: &UblkDev| {
+            qcow2.run_queue(qid, dev).unwrap();
+        };
+
+        sess.run_target(&mut ctrl, &dev, q_fn, move |dev_id| {
+            __test_ublk_direct_disk(dev_id, |_, f, data| {
+                // unallocated clusters are read from backing file
+                disk_read(f, 0, data);
+                assert!(data[..] == pattern[..65536]);
+
+                // partial cluster write keeps the rest of backing data
+                data[..4096].fill(0x5a);
+                disk_write(f, 8192, &data[..4096]);
+
+                disk_read(f, 0, data);
+                assert!(data[..8192] == pattern[..8192]);
+                assert!(data[8192..12288].iter().all(|b| *b == 0x5a));
+                assert!(data[12288..] == pattern[12288..65536]);
+
+                // beyond backing file is read as zeros
+                disk_read(f, 32 << 20, data);
+                assert!(data.iter().all(|b| *b == 0));
+            });
+        })
+        .unwrap();
+    }
+
+    /// Minimal NBD server over unix socket for testing nbd target, read is
+    /// replied with structured data chunks
+    fn nbd_serve_conn(mut s: std::os::unix::net::UnixStream, disk: Arc<Mutex<Vec<u8>>>) {
+        use std::io::{Read, Write};
+
+        fn opt_reply(s: &mut std::os::unix::net::UnixStream, opt: u32, rep: u32, data: &[u8]) {
+            let mut buf = 0x0003_e889_0455_65a9_u64.to_be_bytes().to_vec();
+            buf.extend_from_slice(&opt.to_be_bytes());
+            buf.extend_from_slice(&rep.to_be_bytes());
+            buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            buf.extend_from_slice(data);
+            s.write_all(&buf).unwrap();
+        }
+        fn chunk(flags: u16, ty: u16, handle: &[u8], payload: &[u8]) -> Vec<u8> {
+            let mut buf = 0x668e_33ef_u32.to_be_bytes().to_vec();
+            buf.extend_from_slice(&flags.to_be_bytes());
+            buf.extend_from_slice(&ty.to_be_bytes());
+            buf.extend_from_slice(handle);
+            buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            buf.extend_from_slice(payload);
+            buf
+        }
+
+        let size = disk.lock().unwrap().len() as u64;
+        let mut hello = b"NBDMAGICIHAVEOPT".to_vec();
+        hello.extend_from_slice(&3_u16.to_be_bytes());
+        s.write_all(&hello).unwrap();
+        let mut buf = [0_u8; 16];
+        s.read_exact(&mut buf[..4]).unwrap();
+
+        // STRUCTURED_REPLY, then GO
+        loop {
+            s.read_exact(&mut buf).unwrap();
+            let opt = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+            let len = u32::from_be_bytes(buf[12..16].try_into().unwrap());
+            let mut data = vec![0_u8; len as usize];
+            s.read_exact(&mut data).unwrap();
+
+            if opt == 8 {
+                opt_reply(&mut s, opt, 1, &[]);
+            } else {
+                let mut info = 0_u16.to_be_bytes().to_vec();
+                info.extend_from_slice(&size.to_be_bytes());
+                info.extend_from_slice(&(1_u16 | 4 | 8 | 0x20 | 0x40 | 0x100).to_be_bytes());
+                opt_reply(&mut s, opt, 3, &info);
+                opt_reply(&mut s, opt, 1, &[]);
+                break;
+            }
+        }
+
+        let mut req = [0_u8; 28];
+        while s.read_exact(&mut req).is_ok() {
+            let cmd = u16::from_be_bytes(req[6..8].try_into().unwrap());
+            let handle = req[8..16].to_vec();
+            let off = u64::from_be_bytes(req[16..24].try_into().unwrap()) as usize;
+            let len = u32::from_be_bytes(req[24..28].try_into().unwrap()) as usize;
+            let mut reply = Vec::new();
+
+            match cmd {
+                0 => {
+                    let d = disk.lock().unwrap();
+                    let half = len / 2;
+                    for (o, l) in [(off, half), (off + half, len - half)] {
+                        let mut payload = (o as u64).to_be_bytes().to_vec();
+                        payload.extend_from_slice(&d[o..o + l]);
+                        reply.extend(chunk(0, 1, &handle, &payload));
+                    }
+                }
+                1 => {
+                    let mut data = vec![0_u8; len];
+                    s.read_exact(&mut data).unwrap();
+                    disk.lock().unwrap()[off..off + len].copy_from_slice(&data);
+                }
+                2 => break,
+                4 | 6 => disk.lock().unwrap()[off..off + len].fill(0),
+                _ => {}
+            }
+            reply.extend(chunk(1, 0, &handle, &[]));
+            s.write_all(&reply).unwrap();
+        }
+    }
+
+    /// make one ublk-nbd with 2 queues over the test NBD server, and test
+    /// read, write, flush & discard
+    #[test]
+    fn test_ublk_nbd() {
+        use libublk::targets::nbd::NbdTgtBuilder;
+        use std::os::unix::io::AsRawFd;
+
+        let dir = tempfile::tempdir().unwrap();
+        let sock = dir.path().join("nbd.sock");
+        let listener = std::os::unix::net::UnixListener::bind(&sock).unwrap();
+        let pattern: Vec<u8> = (0..(16_u32 << 20)).map(|i| (i % 253) as u8).collect();
+        let disk = Arc::new(Mutex::new(pattern.clone()));
+        let d = disk.clone();
+        std::thread::spawn(move || {
+            for s in listener.incoming() {
+                let d = d.clone();
+                std::thread::spawn(move || nbd_serve_conn(s.unwrap(), d));
+            }
+        });
+
+        let mut nbd = NbdTgtBuilder::default()
+            .addr(sock.to_str().unwrap())
+            .build()
+            .unwrap();
+        let sess = UblkSessionBuilder::default()
+            .name("nbd")
+            .nr_queues(2_u32)
+            .dev_flags(UBLK_DEV_F_ADD_DEV | nbd.dev_flags())
+            .build()
+            .unwrap();
+
+        let (mut ctrl, dev) = sess.create_devices(|dev| nbd.init_tgt(dev)).unwrap();
+        assert!(nbd.export_info().0 == 16 << 20);
+        let q_fn = move |qid: u16, dev: &UblkDev| {
+            nbd.run_queue(qid, dev).unwrap();
+        };
+
+        sess.run_target(&mut ctrl, &dev, q_fn, move |dev_id| {
+            __test_ublk_direct_disk(dev_id, |_, f, data| {
+                disk_read(f, 1 << 20, data);
+                assert!(data[..] == pattern[1 << 20..(1 << 20) + 65536]);
+
+                data.fill(0x5a);
+                disk_write(f, 8192, data);
+                assert!(disk.lock().unwrap()[8192..8192 + 65536]
+                    .iter()
+                    .all(|b| *b == 0x5a));
+
+                // BLKDISCARD
+                let range = [0_u64, 65536];
+                assert!(unsafe { libc::ioctl(f.as_raw_fd(), 0x1277, range.as_ptr()) } == 0);
+                assert!(disk.lock().unwrap()[..65536].iter().all(|b| *b == 0));
+            });
+        })
+        .unwrap();
+    }
+
+    /// make one sparse file target, check allocation map after write,
+    /// discard and write zeroes
+    #[test]
+    fn test_ublk_sparse() {
+        use libublk::targets::sparse::SparseTgtBuilder;
+        use std::os::unix::io::AsRawFd;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sparse.img");
+        let mut sparse = SparseTgtBuilder::default()
+            .file_path(path.to_str().unwrap())
+            .size(64_u64 << 20)
+            .build()
+            .unwrap();
+        let sess = UblkSessionBuilder::default()
+            .name("sparse")
+            .nr_queues(1_u32)
+            .dev_flags(UBLK_DEV_F_ADD_DEV | sparse.dev_flags())
+            .build()
+            .unwrap();
+
+        let (mut ctrl, dev) = sess.create_devices(|dev| sparse.init_tgt(dev)).unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() == 64 << 20);
+        assert!(sparse.allocation_map(0, 64 << 20).unwrap().is_empty());
+        let sp = sparse.clone();
+        let q_fn = move |qid: u16, dev: &UblkDev| {
+            sparse.run_queue(qid, dev).unwrap();
+        };
+
+        sess.run_target(&mut ctrl, &dev, q_fn, move |dev_id| {
+            __test_ublk_direct_disk(dev_id, |_, f, data| {
+                data.fill(0x5a);
+                disk_write(f, 1 << 20, data);
+                let map = sp.allocation_map(0, 64 << 20).unwrap();
+                assert!(map.len() == 1 && map[0].0 <= 1 << 20);
+                assert!(map[0].0 + map[0].1 >= (1 << 20) + 65536);
+                let allocated = sp.allocated_bytes().unwrap();
+
+                // BLKDISCARD punches hole
+                let range = [1_u64 << 20, 32768];
+                assert!(unsafe { libc::ioctl(f.as_raw_fd(), 0x1277, range.as_ptr()) } == 0);
+                assert!(sp.allocated_bytes().unwrap() < allocated);
+
+                // BLKZEROOUT
+                let range = [(1_u64 << 20) + 32768, 32768];
+                assert!(unsafe { libc::ioctl(f.as_raw_fd(), 0x127f, range.as_ptr()) } == 0);
+                disk_read(f, 1 << 20, data);
+                assert!(data.iter().all(|b| *b == 0));
+            });
+        })
+        .unwrap();
+    }
+
+    /// make striped & concatenated devices over two files, and check data
+    /// is spread over members as expected
+    #[test]
+    fn test_ublk_stripe() {
+        use libublk::targets::stripe::{StripeLayout, StripeTgtBuilder};
+        use std::os::unix::fs::FileExt;
+
+        for (layout, async_io) in [
+            (StripeLayout::Striped, false),
+            (StripeLayout::Striped, true),
+            (StripeLayout::Concat, true),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let members: Vec<String> = (0..2)
+                .map(|i| {
+                    let p = dir.path().join(format!("m{}", i));
+                    let f = std::fs::File::create(&p).unwrap();
+
+                    f.set_len(16 << 20).unwrap();
+                    p.to_str().unwrap().to_string()
+                })
+                .collect();
+            let mut stripe = StripeTgtBuilder::default()
+                .members(members.clone())
+                .layout(layout)
+                .chunk_size(16384_u32)
+                .async_io(async_io)
+                .build()
+                .unwrap();
+            let sess = UblkSessionBuilder::default()
+                .name("stripe")
+                .nr_queues(2_u32)
+                .dev_flags(UBLK_DEV_F_ADD_DEV | stripe.dev_flags())
+                .build()
+                .unwrap();
+
+            let (mut ctrl, dev) = sess.create_devices(|dev| stripe.init_tgt(dev)).unwrap();
+            assert!(dev.tgt.dev_size == 32 << 20);
+            let q_fn = move |qid: u16, dev: &UblkDev| {
+                stripe.run_queue(qid, dev).unwrap();
+            };
+
+            sess.run_target(&mut ctrl, &dev, q_fn, move |dev_id| {
+                __test_ublk_direct_disk(dev_id, |_, f, data| {
+                    let pattern: Vec<u8> = (0..65536_u32).map(|i| (i % 251) as u8).collect();
+
+                    // cross chunk boundary, and member boundary for concat
+                    let off = (16 << 20) - 8192;
+                    data.copy_from_slice(&pattern);
+                    disk_write(f, off, data);
+
+                    data.fill(0);
+                    disk_read(f, off, data);
+                    assert!(data[..] == pattern[..]);
+
+                    let m0 = std::fs::File::open(&members[0]).unwrap();
+                    let m1 = std::fs::File::open(&members[1]).unwrap();
+                    let mut chunk = vec![0_u8; 8192];
+                    match layout {
+                        // the first 8K is in the 2nd half of chunk 1023 on m1,
+                        // and the following chunk 1024 starts at 8M of m0
+                        StripeLayout::Striped => {
+                            m1.read_exact_at(&mut chunk, (8 << 20) - 8192).unwrap();
+                            assert!(chunk[..] == pattern[..8192]);
+                            m0.read_exact_at(&mut chunk, 8 << 20).unwrap();
+                            assert!(chunk[..] == pattern[8192..16384]);
+                        }
+                        StripeLayout::Concat => {
+                            m0.read_exact_at(&mut chunk, (16 << 20) - 8192).unwrap();
+                            assert!(chunk[..] == pattern[..8192]);
+                            m1.read_exact_at(&mut chunk, 0).unwrap();
+                            assert!(chunk[..] == pattern[8192..16384]);
+                        }
+                    }
+                });
+            })
+            .unwrap();
+        }
+    }
+
+    /// make raid1 over two files, fail one replica and write in degraded
+    /// mode, then re-add it and wait until resync is done
+    #[test]
+    fn test_ublk_raid1() {
+        use libublk::targets::raid1::{Raid1MemberState, Raid1TgtBuilder};
+
+        let dir = tempfile::tempdir().unwrap();
+        let members: Vec<String> = (0..2)
+            .map(|i| {
+                let p = dir.path().join(format!("r{}", i));
+                let f = std::fs::File::create(&p).unwrap();
+
+                f.set_len(16 << 20).unwrap();
+                p.to_str().unwrap().to_string()
+            })
+            .collect();
+        let mut raid1 = Raid1TgtBuilder::default()
+            .members(members.clone())
+            .region_size(1_u32 << 20)
+            .build()
+            .unwrap();
+        let sess = UblkSessionBuilder::default()
+            .name("raid1")
+            .nr_queues(2_u32)
+            .dev_flags(UBLK_DEV_F_ADD_DEV | raid1.dev_flags())
+            .build()
+            .unwrap();
+
+        let (mut ctrl, dev) = sess.create_devices(|dev| raid1.init_tgt(dev)).unwrap();
+        let r = raid1.clone();
+        let q_fn = move |qid: u16, dev: &UblkDev| {
+            raid1.run_queue(qid, dev).unwrap();
+        };
+
+        sess.run_target(&mut ctrl, &dev, q_fn, move |dev_id| {
+            __test_ublk_direct_disk(dev_id, |ctrl, f, data| {
+                data.fill(0x5a);
+                disk_write(f, 0, data);
+                assert!(r.nr_dirty_regions().unwrap() == 0);
+
+                // degraded write is only stored in replica 0
+                r.fail_member(1).unwrap();
+                data.fill(0xa5);
+                disk_write(f, 2 << 20, data);
+                assert!(r.nr_dirty_regions().unwrap() == 1);
+                assert!(std::fs::read(&members[0]).unwrap() != std::fs::read(&members[1]).unwrap());
+
+                let state = ctrl.get_target_state().unwrap();
+                assert!(state["raid1"]["bitmap"]["failed"][1] == true);
+
+                disk_read(f, 2 << 20, data);
+                assert!(data.iter().all(|b| *b == 0xa5));
+
+                // resync after the replica returns
+                r.readd_member(1).unwrap();
+                for _ in 0..50 {
+                    if r.member_states().unwrap() == vec![Raid1MemberState::Active; 2] {
+                        break;
+                    }
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                assert!(r.member_states().unwrap() == vec![Raid1MemberState::Active; 2]);
+                assert!(r.nr_dirty_regions().unwrap() == 0);
+                assert!(std::fs::read(&members[0]).unwrap() == std::fs::read(&members[1]).unwrap());
+            });
+        })
+        .unwrap();
+    }
+
+    /// make one cow device over base image, write & flush, then merge the
+    /// overlay back into base
+    #[test]
+    fn test_ublk_cow() {
+        use libublk::targets::cow::{merge_overlay, CowTgtBuilder};
+
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("golden.img");
+        let ovl = dir.path().join("job.cow");
+        let (base_s, ovl_s) = (
+            base.to_str().unwrap().to_string(),
+            ovl.to_str().unwrap().to_string(),
+        );
+        let pattern: Vec<u8> = (0..(8_u32 << 20)).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&base, &pattern).unwrap();
+
+        let mut cow = CowTgtBuilder::default()
+            .base_path(base_s.clone())
+            .overlay_path(ovl_s.clone())
+            .build()
+            .unwrap();
+        let sess = UblkSessionBuilder::default()
+            .name("cow")
+            .nr_queues(2_u32)
+            .dev_flags(UBLK_DEV_F_ADD_DEV | cow.dev_flags())
+            .build()
+            .unwrap();
+
+        let (mut ctrl, dev) = sess.create_devices(|dev| cow.init_tgt(dev)).unwrap();
+        let c = cow.clone();
+        let q_fn = move |qid: u16, dev: &UblkDev| {
+            cow.run_queue(qid, dev).unwrap();
+        };
+
+        let p = pattern.clone();
+        sess.run_target(&mut ctrl, &dev, q_fn, move |dev_id| {
+            __test_ublk_direct_disk(dev_id, |_, f, data| {
+                // partial write crossing two chunks
+                data[..8192].fill(0x5a);
+                disk_write(f, 65536 - 4096, &data[..8192]);
+                assert!(c.allocated_chunks().unwrap() == 2);
+
+                disk_read(f, 0, data);
+                assert!(data[..61440] == p[..61440]);
+                assert!(data[61440..].iter().all(|b| *b == 0x5a));
+
+                disk_read(f, 65536, data);
+                assert!(data[..4096].iter().all(|b| *b == 0x5a));
+                assert!(data[4096..] == p[65536 + 4096..131072]);
+
+                // base is never written
+                assert!(std::fs::read(&base).unwrap() == p);
+            });
+        })
+        .unwrap();
+
+        assert!(merge_overlay(&base_s, &ovl_s).unwrap() == 2);
+        let merged = std::fs::read(&base_s).unwrap();
+        assert!(merged[61440..69632].iter().all(|b| *b == 0x5a));
+        assert!(merged[..61440] == pattern[..61440] && merged[69632..] == pattern[69632..]);
+    }
+
+    /// make one null target which adds latency and fails all IOs
+    #[test]
+    fn test_ublk_null_target_fault() {
+        use libublk::targets::null::NullTgtBuilder;
+        use std::io::Read;
+
+        for dev_flags in [UBLK_DEV_F_ADD_DEV, UBLK_DEV_F_ADD_DEV | UBLK_DEV_F_ASYNC] {
+            let null = NullTgtBuilder::default()
+                .size(64_u64 << 20)
+                .lat_min(Duration::from_millis(1))
+                .lat_max(Duration::from_millis(2))
+                .error_rate(1.0)
+                .errno(libc::EIO)
+                .build()
+                .unwrap();
+            let sess = UblkSessionBuilder::default()
+                .name("null")
+                .dev_flags(dev_flags)
+                .build()
+                .unwrap();
+
+            let (mut ctrl, dev) = sess.create_devices(|dev| null.init_tgt(dev)).unwrap();
+            let q_fn = move |qid: u16, dev: &UblkDev| {
+                null.run_queue(qid, dev).unwrap();
+            };
+
+            sess.run_target(&mut ctrl, &dev, q_fn, move |dev_id| {
+                let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+                let bdev = ctrl.wait_for_bdev(Duration::from_secs(3)).unwrap();
+                let mut buf = [0_u8; 4096];
+
+                let r = std::fs::File::open(&bdev.path).and_then(|mut f| f.read_exact(&mut buf));
+                assert!(r.is_err());
+
+                ctrl.kill_dev().unwrap();
+            })
+            .unwrap();
+        }
+    }
+
+    /// collect IO statistics of async null target
+    #[test]
+    fn test_ublk_stats_async() {
+        use libublk::targets::null::NullTgtBuilder;
+
+        let null = NullTgtBuilder::default().build().unwrap();
+        let sess = UblkSessionBuilder::default()
+            .name("null")
+            .nr_queues(2_u32)
+            .dev_flags(UBLK_DEV_F_ADD_DEV | UBLK_DEV_F_ASYNC | UBLK_DEV_F_STATS)
+            .build()
+            .unwrap();
+
+        let (mut ctrl, dev) = sess.create_devices(|dev| null.init_tgt(dev)).unwrap();
+        let q_fn = move |qid: u16, dev: &UblkDev| {
+            null.run_queue(qid, dev).unwrap();
+        };
+
+        let s_dev = dev.clone();
+        sess.run_target(&mut ctrl, &dev, q_fn, move |dev_id| {
+            let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+
+            ctrl.wait_for_bdev(Duration::from_secs(3)).unwrap();
+            read_ublk_disk(dev_id);
+
+            let stats = s_dev.get_stats().unwrap();
+            assert!(stats.read.ios > 0 && stats.read.bytes >= 40 << 20);
+            assert!(stats.lat_hist.iter().sum::<u64>() > 0);
+            assert!(stats.inflight_max > 0 && stats.errors == 0);
+
+            ctrl.kill_dev().unwrap();
+        })
+        .unwrap();
+    }
+
+    /// wrap null io handler with fault injector, and change rules at runtime
+    #[test]
+    fn test_ublk_fault_injection() {
+        use libublk::targets::fault::*;
+        use std::io::Read;
+
+        let inj = Arc::new(UblkFaultInjector::new());
+        let mut rule = UblkFaultRule::new(UblkFaultAction::Error { errno: libc::EIO });
+        rule.op = UblkFaultOp::Read;
+        inj.add_rule(rule).unwrap();
+
+        let sess = UblkSessionBuilder::default()
+            .name("null")
+            .dev_flags(UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+        let tgt_init = |dev: &mut UblkDev| {
+            dev.set_default_params(32_u64 << 20);
+            Ok(0)
+        };
+        let (mut ctrl, dev) = sess.create_devices(tgt_init).unwrap();
+        let inj_q = inj.clone();
+        let q_fn = move |qid: u16, dev: &UblkDev| {
+            let io_handler = move |q: &UblkQueue, tag: u16, _io: &UblkIOCtx| {
+                let iod = q.get_iod(tag);
+                let bytes = (iod.nr_sectors << 9) as i32;
+
+                q.complete_io_cmd(tag, Ok(UblkIORes::Result(bytes)));
+            };
+            let fq = UblkFaultQueue::new(&inj_q, qid, dev.dev_info.queue_depth as u32);
+
+            UblkQueue::new(qid, dev)
+                .unwrap()
+                .wait_and_handle_io(fq.wrap_io_handler(io_handler))
+                .unwrap();
+        };
+
+        let inj_ctrl = inj.clone();
+        sess.run_target(&mut ctrl, &dev, q_fn, move |dev_id| {
+            let mut ctrl = UblkCtrl::new_simple(dev_id, 0).unwrap();
+            let bdev = ctrl.wait_for_bdev(Duration::from_secs(3)).unwrap();
+            let read_bdev = || {
+                let mut buf = [0_u8; 4096];
+
+                std::fs::File::open(&bdev.path).and_then(|mut f| f.read_exact(&mut buf))
+            };
+
+            assert!(read_bdev().is_err());
+            assert!(inj_ctrl.get_rules()[0].injected > 0);
+
+            // delayed IO is handed to the wrapped handler
+            inj_ctrl
+                .set_rules(vec![UblkFaultRule::new(UblkFaultAction::Delay {
+                    us: 1000,
+                })])
+                .unwrap();
+            assert!(read_bdev().is_ok());
+            assert!(inj_ctrl.get_rules()[0].injected > 0);
+
+            inj_ctrl.clear();
+            assert!(read_bdev().is_ok());
+
+            ctrl.kill_dev().unwrap();
+        })
+        .unwrap();
+    }
+
+    /// Handle READ/WRITE of `iod` by copying data between io buffer and `disk`
+    fn mem_disk_io(disk: &Mutex<Vec<u8>>, iod: &sys::ublksrv_io_desc, buf: *mut u8) -> i32 {
+        let off = (iod.start_sector << 9) as usize;
+        let bytes = (iod.nr_sectors << 9) as usize;
+        let io_buf = unsafe { std::slice::from_raw_parts_mut(buf, bytes) };
+        let mut disk = disk.lock().unwrap();
+
+        match iod.op_flags & 0xff {
+            sys::UBLK_IO_OP_READ => io_buf.copy_from_slice(&disk[off..off + bytes]),
+            sys::UBLK_IO_OP_WRITE => disk[off..off + bytes].copy_from_slice(io_buf),
+            _ => {}
+        }
+        bytes as i32
+    }
+
+    fn __test_ublk_fault_torn_write(async_io: bool) {
+        use libublk::targets::fault::*;
+        use std::io::{Seek, SeekFrom, Write};
+
+        let size = 32_u64 << 20;
+        let disk = Arc::new(Mutex::new(vec![0_u8; size as usize]));
+        let inj = Arc::new(UblkFaultInjector::new());
+        let mut rule = UblkFaultRule::new(UblkFaultAction::TornWrite { sectors: 8 });
+        rule.count = Some(1);
+        inj.add_rule(rule).unwrap();
+
+        let sess = UblkSessionBuilder::default()
+            .name("null")
+            .dev_flags(UBLK_DEV_F_ADD_DEV | if async_io { UBLK_DEV_F_ASYNC } else { 0 })
+            .build()
+            .unwrap();
+        let tgt_init = |dev: &mut UblkDev| {
+            dev.set_default_params(size);
+            Ok(0)
+        };
+        let (mut ctrl, dev) = sess.create_devices(tgt_init).unwrap();
+        let inj_q = inj.clone();
+        let disk_q = disk.clone();
+        let q_fn = move |qid: u16, dev: &UblkDev| {
+            let fq = UblkFaultQueue::new(&inj_q, qid, dev.dev_info.queue_depth as u32);
+            let disk = disk_q.clone();
+
+            if async_io {
+                let io_handler = fq.wrap_async_handler(move |q, tag| {
+                    let disk = disk.clone();
+
+                    async move { mem_disk_io(&disk, q.get_iod(tag), q.get_io_buf_addr(tag)) }
+                });
+                libublk::targets::run_async_queue(qid, dev, io_handler).unwrap();
+            } else {
+                let io_handler = move |q: &UblkQueue, tag: u16, _io: &UblkIOCtx| {
+                    let res = mem_disk_io(&disk, q.get_iod(tag), q.get_io_buf_addr(tag));
+
+                    q.complete_io_cmd(tag, Ok(UblkIORes::Result(res)));
+                };
+                UblkQueue::new(qid, dev)
+                    .unwrap()
+                    .wait_and_handle_io(fq.wrap_io_handler(io_handler))
+                    .unwrap();
+            }
+        };
+
+        sess.run_target(&mut ctrl, &dev, q_fn, move |dev_id| {
+            __test_ublk_direct_disk(dev_id, |_, f, data| {
+                for (i, b) in data.iter_mut().enumerate() {
+                    *b = (i % 251) as u8 + 1;
+                }
+
+                // the write fails, and only its first 8 sectors are stored
+                f.seek(SeekFrom::Start(0)).unwrap();
+                assert!(f.write_all(data).is_err());
+                assert!(inj.get_rules()[0].injected == 1);
+
+                let store = disk.lock().unwrap();
+                assert!(store[..4096] == data[..4096]);
+                assert!(store[4096..65536].iter().all(|b| *b == 0));
+            });
+        })
+        .unwrap();
+    }
+
+    /// tear write of one ublk disk over memory store, and test if the head
+    /// of data is stored and the tail isn't
+    #[test]
+    fn test_ublk_fault_torn_write() {
+        __test_ublk_fault_torn_write(false);
+        __test_ublk_fault_torn_write(true);
+    }
+
+    /// make one sparse ramdisk, and test chunk allocation & memory cap
+    #[test]
+    fn test_ublk_ramdisk_sparse() {
+        use libublk::targets::ramdisk::RamdiskTgtBuilder;
+        use std::io::{Seek, SeekFrom, Write};
+
+        let mut rd = RamdiskTgtBuilder::default()
+            .size(1_u64 << 30)
+            .mem_cap(1_u64 << 20)
+            .build()
+            .unwrap();
+        let sess = UblkSessionBuilder::default()
+            .name("ramdisk")
+            .dev_flags(UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+
+        let (mut ctrl, dev) = sess.create_devices(|dev| rd.init_tgt(dev)).unwrap();
+        let rd_q = rd.clone();
+        let q_fn = move |qid: u16, dev: &UblkDev| {
+            rd_q.run_queue(qid, dev).unwrap();
+        };
+
+        sess.run_target(&mut ctrl, &dev, q_fn, move |dev_id| {
+            __test_ublk_direct_disk(dev_id, |_, f, data| {
+                // nothing is allocated before the first write
+                read_ublk_disk(dev_id);
+                assert!(rd.mem_used() == 0);
+
+                disk_write(f, 0, data);
+                assert!(rd.mem_used() == 65536);
+
+                // beyond the memory cap
+                f.seek(SeekFrom::Start(512_u64 << 20)).unwrap();
+                for _ in 0..16 {
+                    let _ = f.write_all(data);
+                }
+                assert!(rd.mem_used() == 1_u64 << 20);
+            });
+        })
+        .unwrap();
+    }
+
     /// make FnMut closure for IO handling
     #[test]
     fn test_fn_mut_io_closure() {
@@ -353,7 +1189,8 @@ mod integration {
 
             UblkQueue::new(qid, _dev)
                 .unwrap()
-                .wait_and_handle_io(io_handler);
+                .wait_and_handle_io(io_handler)
+                .unwrap();
         }
 
         __test_ublk_null(
@@ -379,22 +1216,6 @@ mod integration {
             }
         }
 
-        fn ublk_state_wait_until(ctrl: &mut UblkCtrl, state: u16, timeout: u32) {
-            let mut count = 0;
-            let unit = 100_u32;
-            loop {
-                std::thread::sleep(std::time::Duration::from_millis(unit as u64));
-
-                ctrl.get_info().unwrap();
-                if ctrl.dev_info.state == state {
-                    std::thread::sleep(std::time::Duration::from_millis(20));
-                    break;
-                }
-                count += unit;
-                assert!(count < timeout);
-            }
-        }
-
         let tgt_dir = get_curr_bin_dir().unwrap();
         let tmpfile = tempfile::NamedTempFile::new().unwrap();
         let file = std::fs::File::create(tmpfile.path()).unwrap();
@@ -432,7 +1253,8 @@ mod integration {
         assert!(tid != 0);
 
         let mut ctrl = UblkCtrl::new_simple(id, 0).unwrap();
-        ublk_state_wait_until(&mut ctrl, sys::UBLK_S_DEV_LIVE as u16, 2000);
+        ctrl.wait_for_state(UblkDevState::Live, Duration::from_millis(2000))
+            .unwrap();
 
         //ublk block device should be observed now
         let dev_path = ctrl.get_bdev_path();
@@ -444,7 +1266,8 @@ mod integration {
         }
 
         //wait device becomes quiesced
-        ublk_state_wait_until(&mut ctrl, sys::UBLK_S_DEV_QUIESCED as u16, 6000);
+        ctrl.wait_for_state(UblkDevState::Quiesced, Duration::from_millis(6000))
+            .unwrap();
 
         let file = std::fs::File::create(tmpfile.path()).unwrap();
         //recover device
@@ -456,7 +1279,8 @@ mod integration {
         cmd.wait().unwrap();
         //let buf = std::fs::read_to_string(tmpfile.path()).unwrap();
         //println!("{}", buf);
-        ublk_state_wait_until(&mut ctrl, sys::UBLK_S_DEV_LIVE as u16, 20000);
+        ctrl.wait_for_state(UblkDevState::Live, Duration::from_millis(20000))
+            .unwrap();
         ctrl.del_dev().unwrap();
     }
 }
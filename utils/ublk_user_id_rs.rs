@@ -3,20 +3,9 @@
 fn main() {
     let s = std::env::args().nth(1).unwrap_or_else(|| "".to_string());
 
-    if s.len() >= 6 && (&s[0..5] == "ublkb" || &s[0..5] == "ublkc") {
-        match s[5..].parse::<i32>() {
-            Ok(id) => match libublk::ctrl::UblkCtrl::new_simple(id, 0) {
-                Ok(ctrl) => {
-                    let dinfo = &ctrl.dev_info;
-                    if (dinfo.flags & libublk::sys::UBLK_F_UNPRIVILEGED_DEV as u64) != 0 {
-                        println!("{}:{}", dinfo.owner_uid, dinfo.owner_gid);
-                    }
-                    std::process::exit(0);
-                }
-                _ => {}
-            },
-            _ => {}
-        }
+    if let Some((uid, gid)) = libublk::ctrl::UblkCtrl::get_dev_owner_by_name(&s) {
+        println!("{}:{}", uid, gid);
+        std::process::exit(0);
     }
     std::process::exit(-1);
 }